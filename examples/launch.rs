@@ -4,7 +4,7 @@ use qemu_launch::qemu::Qemu;
 fn main() {
     let config = config::QemuConfig::builder();
     // todo: fill in the config
-    let qemu = Qemu::from_config(config);
+    let mut qemu = Qemu::from_config(config).expect("invalid config");
     qemu.dump();
     qemu.launch().expect("launched fail");
 }
@@ -1,15 +1,30 @@
+use std::collections::{BTreeMap, HashSet};
 use std::os::unix::prelude::RawFd;
+use std::path::Path;
+use std::rc::Rc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::device::Device;
-use crate::types::{Incoming, IoThread, Kernel, Knobs, Machine, Memory, QmpSocket, Rtc, Smp, FwCfg};
-use crate::types::{MACHINE_TYPE_MICROVM, MIGRATION_DEFER, MIGRATION_EXEC, MIGRATION_FD};
+use crate::device::{
+    BlockDevice, Device, DeviceSpec, PciOptions, PcieRootPortDevice, VirtioOptions,
+};
+use crate::device_consts::{VFIOAP, VFIOCCW, VFIOPCI, VIRTIOBLOCK};
+use crate::qemu::Qemu;
+use crate::qmp::QmpClient;
+use crate::types::{
+    parse_size, qemu_escape, valid_action, DISPLAY_TYPES, MACHINE_TYPE_MICROVM, MIGRATION_DEFER,
+    MIGRATION_EXEC, MIGRATION_FD, MIGRATION_RDMA, MIGRATION_TCP, MIGRATION_UNIX, VGA_TYPES,
+};
+use crate::types::{
+    Arch, Boot, DriveSpec, ExitPolicy, FwCfg, GlobalParam, HostFeature, Incoming, IoThread, Kernel,
+    Knobs, Machine, MachinePreset, Memory, MonitorSocket, Numa, Plugin, QemuVersion, QmpSocket,
+    Rtc, Seccomp, Smp, TlsCreds, Vnc,
+};
 
 /// the configuration of QEMU
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct QemuConfig {
     /// binary path of QEMU
@@ -18,19 +33,19 @@ pub struct QemuConfig {
 
     /// user id
     #[serde(default)]
-    uid: u32,
+    pub(crate) uid: u32,
 
     /// group id
     #[serde(default)]
-    gid: u32,
+    pub(crate) gid: u32,
 
     /// groups(supplementary group IDs)
     #[serde(default)]
-    groups: Vec<u32>,
+    pub(crate) groups: Vec<u32>,
 
     /// QEMU guest name
     #[serde(default)]
-    name: String,
+    pub(crate) name: String,
 
     /// uuid of qemu process
     #[serde(default)]
@@ -49,18 +64,39 @@ pub struct QemuConfig {
     machine: Machine,
 
     #[serde(default)]
-    qmp_sockets: Vec<QmpSocket>,
+    pub(crate) qmp_sockets: Vec<QmpSocket>,
 
+    /// human monitor sockets
+    #[serde(default)]
+    monitors: Vec<MonitorSocket>,
+
+    /// shared with the clones of the config, devices cannot be cloned
     #[serde(skip_deserializing, skip_serializing)]
-    devices: Vec<Box<dyn Device>>,
+    devices: Vec<Rc<dyn Device>>,
+
+    /// devices declared in the config file, built along with `devices`
+    #[serde(default)]
+    device_specs: Vec<DeviceSpec>,
 
     #[serde(default)]
     rtc: Rtc,
 
+    /// boot order and menu
+    #[serde(default)]
+    boot: Boot,
+
     /// vga mode
     #[serde(default)]
     vga: String,
 
+    /// vnc server
+    #[serde(default)]
+    vnc: Vnc,
+
+    /// tls credentials for vnc and migration
+    #[serde(default)]
+    tls_creds: Vec<TlsCreds>,
+
     /// guest kernel configuration
     #[serde(default)]
     kernel: Kernel,
@@ -73,28 +109,58 @@ pub struct QemuConfig {
     #[serde(default)]
     smp: Smp,
 
+    /// numa cpu assignments and node distances
+    #[serde(default)]
+    numa: Numa,
+
     /// -global
     #[serde(default)]
     global_params: String,
 
+    /// -global driver.property=value
+    #[serde(default)]
+    globals: Vec<GlobalParam>,
+
+    #[serde(default)]
+    pub(crate) knobs: Knobs,
+
+    /// guest panic/reboot behavior, supersedes knobs.no_reboot and knobs.no_shutdown
     #[serde(default)]
-    knobs: Knobs,
+    exit_policy: Option<ExitPolicy>,
+
+    /// -action event=action, e.g. reboot=shutdown, supersedes
+    /// knobs.no_reboot and knobs.no_shutdown
+    #[serde(default)]
+    actions: BTreeMap<String, String>,
 
     // -bios
     #[serde(default)]
     bios: String,
 
+    /// -L, directories qemu looks up firmware blobs in
+    #[serde(default)]
+    bios_search_paths: Vec<String>,
+
     #[serde(default)]
     no_graphic: bool,
 
+    /// -display backend, optionally with suboptions, e.g. "gtk,gl=on", or "vnc"
+    /// to show the guest on the vnc server of vnc.display
+    #[serde(default)]
+    display: String,
+
     #[serde(default)]
     pflashs: Vec<String>,
 
+    /// plain -drive disks
+    #[serde(default)]
+    drives: Vec<DriveSpec>,
+
     #[serde(default)]
     incoming: Incoming,
 
     #[serde(default)]
-    fds: Vec<RawFd>,
+    pub(crate) fds: Vec<RawFd>,
 
     #[serde(default)]
     fw_cfgs: Vec<FwCfg>,
@@ -103,20 +169,61 @@ pub struct QemuConfig {
     io_threads: Vec<IoThread>,
 
     #[serde(default)]
-    pid_file: String,
+    pub(crate) pid_file: String,
 
     #[serde(default)]
     log_file: String,
 
+    /// tcg plugins
+    #[serde(default)]
+    plugins: Vec<Plugin>,
+
+    /// check that the host files and directories the config refers to exist
+    #[serde(default)]
+    validate_paths: bool,
+
+    /// run `validate()` in `build_all()`, which fails on any problem
+    /// instead of leaving the invalid parts out
+    #[serde(default)]
+    strict: bool,
+
+    /// version of the qemu binary, suboptions newer than it are left out,
+    /// all of them are emitted when unknown
+    #[serde(default)]
+    pub(crate) qemu_version: Option<QemuVersion>,
+
+    /// options appended verbatim after all the others, for what is not modeled
+    #[serde(default)]
+    raw_args: Vec<String>,
+
     /// qemu parameters
     pub qemu_params: Vec<String>,
+
+    /// set by `build_all()`, to tell the params it emitted from the pushed ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    built: Option<BuiltParams>,
+}
+
+/// the qemu_params and fds of a config before `build_all()` emitted its own
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct BuiltParams {
+    /// the params pushed before the build
+    pushed: Vec<String>,
+    /// the number of params after the build, the ones past it were pushed since
+    len: usize,
+    /// the fds appended before the build
+    #[serde(default)]
+    pushed_fds: Vec<RawFd>,
+    /// the number of fds after the build, the ones past it were appended since
+    #[serde(default)]
+    fds_len: usize,
 }
 
 /// QemuConfig
 /// # How to build
 /// To build your own config, you use builder(), and cumulatively add the components you want
 /// ```rust
-/// use config::QemuConfig;
+/// use qemu_launch::config::QemuConfig;
 ///
 /// # fn main() {
 /// let config = QemuConfig::builder()
@@ -125,38 +232,312 @@ pub struct QemuConfig {
 /// ```
 impl QemuConfig {
     /// Fill the `self.qemu_params` based on the fields we have filled
-    /// Notice that this is not idempotent, duplicate call will append
-    /// new params after the original ones
+    /// A built config is built again from its fields by further calls, so the
+    /// changes made since are reflected and the params are not duplicated
+    ///
+    /// panics on an invalid config, see `try_build_all()`
     pub fn build_all(&self) -> Self {
-        let uuid = Uuid::new_v4();
-        let cfg = self.clone();
+        self.try_build_all().expect("failed to build all")
+    }
+
+    /// set the version of the qemu binary the config is rendered for
+    pub fn set_qemu_version(mut self, version: QemuVersion) -> Self {
+        self.qemu_version = Some(version);
+        self
+    }
+
+    /// run the qemu binary at bin_path to find out its version
+    pub fn detect_qemu_version(self) -> Result<Self> {
+        let version = Qemu::new(self.bin_path.clone(), vec![]).version()?;
+        Ok(self.set_qemu_version(version))
+    }
+
+    /// whether the qemu binary is at least min, assumed when the version is unknown
+    pub(crate) fn supports(&self, min: QemuVersion) -> bool {
+        self.qemu_version.is_none_or(|version| version >= min)
+    }
+
+    /// same as `build_all()`, but returns the error of an invalid config
+    pub fn try_build_all(&self) -> Result<Self> {
+        for warning in self.warnings() {
+            log::warn!("{}", warning);
+        }
+        if self.strict {
+            self.validate()?;
+        }
+        let spec_devices = self.build_devices();
+        if let Some(e) = self.build_errors(&spec_devices).into_iter().next() {
+            return Err(e);
+        }
+
+        // the uuid is kept across builds, once generated
+        let uuid = Uuid::parse_str(&self.uuid).unwrap_or_else(|_| Uuid::new_v4());
+        let mut cfg = self.clone();
+        cfg.uuid = uuid.to_string();
+        cfg.qemu_params = self.pushed_params();
+        cfg.fds = self.pushed_fds();
 
         // the order of the functions matters
         let cfg = cfg
             .add_cpu_model(&self.cpu_model)
+            .add_bios_search_paths(&self.bios_search_paths)
             .add_bios(&self.bios)
-            .add_kernel(&self.kernel)
+            .add_boot(&self.boot)?
+            .add_kernel(&self.kernel)?
             .add_machine(&self.machine)
             .add_memory(&self.memory)
             .add_name(&self.name)
-            .add_seccomp(&self.seccomp_sandbox)
+            .add_seccomp(&self.sandbox_param()?)
             .add_uuid(uuid)
             .add_no_graphic(self.no_graphic)
             .add_rtc(&self.rtc)
             .add_qmp_sockets(&self.qmp_sockets)
-            .add_vga(&self.vga)
+            .add_monitors(&self.monitors)
+            .add_vga(&self.vga)?
+            .add_display(&self.display)?
+            .add_tls_creds(&self.tls_creds)
+            .add_vnc(&self.vnc)
             .add_io_threads(&self.io_threads)
             .add_incoming(&self.incoming)
             .add_pflash_param(&self.pflashs)
+            .add_drives(&self.drives)
+            .add_fwcfg(&self.fw_cfgs)
             .add_pid_file(&self.pid_file)
             .add_log_file(&self.log_file)
+            .add_plugins(&self.plugins)
             .add_global_params(&self.global_params)
+            .add_globals(&self.globals)?
+            .add_exit_policy(self.exit_policy)
+            .add_actions(&self.actions)?
             .add_knobs(&self.knobs)
-            .add_smp(&self.smp)
-            .expect("failed to build all");
+            .add_numa(&self.numa)
+            .add_smp(&self.smp)?;
 
         // call add_devices after regular appendance
-        cfg.add_devices(&self.devices)
+        let mut cfg = cfg
+            .add_devices(&self.devices)
+            .add_devices(&spec_devices)
+            .add_raw_args(&self.raw_args);
+        cfg.built = Some(BuiltParams {
+            pushed: self.pushed_params(),
+            len: cfg.qemu_params.len(),
+            pushed_fds: self.pushed_fds(),
+            fds_len: cfg.fds.len(),
+        });
+        Ok(cfg)
+    }
+
+    /// the params pushed to qemu_params other than by `build_all()`,
+    /// before it or since, those dropped by `strip_flag()` are emitted again
+    fn pushed_params(&self) -> Vec<String> {
+        match &self.built {
+            None => self.qemu_params.clone(),
+            Some(built) => {
+                let since = self.qemu_params.get(built.len..).unwrap_or_default();
+                [built.pushed.as_slice(), since].concat()
+            }
+        }
+    }
+
+    /// the fds appended other than by `build_all()`, before it or since
+    fn pushed_fds(&self) -> Vec<RawFd> {
+        match &self.built {
+            None => self.fds.clone(),
+            Some(built) => {
+                let since = self.fds.get(built.fds_len..).unwrap_or_default();
+                [built.pushed_fds.as_slice(), since].concat()
+            }
+        }
+    }
+
+    /// the errors `try_build_all()` fails on, shared with `validate()`,
+    /// the add_* steps that can fail are run on throwaway clones
+    fn build_errors(&self, spec_devices: &[Box<dyn Device>]) -> Vec<anyhow::Error> {
+        let devices = || self.all_devices(spec_devices);
+        let results = [
+            self.clone().add_boot(&self.boot).map(|_| ()),
+            self.clone().add_kernel(&self.kernel).map(|_| ()),
+            self.sandbox_param().map(|_| ()),
+            self.clone().add_vga(&self.vga).map(|_| ()),
+            self.clone().add_display(&self.display).map(|_| ()),
+            self.clone().add_globals(&self.globals).map(|_| ()),
+            self.clone().add_actions(&self.actions).map(|_| ()),
+            self.clone().add_smp(&self.smp).map(|_| ()),
+            self.check_drives(),
+            self.check_hotplug_memory(),
+            self.check_numa_memory(),
+            Self::check_boot_indices(devices()),
+            self.check_machine_buses(devices()),
+            Self::check_devnos(devices()),
+            Self::check_usb_buses(devices()),
+            self.check_iothreads(devices()),
+            self.check_machine_support(devices()),
+        ];
+        results.into_iter().filter_map(|r| r.err()).collect()
+    }
+
+    /// `add_drive()` skips invalid drives, a config is not built without them
+    fn check_drives(&self) -> Result<()> {
+        match self.drives.iter().find(|drive| !drive.valid()) {
+            Some(drive) => Err(anyhow!("invalid drive {}", drive.params())),
+            None => Ok(()),
+        }
+    }
+
+    /// checks the whole config before launch, the error lists every problem found
+    ///
+    /// `build_all()` leaves the invalid parts out of the command line, unless
+    /// the config is strict, and only fails on the first error otherwise.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = vec![];
+
+        let rtc = &self.rtc;
+        let rtc_set = !rtc.base.is_empty() || !rtc.clock.is_empty() || !rtc.drift_fix.is_empty();
+        if rtc_set && !rtc.valid() {
+            problems.push(format!(
+                "invalid rtc base={} clock={} driftfix={}",
+                rtc.base, rtc.clock, rtc.drift_fix
+            ));
+        }
+        for socket in self.qmp_sockets.iter().filter(|socket| !socket.valid()) {
+            problems.push(format!(
+                "invalid {} qmp socket {}",
+                socket.socket_type,
+                socket.address()
+            ));
+        }
+        for monitor in self.monitors.iter().filter(|monitor| !monitor.valid()) {
+            problems.push(format!(
+                "invalid {} monitor {}",
+                monitor.socket_type, monitor.name
+            ));
+        }
+        for creds in self.tls_creds.iter().filter(|creds| !creds.valid()) {
+            problems.push(format!("invalid tls creds {}", creds.id));
+        }
+        for fwcfg in self.fw_cfgs.iter().filter(|fwcfg| !fwcfg.valid()) {
+            problems.push(format!(
+                "fw_cfg {} needs either a file or a string",
+                fwcfg.name
+            ));
+        }
+        if self.plugins.iter().any(|plugin| !plugin.valid()) {
+            problems.push("plugins need a path".to_owned());
+        }
+        if !self.numa.valid() {
+            problems
+                .push("numa distances must be symmetric and farther than the local one".to_owned());
+        }
+
+        if self.knobs.hugepages && self.memory.size.is_empty() {
+            problems.push("knobs.hugepages requires memory.size".to_owned());
+        }
+        let nodes_have_paths =
+            !self.numa.nodes.is_empty() && self.numa.nodes.iter().all(|n| !n.mem_path.is_empty());
+        if self.knobs.file_backed_mem && self.memory.path.is_empty() && !nodes_have_paths {
+            problems.push("knobs.file_backed_mem requires memory.path".to_owned());
+        }
+        problems.extend(self.incoming_problem());
+
+        let spec_devices = self.build_devices();
+        for dev in self.all_devices(&spec_devices) {
+            if let Err(e) = dev.check() {
+                problems.push(e.to_string());
+            }
+        }
+
+        problems.extend(
+            self.build_errors(&spec_devices)
+                .iter()
+                .map(|e| e.to_string()),
+        );
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("invalid config:\n  {}", problems.join("\n  ")))
+    }
+
+    /// the reason the incoming migration cannot be set up, if any
+    fn incoming_problem(&self) -> Option<String> {
+        let incoming = &self.incoming;
+        match incoming.migration_type.as_str() {
+            "" | MIGRATION_EXEC | MIGRATION_DEFER => None,
+            // SAFETY: F_GETFD only reads the flags of the fd
+            MIGRATION_FD if unsafe { libc::fcntl(incoming.fd, libc::F_GETFD) } < 0 => Some(
+                format!("incoming migration fd {} is not an open fd", incoming.fd),
+            ),
+            MIGRATION_FD => None,
+            MIGRATION_RDMA if incoming.host.is_empty() || incoming.port == 0 => {
+                Some("rdma incoming migration requires host and port".to_owned())
+            }
+            MIGRATION_RDMA => None,
+            MIGRATION_TCP if incoming.host.is_empty() || incoming.port == 0 => {
+                Some("tcp incoming migration requires host and port".to_owned())
+            }
+            MIGRATION_TCP => None,
+            MIGRATION_UNIX if incoming.path.is_empty() => {
+                Some("unix incoming migration requires a path".to_owned())
+            }
+            MIGRATION_UNIX => None,
+            other => Some(format!("unknown incoming migration type {}", other)),
+        }
+    }
+
+    /// returns the problems of the config that do not prevent it from being built,
+    /// but are most likely a mistake, they are logged by `build_all()`
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        // an empty machine type leaves the choice to qemu, which picks one with hotplug
+        if self.smp.max_cpus > self.smp.cpus
+            && !self.machine.machine_type.is_empty()
+            && !self.machine.supports_cpu_hotplug()
+        {
+            warnings.push(format!(
+                "smp.max_cpus ({}) > smp.cpus ({}) implies cpu hotplug, \
+                 which machine type {} does not support",
+                self.smp.max_cpus, self.smp.cpus, self.machine.machine_type
+            ));
+        }
+
+        // the vcpus cold-plugged with -device take the slots above smp.cpus
+        let spec_devices = self.build_devices();
+        let cold_vcpus: u32 = self
+            .all_devices(&spec_devices)
+            .filter(|dev| dev.valid())
+            .map(|dev| dev.cold_vcpus())
+            .sum();
+        let free_slots = self.smp.max_cpus.saturating_sub(self.smp.cpus);
+        if cold_vcpus > free_slots {
+            warnings.push(format!(
+                "{} cold-plugged vcpus need as many free slots, smp.max_cpus ({}) \
+                 leaves {} above smp.cpus ({})",
+                cold_vcpus, self.smp.max_cpus, free_slots, self.smp.cpus
+            ));
+        }
+
+        warnings.extend(
+            self.all_devices(&spec_devices)
+                .filter(|dev| dev.valid())
+                .flat_map(|dev| dev.warnings()),
+        );
+
+        // slew catches up with missed ticks by injecting interrupts, which
+        // does not work the same when tcg is not paced by the host clock
+        if self.rtc.clock == "host" && self.rtc.drift_fix == "slew" && self.machine.is_tcg() {
+            warnings.push(
+                "rtc clock=host with driftfix=slew behaves differently under tcg, \
+                 consider clock=vm for reproducible runs"
+                    .to_owned(),
+            );
+        }
+
+        if self.exit_policy.is_some() && !self.actions.is_empty() {
+            warnings.push("both exit_policy and actions are set, they may conflict".to_owned());
+        }
+
+        warnings
     }
 
     /// returns a default instance of `QemuConfig`
@@ -166,6 +547,156 @@ impl QemuConfig {
         }
     }
 
+    /// load the config from a toml file
+    pub fn from_toml(path: &str) -> Self {
+        Self::try_from_toml(path).expect("failed to load the config file")
+    }
+
+    /// same as `from_toml`, returning the read and parse errors
+    pub fn try_from_toml(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the config file {}", path))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse the config file {}", path))
+    }
+
+    /// load the config from a json file
+    pub fn from_json(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read the config file {}", path))?;
+        Self::from_json_str(&content)
+            .with_context(|| format!("failed to parse the config file {}", path))
+    }
+
+    /// parse the config from a json document
+    pub fn from_json_str(content: &str) -> Result<Self> {
+        Ok(serde_json::from_str(content)?)
+    }
+
+    /// load the config from a json or toml file, told by its extension
+    pub fn from_file(path: &str) -> Result<Self> {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(path),
+            Some("toml") => Self::try_from_toml(path),
+            _ => Err(anyhow!("unknown config file format of {}", path)),
+        }
+    }
+
+    /// the config as a json document, the devices added in code are left out
+    pub fn to_json_string(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// instantiate the devices declared in device_specs
+    pub fn build_devices(&self) -> Vec<Box<dyn Device>> {
+        self.device_specs.iter().map(|spec| spec.build()).collect()
+    }
+
+    /// the devices added in code, followed by the given built device_specs
+    fn all_devices<'a>(
+        &'a self,
+        spec_devices: &'a [Box<dyn Device>],
+    ) -> impl Iterator<Item = &'a dyn Device> + Clone {
+        self.devices
+            .iter()
+            .map(|dev| dev.as_ref())
+            .chain(spec_devices.iter().map(|dev| dev.as_ref()))
+    }
+
+    /// setup a sane baseline for a machine: machine type, acceleration,
+    /// default devices and knobs, the specifics can be overridden afterwards
+    pub fn apply_preset(mut self, preset: MachinePreset) -> Self {
+        const PRESET_ROOT_PORTS: u32 = 4;
+
+        let (machine_type, root_port_bus) = match preset {
+            MachinePreset::Q35Modern => ("q35", Some("pcie.0")),
+            MachinePreset::PcLegacy => ("pc", None),
+            MachinePreset::Arm64Virt => ("virt", Some("pcie.0")),
+            MachinePreset::Microvm => (MACHINE_TYPE_MICROVM, None),
+        };
+
+        self.machine = Machine {
+            machine_type: machine_type.to_owned(),
+            acceleration: "kvm".to_owned(),
+            options: String::new(),
+        };
+        self.knobs.no_user_config = true;
+
+        match preset {
+            // the default cpu model of virt cannot be accelerated by kvm
+            MachinePreset::Arm64Virt => self.cpu_model = "host".to_owned(),
+            // microvm has no use for option roms and legacy devices
+            MachinePreset::Microvm => {
+                self.machine.options = "x-option-roms=off".to_owned();
+                self.knobs.no_defaults = true;
+            }
+            _ => {}
+        }
+
+        if let Some(bus) = root_port_bus {
+            for i in 0..PRESET_ROOT_PORTS {
+                self.devices.push(Rc::new(PcieRootPortDevice {
+                    id: format!("rp{}", i),
+                    bus: bus.to_owned(),
+                    chassis: i + 1,
+                    slot: i + 1,
+                    multifunction: false,
+                }));
+            }
+        }
+        self
+    }
+
+    /// returns the most restrictive seccomp filter the configured devices can run with,
+    /// render it with `Seccomp::param()` to get the -sandbox value
+    pub fn recommended_seccomp(&self) -> Seccomp {
+        let mut seccomp = Seccomp::hardened();
+
+        // vfio needs to tune the priority and affinity of its interrupt threads
+        if self
+            .devices
+            .iter()
+            .any(|dev| [VFIOPCI, VFIOCCW, VFIOAP].contains(&dev.driver()))
+        {
+            seccomp.deny_resource_control = false;
+        }
+        seccomp
+    }
+
+    /// the host features the config needs, e.g. to schedule it on a capable host
+    pub fn required_host_features(&self) -> HashSet<HostFeature> {
+        let mut features = HashSet::new();
+        if self.machine.acceleration.contains("kvm") {
+            features.insert(HostFeature::Kvm);
+        }
+        if self.knobs.hugepages {
+            features.insert(HostFeature::Hugepages);
+        }
+
+        let spec_devices = self.build_devices();
+        for dev in self.all_devices(&spec_devices) {
+            features.extend(dev.host_features());
+        }
+        features
+    }
+
+    /// the -sandbox value, knobs.seccomp turns it on when seccomp_sandbox is empty
+    fn sandbox_param(&self) -> Result<String> {
+        if !self.knobs.seccomp {
+            return Ok(self.seccomp_sandbox.clone());
+        }
+        if self.seccomp_sandbox.is_empty() {
+            return Ok("on".to_owned());
+        }
+        if self.seccomp_sandbox.split(',').next() == Some("off") {
+            return Err(anyhow!(
+                "knobs.seccomp is set but seccomp_sandbox is {}",
+                self.seccomp_sandbox
+            ));
+        }
+        Ok(self.seccomp_sandbox.clone())
+    }
+
     /// setup the seccomp
     pub fn add_seccomp(mut self, seccomp_sandbox: &str) -> Self {
         if !seccomp_sandbox.is_empty() {
@@ -185,19 +716,24 @@ impl QemuConfig {
     }
 
     /// setup the machine type and related settings, e.g. accel=kvm
+    /// the architecture's default machine is used when machine_type is empty
     pub fn add_machine(mut self, machine: &Machine) -> Self {
-        if !machine.machine_type.is_empty() {
-            let mut machine_params = vec![machine.machine_type.to_owned()];
-            if !machine.acceleration.is_empty() {
-                machine_params.push(format!("accel={}", machine.acceleration));
-            }
-            if !machine.options.is_empty() {
-                machine_params.push(machine.options.to_owned());
-            }
+        let machine_type = if machine.machine_type.is_empty() {
+            Arch::from_machine_or_host(&machine.machine_type).default_machine()
+        } else {
+            &machine.machine_type
+        };
 
-            self.qemu_params.push("-machine".to_owned());
-            self.qemu_params.push(machine_params.join(","));
+        let mut machine_params = vec![machine_type.to_owned()];
+        if !machine.acceleration.is_empty() {
+            machine_params.push(format!("accel={}", machine.acceleration));
         }
+        if !machine.options.is_empty() {
+            machine_params.push(machine.options.to_owned());
+        }
+
+        self.qemu_params.push("-machine".to_owned());
+        self.qemu_params.push(machine_params.join(","));
         self
     }
 
@@ -210,16 +746,274 @@ impl QemuConfig {
         self
     }
 
+    /// memory devices are plugged into the hotplug memory region,
+    /// which only exists when the memory has maxmem and slots
+    /// and all of them together with the base memory must fit in maxmem
+    fn check_hotplug_memory(&self) -> Result<()> {
+        let sizes: Vec<u64> = self
+            .devices
+            .iter()
+            .filter_map(|dev| dev.hotplug_memory())
+            .collect();
+        if sizes.is_empty() {
+            return Ok(());
+        }
+        if self.memory.max_memory.is_empty() || self.memory.slots == 0 {
+            return Err(anyhow!(
+                "memory devices require memory.max_memory and memory.slots to be set"
+            ));
+        }
+
+        let max_memory = self
+            .memory
+            .max_memory_bytes()
+            .ok_or_else(|| anyhow!("invalid memory.max_memory {}", self.memory.max_memory))?;
+        let base = self
+            .memory
+            .size_bytes()
+            .ok_or_else(|| anyhow!("invalid memory.size {}", self.memory.size))?;
+        let total = sizes.iter().sum::<u64>() + base;
+        if total > max_memory {
+            return Err(anyhow!(
+                "memory devices and base memory take {} bytes, more than maxmem {}",
+                total,
+                self.memory.max_memory
+            ));
+        }
+        Ok(())
+    }
+
+    /// two devices with the same bootindex make qemu refuse to start
+    fn check_boot_indices<'a>(devices: impl Iterator<Item = &'a dyn Device>) -> Result<()> {
+        let mut seen = std::collections::BTreeSet::new();
+        for index in devices.filter_map(|dev| dev.boot_index()) {
+            if !seen.insert(index) {
+                return Err(anyhow!(
+                    "bootindex {} is used by more than one device",
+                    index
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// ccw addresses identify the devices on s390x, they cannot be shared
+    fn check_devnos<'a>(devices: impl Iterator<Item = &'a dyn Device>) -> Result<()> {
+        let mut seen = std::collections::BTreeSet::new();
+        for devno in devices.filter_map(|dev| dev.devno()) {
+            if !seen.insert(devno) {
+                return Err(anyhow!("devno {} is used by more than one device", devno));
+            }
+        }
+        Ok(())
+    }
+
+    /// usb devices must plug to the bus of a usb controller plugged before them
+    fn check_usb_buses<'a>(devices: impl Iterator<Item = &'a dyn Device>) -> Result<()> {
+        let mut buses = HashSet::new();
+        for dev in devices.filter(|dev| dev.valid()) {
+            if let Some(bus) = dev.provided_usb_bus() {
+                buses.insert(bus);
+            }
+            if let Some(bus) = dev.usb_bus() {
+                if !buses.contains(bus) {
+                    return Err(anyhow!(
+                        "{} plugs to usb bus {}, which no usb controller before it provides",
+                        dev.driver(),
+                        bus
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the numa nodes split memory.size between them, and the files backing
+    /// them can't be shared
+    fn check_numa_memory(&self) -> Result<()> {
+        if self.numa.nodes.is_empty() {
+            return Ok(());
+        }
+
+        let size = self
+            .memory
+            .size_bytes()
+            .ok_or_else(|| anyhow!("numa nodes need a valid memory.size"))?;
+        let mut total = 0u64;
+        for (node_id, node) in self.numa.nodes.iter().enumerate() {
+            total += parse_size(&node.size, 20)
+                .ok_or_else(|| anyhow!("invalid size {} of numa node {}", node.size, node_id))?;
+        }
+        if total != size {
+            return Err(anyhow!(
+                "numa nodes take {} bytes, memory.size {} is {}",
+                total,
+                self.memory.size,
+                size
+            ));
+        }
+
+        if self.knobs.file_backed_mem && !self.knobs.hugepages {
+            let mut seen = std::collections::BTreeSet::new();
+            for node in &self.numa.nodes {
+                let mem_path = node.mem_path(&self.memory);
+                if !seen.insert(mem_path) {
+                    return Err(anyhow!(
+                        "numa nodes share the memory file {}, set their own mem_path",
+                        mem_path
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// the iothreads devices run their io in must be declared in io_threads
+    fn check_iothreads<'a>(&self, devices: impl Iterator<Item = &'a dyn Device>) -> Result<()> {
+        for iothread in devices.filter_map(|dev| dev.iothread()) {
+            if !self.io_threads.iter().any(|thread| thread.id == iothread) {
+                return Err(anyhow!(
+                    "iothread {} is not declared in io_threads",
+                    iothread
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// some devices only exist on some machines, e.g. intel-iommu on q35
+    fn check_machine_support<'a>(
+        &self,
+        mut devices: impl Iterator<Item = &'a dyn Device>,
+    ) -> Result<()> {
+        let machine_type = &self.machine.machine_type;
+        if let Some(dev) = devices.find(|dev| dev.valid() && !dev.supports_machine(machine_type)) {
+            return Err(anyhow!(
+                "{} is not supported by the {} machine",
+                dev.driver(),
+                if machine_type.is_empty() {
+                    "default"
+                } else {
+                    machine_type
+                }
+            ));
+        }
+        Ok(())
+    }
+
+    /// microvm has no pci bus, its virtio devices need the mmio transport
+    fn check_machine_buses<'a>(
+        &self,
+        mut devices: impl Iterator<Item = &'a dyn Device>,
+    ) -> Result<()> {
+        if self.machine.machine_type != MACHINE_TYPE_MICROVM {
+            return Ok(());
+        }
+        if let Some(dev) = devices.find(|dev| dev.valid() && dev.on_pci_bus(self)) {
+            return Err(anyhow!(
+                "{} is a pci device, but microvm has no pci bus, use the mmio transport",
+                dev.driver()
+            ));
+        }
+        Ok(())
+    }
+
+    /// attach a disk image as a virtio-blk drive, the format is told by the
+    /// extension and left for qemu to probe when unknown
+    ///
+    /// The drive gets the first diskN id no other device or device spec drive uses.
+    pub fn add_disk(mut self, path: &str) -> Self {
+        let format = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("qcow2") => "qcow2",
+            Some("img") | Some("raw") => "raw",
+            _ => "",
+        };
+
+        let mut used: Vec<String> = self
+            .devices
+            .iter()
+            .filter_map(|dev| dev.drive_id().map(str::to_owned))
+            .collect();
+        used.extend(
+            self.device_specs
+                .iter()
+                .filter_map(|spec| spec.build().drive_id().map(str::to_owned)),
+        );
+        let id = (0..)
+            .map(|n| format!("disk{}", n))
+            .find(|id| !used.contains(id))
+            .unwrap();
+        self.devices.push(Rc::new(BlockDevice {
+            id,
+            file: path.to_owned(),
+            format: format.to_owned(),
+            driver: VIRTIOBLOCK.to_owned(),
+            transport: String::new(),
+            bus: String::new(),
+            logical_block_size: None,
+            physical_block_size: None,
+            readonly: false,
+            cache: String::new(),
+            aio: String::new(),
+            virtio: VirtioOptions::default(),
+            boot_index: None,
+            pci: PciOptions::default(),
+        }));
+        self
+    }
+
+    /// add a device to the config, unlike the other add_* methods it does not
+    /// emit params right away
+    ///
+    /// The devices are applied with `add_devices()` at the end of `build_all()`,
+    /// they are shared with the clones of the config as they cannot be cloned.
+    pub fn add_device(mut self, dev: Box<dyn Device>) -> Self {
+        self.devices.push(Rc::from(dev));
+        self
+    }
+
     /// Normally, we add device after `build_all()` since it is not cloneable
-    pub fn add_devices(mut self, devices: &Vec<Box<dyn Device>>) -> Self {
-        devices.into_iter().for_each(|dev| {
-            if dev.valid() {
+    /// with validate_paths, devices whose host files are missing are skipped like invalid ones
+    pub fn add_devices<D: AsRef<dyn Device>>(mut self, devices: &[D]) -> Self {
+        devices.iter().map(|dev| dev.as_ref()).for_each(|dev| {
+            if let Err(e) = dev.check() {
+                log::warn!("skipping device: {}", e);
+                return;
+            }
+            if self.host_paths_exist(&dev.host_paths()) {
                 dev.set_qemu_params(&mut self);
             }
         });
         self
     }
 
+    /// apply the settings the devices only take over qmp once qemu is launched,
+    /// e.g. the stats polling interval of a balloon, the devices `add_devices()` skips are left out
+    pub fn post_launch(&self, qmp: &mut QmpClient) -> Result<()> {
+        let spec_devices = self.build_devices();
+        for dev in self.all_devices(&spec_devices) {
+            if dev.check().is_ok() && self.host_paths_exist(&dev.host_paths()) {
+                dev.post_launch(qmp)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// whether the given host paths exist, always true without validate_paths
+    fn host_paths_exist(&self, paths: &[&str]) -> bool {
+        if !self.validate_paths {
+            return true;
+        }
+
+        paths.iter().all(|path| {
+            let exists = Path::new(path).exists();
+            if !exists {
+                log::warn!("{} does not exist on the host", path);
+            }
+            exists
+        })
+    }
+
     /// setup the uuid of qemu
     pub fn add_uuid(mut self, uuid: Uuid) -> Self {
         if !uuid.is_nil() {
@@ -271,11 +1065,23 @@ impl QemuConfig {
                 if smp.max_cpus < smp.cpus {
                     return Err(anyhow!("smp.max_cpus should >= smp.cpus"));
                 }
+
+                // qemu derives the missing ones of a partial topology
+                let topology = [smp.sockets, smp.cores, smp.threads];
+                if topology.iter().all(|n| *n > 0)
+                    && topology.iter().map(|n| *n as u64).product::<u64>() != smp.max_cpus as u64
+                {
+                    return Err(anyhow!(
+                        "smp topology {} sockets * {} cores * {} threads does not match {} max_cpus",
+                        smp.sockets,
+                        smp.cores,
+                        smp.threads,
+                        smp.max_cpus
+                    ));
+                }
                 smp_params.push(format!("maxcpus={}", smp.max_cpus));
             }
 
-            assert_eq!(smp.sockets * smp.cores * smp.threads, smp.max_cpus);
-
             self.qemu_params.push("-smp".to_owned());
             self.qemu_params.push(smp_params.join(","));
         }
@@ -291,8 +1097,45 @@ impl QemuConfig {
         self
     }
 
+    /// append args to qemu as is, e.g. a -device the config has no model for
+    pub fn add_raw_args(mut self, args: &[String]) -> Self {
+        self.qemu_params.extend_from_slice(args);
+        self
+    }
+
+    /// set the default value of a property for all the devices of a driver
+    pub fn add_global(mut self, global: &GlobalParam) -> Result<Self> {
+        if !global.valid() {
+            return Err(anyhow!(
+                "global {} needs a driver, a property and a value",
+                global.param()
+            ));
+        }
+        self.qemu_params.push("-global".to_owned());
+        self.qemu_params.push(global.param());
+        Ok(self)
+    }
+
+    pub fn add_globals(self, globals: &[GlobalParam]) -> Result<Self> {
+        globals
+            .iter()
+            .try_fold(self, |cfg, global| cfg.add_global(global))
+    }
+
     /// setup kernel, init ramdisk, and other params, e.g. -append "root=/dev/vda console=ttyS0"
-    pub fn add_kernel(mut self, kernel: &Kernel) -> Self {
+    /// initrd and params are meaningless without a kernel to boot directly
+    pub fn add_kernel(mut self, kernel: &Kernel) -> Result<Self> {
+        if kernel.path.is_empty() {
+            if !kernel.initrd_path.is_empty() {
+                return Err(anyhow!("kernel.initrd_path is set without kernel.path"));
+            }
+            if !kernel.params.is_empty() {
+                return Err(anyhow!(
+                    "kernel.params is set without kernel.path, -append requires -kernel"
+                ));
+            }
+        }
+
         if !kernel.path.is_empty() {
             self.qemu_params.push("-kernel".to_owned());
             self.qemu_params.push(kernel.path.to_owned());
@@ -307,7 +1150,7 @@ impl QemuConfig {
                 self.qemu_params.push(kernel.params.to_owned());
             }
         }
-        self
+        Ok(self)
     }
 
     /// setup the bios that qemu uses
@@ -319,6 +1162,33 @@ impl QemuConfig {
         self
     }
 
+    /// setup the boot order and menu
+    pub fn add_boot(mut self, boot: &Boot) -> Result<Self> {
+        if !boot.valid() {
+            return Err(anyhow!("unknown boot device in boot order {}", boot.order));
+        }
+
+        let boot_params = boot.params();
+        if !boot_params.is_empty() {
+            self.qemu_params.push("-boot".to_owned());
+            self.qemu_params.push(boot_params.join(","));
+        }
+        Ok(self)
+    }
+
+    /// add the firmware search directories, in lookup order
+    /// with validate_paths, the missing ones are skipped
+    pub fn add_bios_search_paths(mut self, paths: &[String]) -> Self {
+        for path in paths {
+            if !self.host_paths_exist(&[path]) {
+                continue;
+            }
+            self.qemu_params.push("-L".to_owned());
+            self.qemu_params.push(path.to_owned());
+        }
+        self
+    }
+
     /// disable the graphical output
     pub fn add_no_graphic(mut self, no_graphic: bool) -> Self {
         if no_graphic {
@@ -343,6 +1213,7 @@ impl QemuConfig {
             rtc_params.push(format!("clock={}", rtc.clock));
         }
 
+        self.qemu_params.push("-rtc".to_owned());
         self.qemu_params.push(rtc_params.join(","));
         self
     }
@@ -354,41 +1225,161 @@ impl QemuConfig {
                 continue;
             }
 
-            let mut qmp_params = vec![format!("{}:{}", socket.socket_type, socket.name)];
-            if socket.is_server {
-                qmp_params.push("server=on".to_owned());
-                if socket.no_wait {
-                    qmp_params.push("wait=off".to_owned());
-                }
-            }
-
             self.qemu_params.push("-qmp".to_owned());
-            self.qemu_params.push(qmp_params.join(","))
+            self.qemu_params.push(socket.params());
         }
         self
     }
 
-    /// setup the vga for qemu
-    pub fn add_vga(mut self, vga: &str) -> Self {
-        if !vga.is_empty() {
-            self.qemu_params.push("-vga".to_owned());
-            self.qemu_params.push(vga.to_owned());
+    /// setup the human monitors
+    pub fn add_monitors(mut self, monitors: &[MonitorSocket]) -> Self {
+        for monitor in monitors {
+            if !monitor.valid() {
+                continue;
+            }
+            self.qemu_params.push("-monitor".to_owned());
+            self.qemu_params.push(monitor.params());
         }
         self
     }
 
-    /// XXX: ONLY called AFTER add_memory() and machine_type is set
-    /// setup the boolean configurations
-    pub fn add_knobs(mut self, knobs: &Knobs) -> Self {
-        if knobs.no_user_config {
-            self.qemu_params.push("-no-user-config".to_owned());
+    /// setup the vga for qemu
+    pub fn add_vga(mut self, vga: &str) -> Result<Self> {
+        if vga.is_empty() {
+            return Ok(self);
         }
 
-        if knobs.no_reboot {
-            self.qemu_params.push("--no-reboot".to_owned());
+        if !VGA_TYPES.contains(&vga) {
+            return Err(anyhow!("unknown vga type {}", vga));
         }
-
-        if knobs.no_graphic {
+        self.qemu_params.push("-vga".to_owned());
+        self.qemu_params.push(vga.to_owned());
+        Ok(self)
+    }
+
+    /// setup the reboot/shutdown/panic behavior from a single policy
+    pub fn add_exit_policy(mut self, exit_policy: Option<ExitPolicy>) -> Self {
+        if let Some(policy) = exit_policy {
+            self.qemu_params.extend(policy.qemu_params());
+        }
+        self
+    }
+
+    /// add tls credentials objects
+    pub fn add_tls_creds(mut self, tls_creds: &[TlsCreds]) -> Self {
+        for creds in tls_creds {
+            if !creds.valid() {
+                continue;
+            }
+            creds.qemu_params(&mut self);
+        }
+        self
+    }
+
+    /// pick a free port for the tcp qmp sockets with dynamic_port
+    pub fn pick_qmp_ports(&mut self) -> Result<()> {
+        for socket in &mut self.qmp_sockets {
+            if socket.dynamic_port && socket.socket_type == QmpSocket::TCP_SOCKET {
+                socket.pick_free_port()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// pick a free port for a vnc server with dynamic_port, returns the
+    /// port the vnc server listens on, if any
+    pub fn pick_vnc_port(&mut self) -> Result<Option<u16>> {
+        if self.vnc.dynamic_port {
+            return Ok(Some(self.vnc.pick_free_port()?));
+        }
+        Ok(self.vnc.port())
+    }
+
+    /// setup the vnc server, optionally encrypted with a tls-creds object
+    pub fn add_vnc(mut self, vnc: &Vnc) -> Self {
+        if !vnc.display.is_empty() {
+            let mut vnc_params = vec![vnc.display.to_owned()];
+            if !vnc.tls_creds.is_empty() {
+                vnc_params.push(format!("tls-creds={}", vnc.tls_creds));
+            }
+
+            self.qemu_params.push("-vnc".to_owned());
+            self.qemu_params.push(vnc_params.join(","));
+        }
+        self
+    }
+
+    /// setup the display backend, which -nographic leaves no room for
+    ///
+    /// A plain vnc display takes the host:port spec of vnc.display, emitted
+    /// as -vnc <spec> by `add_vnc()`.
+    pub fn add_display(mut self, display: &str) -> Result<Self> {
+        if display.is_empty() {
+            return Ok(self);
+        }
+        if self.no_graphic || self.knobs.no_graphic {
+            return Err(anyhow!("display {} conflicts with no_graphic", display));
+        }
+
+        let backend = display.split(',').next().unwrap_or_default();
+        if !DISPLAY_TYPES.contains(&backend) {
+            return Err(anyhow!("unknown display {}", backend));
+        }
+        if backend == "vnc" {
+            if display != "vnc" {
+                return Err(anyhow!(
+                    "display {} takes no options, set them on vnc",
+                    display
+                ));
+            }
+            if !self.vnc.display.contains(':') {
+                return Err(anyhow!(
+                    "display vnc needs a host:port spec in vnc.display, e.g. 127.0.0.1:1"
+                ));
+            }
+            return Ok(self);
+        }
+
+        self.qemu_params.push("-display".to_owned());
+        self.qemu_params.push(display.to_owned());
+        Ok(self)
+    }
+
+    /// setup what qemu does on reboot, shutdown, panic and watchdog events
+    pub fn add_actions(mut self, actions: &BTreeMap<String, String>) -> Result<Self> {
+        if actions.is_empty() {
+            return Ok(self);
+        }
+
+        let mut action_params = vec![];
+        for (name, value) in actions {
+            if !valid_action(name, value) {
+                return Err(anyhow!("unknown action {}={}", name, value));
+            }
+            action_params.push(format!("{}={}", name, value));
+        }
+
+        self.qemu_params.push("-action".to_owned());
+        self.qemu_params.push(action_params.join(","));
+        Ok(self)
+    }
+
+    /// XXX: ONLY called AFTER add_memory() and machine_type is set
+    /// setup the boolean configurations
+    pub fn add_knobs(mut self, knobs: &Knobs) -> Self {
+        if knobs.no_user_config {
+            self.qemu_params.push("-no-user-config".to_owned());
+        }
+
+        // the modern forms of -no-reboot and -no-shutdown take precedence
+        let legacy_actions = self.exit_policy.is_none() && self.actions.is_empty();
+
+        if knobs.no_reboot && legacy_actions {
+            self.qemu_params.push("--no-reboot".to_owned());
+        }
+
+        // add_no_graphic() may have emitted it already
+        if knobs.no_graphic && !self.qemu_params.iter().any(|param| param == "-nographic") {
             self.qemu_params.push("-nographic".to_owned());
         }
 
@@ -396,7 +1387,7 @@ impl QemuConfig {
             self.qemu_params.push("-nodefaults".to_owned());
         }
 
-        if knobs.no_shutdown {
+        if knobs.no_shutdown && legacy_actions {
             self.qemu_params.push("--no-shutdown".to_owned());
         }
 
@@ -418,29 +1409,107 @@ impl QemuConfig {
         self
     }
 
+    /// XXX: ONLY called AFTER add_knobs(), the numa nodes must exist
+    /// setup the numa cpu assignments and the distances between nodes
+    pub fn add_numa(mut self, numa: &Numa) -> Self {
+        if !numa.valid() {
+            return self;
+        }
+
+        for cpu in &numa.cpus {
+            let mut cpu_params = vec![format!("cpu,node-id={}", cpu.node_id)];
+            if let Some(socket_id) = cpu.socket_id {
+                cpu_params.push(format!("socket-id={}", socket_id));
+            }
+            if let Some(core_id) = cpu.core_id {
+                cpu_params.push(format!("core-id={}", core_id));
+            }
+            if let Some(thread_id) = cpu.thread_id {
+                cpu_params.push(format!("thread-id={}", thread_id));
+            }
+
+            self.qemu_params.push("-numa".to_owned());
+            self.qemu_params.push(cpu_params.join(","));
+        }
+
+        for dist in numa.full_distances() {
+            self.qemu_params.push("-numa".to_owned());
+            self.qemu_params.push(format!(
+                "dist,src={},dst={},val={}",
+                dist.src, dist.dst, dist.val
+            ));
+        }
+        self
+    }
+
     /// util functions, setup memory-related boolean configurations
     fn add_knobs_memory(&mut self, knobs: &Knobs) {
+        if !self.numa.nodes.is_empty() {
+            for (node_id, node) in self.numa.nodes.clone().iter().enumerate() {
+                let memdev = if node.memdev.is_empty() {
+                    format!("numa{}", node_id)
+                } else {
+                    node.memdev.clone()
+                };
+                let mem_path = node.mem_path(&self.memory).to_owned();
+                let obj_mem_params =
+                    self.memory_backend_params(knobs, &memdev, &node.size, &mem_path);
+                self.qemu_params.push("-object".to_owned());
+                self.qemu_params.push(obj_mem_params);
+
+                let mut numa_mem_params = vec![format!("node,nodeid={}", node_id)];
+                if !node.cpus.is_empty() {
+                    numa_mem_params.push(node.cpus_param());
+                }
+                numa_mem_params.push(format!("memdev={}", memdev));
+                self.qemu_params.push("-numa".to_owned());
+                self.qemu_params.push(numa_mem_params.join(","));
+            }
+            return;
+        }
+
         if self.memory.size.is_empty() {
             return;
         }
         let dimm_name = "dimm1";
+        let obj_mem_params =
+            self.memory_backend_params(knobs, dimm_name, &self.memory.size, &self.memory.path);
+        let numa_mem_params = format!("node,memdev={}", dimm_name);
+
+        self.qemu_params.push("-object".to_owned());
+        self.qemu_params.push(obj_mem_params);
+
+        if self.is_dimm_supported() {
+            self.qemu_params.push("-numa".to_owned());
+            self.qemu_params.push(numa_mem_params);
+        } else {
+            self.qemu_params.push("-machine".to_owned());
+            self.qemu_params
+                .push(format!("memory-backend={}", dimm_name));
+        }
+    }
+
+    /// the -object params of a guest memory backend of the given id and size,
+    /// mem_path is the file backing it with knobs.file_backed_mem
+    fn memory_backend_params(&self, knobs: &Knobs, id: &str, size: &str, mem_path: &str) -> String {
         let mut obj_mem_params = if knobs.hugepages {
             format!(
-                "memory-backend-file,id={},size={},mem-path=/dev/hugepages",
-                dimm_name, &self.memory.size
+                "memory-backend-file,id={},size={},mem-path={}",
+                id,
+                size,
+                qemu_escape(self.memory.hugepages_path())
             )
-        } else if knobs.file_backed_mem && !self.memory.path.is_empty() {
+        } else if knobs.file_backed_mem && !mem_path.is_empty() {
             format!(
-                "memory-backend-file,id={},size={},mem_path={}",
-                dimm_name, &self.memory.size, &self.memory.path
+                "memory-backend-file,id={},size={},mem-path={}",
+                id,
+                size,
+                qemu_escape(mem_path)
             )
         } else {
-            format!(
-                "memory-backend-file,id={},size={}",
-                dimm_name, self.memory.size
-            )
+            // a file backend needs a mem-path
+            format!("memory-backend-ram,id={},size={}", id, size)
         };
-        let numa_mem_params = format!("node,memdev={}", dimm_name);
 
         if knobs.mem_shared {
             obj_mem_params += ",share=on";
@@ -449,29 +1518,44 @@ impl QemuConfig {
         if knobs.mem_prealloc {
             obj_mem_params += ",prealloc=on";
         }
+        obj_mem_params
+    }
 
-        self.qemu_params.push("-object".to_owned());
-        self.qemu_params.push(obj_mem_params);
+    fn is_dimm_supported(&self) -> bool {
+        self.arch().supports_dimm() && self.machine.machine_type != MACHINE_TYPE_MICROVM
+    }
 
-        if self.is_dimm_supported() {
-            self.qemu_params.push("-numa".to_owned());
-            self.qemu_params.push(numa_mem_params);
+    /// the guest architecture, told by the machine type or the host
+    pub(crate) fn arch(&self) -> Arch {
+        Arch::from_machine_or_host(&self.machine.machine_type)
+    }
+
+    /// the virtio transport of a device, the architecture's default one if unset
+    pub(crate) fn virtio_transport<'a>(&self, transport: &'a str) -> &'a str {
+        if transport.is_empty() {
+            self.arch().default_transport()
         } else {
-            self.qemu_params.push("-machine".to_owned());
-            self.qemu_params
-                .push(format!("memory-backend={}", dimm_name));
+            transport
         }
     }
 
-    fn is_dimm_supported(&self) -> bool {
-        let arch = std::env::consts::ARCH;
-        match arch {
-            "x86_64" | "powerpc64" | "aarch64" | "x86" => {
-                self.machine.machine_type != MACHINE_TYPE_MICROVM
-            }
+    /// declare n iothreads named io0 to io<n-1>, for devices to be spread on
+    ///
+    /// Like `add_device()`, the iothreads are added to io_threads and only
+    /// emitted by `build_all()`, where the devices referring to them are checked.
+    pub fn add_iothread_pool(mut self, n: u32) -> Result<Self> {
+        if n == 0 {
+            return Err(anyhow!("an iothread pool needs at least one iothread"));
+        }
 
-            _ => false,
+        for i in 0..n {
+            let id = format!("io{}", i);
+            if self.io_threads.iter().any(|thread| thread.id == id) {
+                return Err(anyhow!("iothread {} is already declared", id));
+            }
+            self.io_threads.push(IoThread { id });
         }
+        Ok(self)
     }
 
     pub fn add_io_threads(mut self, io_threads: &[IoThread]) -> Self {
@@ -509,6 +1593,27 @@ impl QemuConfig {
                 format!("fd:{}", fds[0])
             }
             MIGRATION_DEFER => "defer".to_string(),
+            MIGRATION_RDMA => {
+                if incoming.host.is_empty() || incoming.port == 0 {
+                    log::warn!("rdma incoming migration requires host and port");
+                    return self;
+                }
+                format!("rdma:{}:{}", incoming.host, incoming.port)
+            }
+            MIGRATION_TCP => {
+                if incoming.host.is_empty() || incoming.port == 0 {
+                    log::warn!("tcp incoming migration requires host and port");
+                    return self;
+                }
+                format!("tcp:{}:{}", incoming.host, incoming.port)
+            }
+            MIGRATION_UNIX => {
+                if incoming.path.is_empty() {
+                    log::warn!("unix incoming migration requires a path");
+                    return self;
+                }
+                format!("unix:{}", incoming.path)
+            }
             _ => {
                 return self;
             }
@@ -516,6 +1621,12 @@ impl QemuConfig {
         self.qemu_params.push("-S".to_owned());
         self.qemu_params.push("-incoming".to_owned());
         self.qemu_params.push(uri);
+
+        if !incoming.tls_creds.is_empty() {
+            self.qemu_params.push("-global".to_owned());
+            self.qemu_params
+                .push(format!("migration.tls-creds={}", incoming.tls_creds));
+        }
         self
     }
 
@@ -527,6 +1638,37 @@ impl QemuConfig {
         self
     }
 
+    /// boot media, e.g. an installer iso
+    pub fn add_cdrom(mut self, path: &str) -> Self {
+        if !path.is_empty() {
+            self.qemu_params.push("-cdrom".to_owned());
+            self.qemu_params.push(path.to_owned());
+        }
+        self
+    }
+
+    /// the first ide hard disk
+    pub fn add_hda(mut self, path: &str) -> Self {
+        if !path.is_empty() {
+            self.qemu_params.push("-hda".to_owned());
+            self.qemu_params.push(path.to_owned());
+        }
+        self
+    }
+
+    /// attach a plain -drive, invalid ones are skipped here and refused by `try_build_all()`
+    pub fn add_drive(mut self, drive: &DriveSpec) -> Self {
+        if drive.valid() {
+            self.qemu_params.push("-drive".to_owned());
+            self.qemu_params.push(drive.params());
+        }
+        self
+    }
+
+    pub fn add_drives(self, drives: &[DriveSpec]) -> Self {
+        drives.iter().fold(self, |cfg, drive| cfg.add_drive(drive))
+    }
+
     pub fn add_pid_file(mut self, pid_file: &str) -> Self {
         if !pid_file.is_empty() {
             self.qemu_params.push("-pidfile".to_owned());
@@ -543,6 +1685,16 @@ impl QemuConfig {
         self
     }
 
+    pub fn add_plugins(mut self, plugins: &[Plugin]) -> Self {
+        for plugin in plugins {
+            if !plugin.valid() {
+                continue;
+            }
+            plugin.qemu_params(&mut self);
+        }
+        self
+    }
+
     pub fn add_fwcfg(mut self, fw_cfgs: &[FwCfg]) -> Self {
         // todo: qmplogger
         for fwcfg in fw_cfgs {
@@ -556,43 +1708,1670 @@ impl QemuConfig {
 }
 
 impl QemuConfig {
+    /// remove every occurrence of a flag from the built `qemu_params`,
+    /// along with its value if it takes one, e.g. both "-nographic" and "-m 4G"
+    ///
+    /// a flag is considered to take a value when the next param is not a flag
+    pub fn strip_flag(&mut self, flag: &str) {
+        let mut params = std::mem::take(&mut self.qemu_params).into_iter().peekable();
+
+        while let Some(param) = params.next() {
+            if param != flag {
+                self.qemu_params.push(param);
+                continue;
+            }
+            if params.peek().is_some_and(|next| !next.starts_with('-')) {
+                params.next();
+            }
+        }
+    }
+
+    /// the command line of the built config, quoted to be pasted into a shell
+    pub fn to_command_string(&self) -> String {
+        std::iter::once(&self.bin_path)
+            .chain(self.qemu_params.iter())
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// the devices, drives, chardevs, netdevs and objects of the config
+    /// in the format of qemu's -readconfig
+    pub fn to_qemu_cfg_string(&self) -> Result<String> {
+        let params = self.try_build_all()?.qemu_params;
+        let sections: Vec<String> = (0..params.len())
+            .filter_map(|i| qemu_cfg_option(&params, i))
+            .collect();
+        Ok(sections.join("\n"))
+    }
+
+    /// the -readconfig file content of the built config, and the params with
+    /// the options moved into it replaced by -readconfig path
+    pub(crate) fn qemu_cfg_params(&self, path: &str) -> (String, Vec<String>) {
+        let (sections, params) = split_qemu_cfg(&self.qemu_params, path);
+        (sections.join("\n"), params)
+    }
+
     pub fn dump(&self) {
         println!("{:?}", self.cpu_model);
     }
 }
 
-/// The devices are not cloned, need to re-add the device if cloned
-impl Clone for QemuConfig {
-    fn clone(&self) -> Self {
-        Self {
-            bin_path: self.bin_path.clone(),
-            uid: self.uid,
-            gid: self.gid,
-            groups: self.groups.clone(),
-            name: self.name.clone(),
-            uuid: self.uuid.clone(),
-            cpu_model: self.cpu_model.clone(),
-            seccomp_sandbox: self.seccomp_sandbox.clone(),
-            machine: self.machine.clone(),
-            devices: vec![],
-            fds: self.fds.clone(),
-            pflashs: self.pflashs.clone(),
-            io_threads: self.io_threads.clone(),
-            log_file: self.log_file.clone(),
-            pid_file: self.pid_file.clone(),
-            vga: self.vga.clone(),
-            kernel: self.kernel.clone(),
-            memory: self.memory.clone(),
-            smp: self.smp.clone(),
-            no_graphic: self.no_graphic,
-            global_params: self.global_params.clone(),
-            bios: self.bios.clone(),
-            qemu_params: self.qemu_params.clone(),
-            rtc: self.rtc.clone(),
-            knobs: self.knobs,
-            qmp_sockets: self.qmp_sockets.clone(),
-            incoming: self.incoming.clone(),
-            fw_cfgs: self.fw_cfgs.clone(),
+/// the options -readconfig takes, with their group and the key of their
+/// implied first suboption, e.g. the driver of -device virtio-rng-pci
+const QEMU_CFG_GROUPS: [(&str, &str, &str); 5] = [
+    ("-object", "object", "qom-type"),
+    ("-chardev", "chardev", "backend"),
+    ("-netdev", "netdev", "type"),
+    ("-drive", "drive", ""),
+    ("-device", "device", "driver"),
+];
+
+/// the -readconfig section of the option at params[i], None if it is not
+/// one -readconfig takes
+fn qemu_cfg_option(params: &[String], i: usize) -> Option<String> {
+    let (flag, value) = (params.get(i)?, params.get(i + 1)?);
+    let (_, group, implied_key) = QEMU_CFG_GROUPS.iter().find(|(f, _, _)| f == flag)?;
+    qemu_cfg_section(group, implied_key, value)
+}
+
+/// move the longest run of consecutive options -readconfig takes out of params
+/// into sections, replaced by -readconfig path, so that the options keep their order
+fn split_qemu_cfg(params: &[String], path: &str) -> (Vec<String>, Vec<String>) {
+    let mut longest = (0, 0, vec![]);
+    let mut i = 0;
+    while i < params.len() {
+        let start = i;
+        let mut sections = vec![];
+        while let Some(section) = qemu_cfg_option(params, i) {
+            sections.push(section);
+            i += 2;
+        }
+        if sections.len() > longest.2.len() {
+            longest = (start, i, sections);
         }
+        if i == start {
+            i += 1;
+        }
+    }
+
+    let (start, end, sections) = longest;
+    if sections.is_empty() {
+        return (sections, params.to_vec());
+    }
+    let mut rest = params[..start].to_vec();
+    rest.push("-readconfig".to_owned());
+    rest.push(path.to_owned());
+    rest.extend_from_slice(&params[end..]);
+    (sections, rest)
+}
+
+/// a -readconfig section of the suboptions in value, None if the file
+/// cannot hold them, i.e. a value with a double quote
+fn qemu_cfg_section(group: &str, implied_key: &str, value: &str) -> Option<String> {
+    let mut id = None;
+    let mut lines = vec![];
+    for (i, suboption) in split_suboptions(value).into_iter().enumerate() {
+        let (key, value) = match suboption.split_once('=') {
+            Some((key, value)) => (key.to_owned(), value.to_owned()),
+            None if i == 0 && !implied_key.is_empty() => (implied_key.to_owned(), suboption),
+            // a bare flag, e.g. the server of -chardev socket,server, turned
+            // off by a no prefix as qemu does, e.g. nowait
+            None => match suboption.strip_prefix("no") {
+                Some(key) if !key.is_empty() => (key.to_owned(), "off".to_owned()),
+                _ => (suboption, "on".to_owned()),
+            },
+        };
+        if value.contains('"') {
+            return None;
+        }
+        if key == "id" {
+            id = Some(value);
+        } else {
+            lines.push(format!("  {} = \"{}\"\n", key, value));
+        }
+    }
+
+    let header = match id {
+        Some(id) => format!("[{} \"{}\"]\n", group, id),
+        None => format!("[{}]\n", group),
+    };
+    Some(header + &lines.concat())
+}
+
+/// split a qemu option value on its commas, a double comma being a literal one
+fn split_suboptions(value: &str) -> Vec<String> {
+    let mut suboptions = vec![];
+    let mut current = String::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != ',' {
+            current.push(c);
+        } else if chars.peek() == Some(&',') {
+            chars.next();
+            current.push(',');
+        } else {
+            suboptions.push(std::mem::take(&mut current));
+        }
+    }
+    suboptions.push(current);
+    suboptions
+}
+
+/// quote arg for a posix shell, unless it is only made of characters the
+/// shell takes literally
+fn shell_quote(arg: &str) -> String {
+    let literal = |c: char| c.is_ascii_alphanumeric() || "_-+=:,./@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(literal) {
+        return arg.to_owned();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::{block, net};
+    use crate::device::{
+        CpuDevice, FloppyDevice, NetDevice, NetOffloads, NetdevBackend, PcDimm, RngDevice,
+        SerialDevice, VFIODevice, VhostUserDevice, VirtioPmem,
+    };
+    use crate::device_consts::{VHOSTUSERNET, VIRTIONETPCI};
+    use crate::types::{NumaCpu, NumaDistance, NumaNode};
+
+    /// an smp topology, a count of 0 is left to qemu
+    fn smp(cpus: u32, sockets: u32, cores: u32, threads: u32, max_cpus: u32) -> Smp {
+        Smp {
+            cpus,
+            cores,
+            threads,
+            sockets,
+            max_cpus,
+        }
+    }
+
+    #[test]
+    fn test_exit_policy_supersedes_knobs() {
+        let config = QemuConfig {
+            knobs: Knobs {
+                no_reboot: true,
+                no_shutdown: true,
+                ..Default::default()
+            },
+            exit_policy: Some(ExitPolicy::ExitOnPanic),
+            ..Default::default()
+        };
+        let config = config.build_all();
+
+        let count = |flag: &str| config.qemu_params.iter().filter(|p| *p == flag).count();
+        assert_eq!(count("--no-reboot"), 1);
+        assert_eq!(count("--no-shutdown"), 0);
+        assert!(config.qemu_params.ends_with(&[
+            "--no-reboot".to_owned(),
+            "-action".to_owned(),
+            "panic=shutdown".to_owned(),
+        ]));
+    }
+
+    #[test]
+    fn test_numa_topology() {
+        let numa = Numa {
+            cpus: vec![
+                NumaCpu {
+                    node_id: 0,
+                    socket_id: Some(0),
+                    ..Default::default()
+                },
+                NumaCpu {
+                    node_id: 1,
+                    socket_id: Some(1),
+                    ..Default::default()
+                },
+            ],
+            distances: vec![NumaDistance {
+                src: 0,
+                dst: 1,
+                val: 20,
+            }],
+            ..Default::default()
+        };
+        assert!(numa.valid());
+
+        let config = QemuConfig::default().add_numa(&numa);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-numa",
+                "cpu,node-id=0,socket-id=0",
+                "-numa",
+                "cpu,node-id=1,socket-id=1",
+                "-numa",
+                "dist,src=0,dst=1,val=20",
+                "-numa",
+                "dist,src=1,dst=0,val=20",
+            ]
+        );
+
+        let asymmetric = Numa {
+            distances: vec![
+                NumaDistance {
+                    src: 0,
+                    dst: 1,
+                    val: 20,
+                },
+                NumaDistance {
+                    src: 1,
+                    dst: 0,
+                    val: 30,
+                },
+            ],
+            ..Default::default()
+        };
+        assert!(!asymmetric.valid());
+    }
+
+    #[test]
+    fn test_numa_nodes() {
+        let mut config = QemuConfig {
+            numa: Numa {
+                nodes: vec![
+                    NumaNode {
+                        size: "2G".to_owned(),
+                        cpus: vec![0, 1, 2, 5],
+                        ..Default::default()
+                    },
+                    NumaNode {
+                        size: "2G".to_owned(),
+                        cpus: vec![3, 4],
+                        memdev: "mem1".to_owned(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.add_knobs_memory(&Knobs::default());
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-object",
+                "memory-backend-ram,id=numa0,size=2G",
+                "-numa",
+                "node,nodeid=0,cpus=0-2,cpus=5,memdev=numa0",
+                "-object",
+                "memory-backend-ram,id=mem1,size=2G",
+                "-numa",
+                "node,nodeid=1,cpus=3-4,memdev=mem1",
+            ]
+        );
+
+        // the nodes split the whole memory
+        config.qemu_params.clear();
+        assert!(config.check_numa_memory().is_err());
+        config.memory.size = "3G".to_owned();
+        let err = config.check_numa_memory().err().unwrap();
+        assert!(err.to_string().contains("numa nodes take"));
+        config.memory.size = "4G".to_owned();
+        assert!(config.check_numa_memory().is_ok());
+
+        // each node is backed by its own file
+        config.knobs.file_backed_mem = true;
+        config.memory.path = "/var/lib/guest.mem".to_owned();
+        let err = config.validate().err().unwrap();
+        assert!(err.to_string().contains("share the memory file"));
+        config.numa.nodes[1].mem_path = "/var/lib/guest1.mem".to_owned();
+        assert!(config.validate().is_ok());
+        config.add_knobs_memory(&config.knobs.clone());
+        assert!(config.qemu_params.contains(
+            &"memory-backend-file,id=numa0,size=2G,mem-path=/var/lib/guest.mem".to_owned()
+        ));
+        assert!(config.qemu_params.contains(
+            &"memory-backend-file,id=mem1,size=2G,mem-path=/var/lib/guest1.mem".to_owned()
+        ));
+    }
+
+    #[test]
+    fn test_hugepages_path() {
+        let mut config = QemuConfig {
+            memory: Memory {
+                size: "4G".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let knobs = Knobs {
+            hugepages: true,
+            ..Default::default()
+        };
+        let mut default_path = config.clone();
+        default_path.add_knobs_memory(&knobs);
+        assert!(default_path.qemu_params[1].ends_with("mem-path=/dev/hugepages"));
+
+        config.memory.hugepages_path = "/dev/hugepages1G".to_owned();
+        config.add_knobs_memory(&knobs);
+        assert!(config.qemu_params[1].ends_with("mem-path=/dev/hugepages1G"));
+    }
+
+    #[test]
+    fn test_file_backed_memory() {
+        let mut config = QemuConfig {
+            memory: Memory {
+                size: "1G".to_owned(),
+                path: "/var/lib/guest.mem".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let knobs = Knobs {
+            file_backed_mem: true,
+            ..Default::default()
+        };
+        config.add_knobs_memory(&knobs);
+        assert_eq!(
+            config.qemu_params[1],
+            "memory-backend-file,id=dimm1,size=1G,mem-path=/var/lib/guest.mem"
+        );
+    }
+
+    #[test]
+    fn test_vnc_tls_creds() {
+        let config = QemuConfig {
+            tls_creds: vec![TlsCreds {
+                id: "tls0".to_owned(),
+                dir: "/etc/pki/qemu".to_owned(),
+                endpoint: "server".to_owned(),
+                verify_peer: true,
+            }],
+            vnc: Vnc {
+                display: ":0".to_owned(),
+                tls_creds: "tls0".to_owned(),
+                dynamic_port: false,
+            },
+            ..Default::default()
+        };
+        let config = config.build_all();
+
+        let params = config.qemu_params.join(" ");
+        assert!(params.contains(
+            "-object tls-creds-x509,id=tls0,dir=/etc/pki/qemu,endpoint=server,verify-peer=on"
+        ));
+        assert!(params.contains("-vnc :0,tls-creds=tls0"));
+    }
+
+    #[test]
+    fn test_tls_creds_require_dir() {
+        let creds = TlsCreds {
+            id: "tls0".to_owned(),
+            endpoint: "client".to_owned(),
+            ..Default::default()
+        };
+        assert!(!creds.valid());
+    }
+
+    #[test]
+    fn test_cpu_hotplug_warning() {
+        let mut config = QemuConfig {
+            machine: Machine {
+                machine_type: MACHINE_TYPE_MICROVM.to_owned(),
+                ..Default::default()
+            },
+            smp: smp(2, 0, 0, 0, 4),
+            ..Default::default()
+        };
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("cpu hotplug"));
+        assert!(warnings[0].contains(MACHINE_TYPE_MICROVM));
+
+        config.machine.machine_type = "pc-q35-7.2".to_owned();
+        assert!(config.warnings().is_empty());
+
+        // the cold-plugged vcpus need a free slot each
+        let cpu = |id: &str, socket_id| CpuDevice {
+            id: id.to_owned(),
+            driver: "host-x86_64-cpu".to_owned(),
+            socket_id: Some(socket_id),
+            core_id: Some(0),
+            thread_id: Some(0),
+        };
+        config.devices.push(Rc::new(cpu("cpu2", 2)));
+        config.devices.push(Rc::new(cpu("cpu3", 3)));
+        assert!(config.warnings().is_empty());
+        assert!(config
+            .build_all()
+            .qemu_params
+            .contains(&"host-x86_64-cpu,id=cpu2,socket-id=2,core-id=0,thread-id=0".to_owned()));
+
+        config.smp.max_cpus = 3;
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("2 cold-plugged vcpus"));
+        assert!(warnings[0].contains("leaves 1 above"));
+    }
+
+    #[test]
+    fn test_machine_presets() {
+        let presets = [
+            (MachinePreset::Q35Modern, "q35", 4),
+            (MachinePreset::PcLegacy, "pc", 0),
+            (MachinePreset::Arm64Virt, "virt", 4),
+            (MachinePreset::Microvm, MACHINE_TYPE_MICROVM, 0),
+        ];
+
+        for (preset, machine_type, root_ports) in presets {
+            let config = QemuConfig::builder().apply_preset(preset);
+            assert_eq!(config.machine.machine_type, machine_type);
+            assert_eq!(config.machine.acceleration, "kvm");
+            assert!(config.knobs.no_user_config);
+
+            let config = config.build_all();
+            let built_root_ports = config
+                .qemu_params
+                .iter()
+                .filter(|p| p.starts_with("pcie-root-port,"))
+                .count();
+            assert_eq!(built_root_ports, root_ports);
+        }
+
+        let config = QemuConfig::builder()
+            .apply_preset(MachinePreset::Q35Modern)
+            .build_all();
+        assert!(config
+            .qemu_params
+            .contains(&"pcie-root-port,id=rp0,bus=pcie.0,chassis=1,slot=1".to_owned()));
+    }
+
+    #[test]
+    fn test_kernel_params_require_kernel() {
+        let initrd_only = Kernel {
+            initrd_path: "/boot/initrd.img".to_owned(),
+            ..Default::default()
+        };
+        let err = QemuConfig::default()
+            .add_kernel(&initrd_only)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("initrd_path"));
+
+        let append_only = Kernel {
+            params: "console=ttyS0".to_owned(),
+            ..Default::default()
+        };
+        let config = QemuConfig {
+            kernel: append_only,
+            ..Default::default()
+        };
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("-append requires -kernel"));
+    }
+
+    #[test]
+    fn test_strip_flag() {
+        let params = ["-name", "vm", "-nographic", "-m", "4G", "-daemonize"];
+        let mut config = QemuConfig {
+            qemu_params: params.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+
+        config.strip_flag("-nographic");
+        assert_eq!(
+            config.qemu_params,
+            vec!["-name", "vm", "-m", "4G", "-daemonize"]
+        );
+
+        config.strip_flag("-m");
+        assert_eq!(config.qemu_params, vec!["-name", "vm", "-daemonize"]);
+
+        config.strip_flag("-daemonize");
+        assert_eq!(config.qemu_params, vec!["-name", "vm"]);
+    }
+
+    #[test]
+    fn test_to_command_string() {
+        let config = QemuConfig {
+            bin_path: "/usr/bin/qemu-system-x86_64".to_owned(),
+            qemu_params: [
+                "-name",
+                "my vm",
+                "-fw_cfg",
+                "name=opt/x,string=it's",
+                "-append",
+                "",
+            ]
+            .iter()
+            .map(|p| p.to_string())
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.to_command_string(),
+            r"/usr/bin/qemu-system-x86_64 -name 'my vm' -fw_cfg 'name=opt/x,string=it'\''s' -append ''"
+        );
+    }
+
+    #[test]
+    fn test_rtc_host_slew_tcg_warning() {
+        let mut config = QemuConfig {
+            rtc: Rtc {
+                base: "utc".to_owned(),
+                clock: "host".to_owned(),
+                drift_fix: "slew".to_owned(),
+            },
+            ..Default::default()
+        };
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("driftfix=slew"));
+        // only a warning, the rtc is still emitted
+        assert!(config.rtc.valid());
+
+        config.machine.acceleration = "kvm".to_owned();
+        assert!(config.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_device_warnings() {
+        let config = QemuConfig::default().add_device(Box::new(NetDevice {
+            offloads: NetOffloads {
+                csum: Some(false),
+                host_tso4: Some(true),
+                ..Default::default()
+            },
+            ..net("net0")
+        }));
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("net device net0: host_tso4=on"));
+    }
+
+    #[test]
+    fn test_add_rtc() {
+        let rtc = Rtc {
+            base: "utc".to_owned(),
+            clock: "host".to_owned(),
+            drift_fix: "slew".to_owned(),
+        };
+        let config = QemuConfig::default().add_rtc(&rtc);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-rtc", "base=utc,driftfix=slew,clock=host"]
+        );
+    }
+
+    #[test]
+    fn test_add_rtc_base_only() {
+        let rtc = Rtc {
+            base: "localtime".to_owned(),
+            ..Default::default()
+        };
+        let config = QemuConfig::default().add_rtc(&rtc);
+        assert_eq!(config.qemu_params, vec!["-rtc", "base=localtime"]);
+    }
+
+    #[test]
+    fn test_vga_types() {
+        let config = QemuConfig::default().add_vga("").unwrap();
+        assert!(config.qemu_params.is_empty());
+
+        let config = QemuConfig::default().add_vga("qxl").unwrap();
+        assert_eq!(config.qemu_params, vec!["-vga", "qxl"]);
+
+        let err = QemuConfig::default().add_vga("qxll").err().unwrap();
+        assert!(err.to_string().contains("qxll"));
+    }
+
+    #[test]
+    fn test_validate_paths_skips_missing_romfile() {
+        let net = NetDevice {
+            pci: PciOptions {
+                romfile: "/nonexistent/efi-virtio.rom".to_owned(),
+                ..Default::default()
+            },
+            ..net("net0")
+        };
+        let devices: Vec<Box<dyn Device>> = vec![Box::new(net)];
+
+        let config = QemuConfig::default().add_devices(&devices);
+        assert_eq!(config.qemu_params.len(), 2);
+
+        let config = QemuConfig {
+            validate_paths: true,
+            ..Default::default()
+        };
+        let config = config.add_devices(&devices);
+        assert!(config.qemu_params.is_empty());
+    }
+
+    #[test]
+    fn test_actions_supersede_legacy_flags() {
+        let mut config = QemuConfig {
+            knobs: Knobs {
+                no_reboot: true,
+                no_shutdown: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config
+            .actions
+            .insert("reboot".to_owned(), "shutdown".to_owned());
+        config
+            .actions
+            .insert("shutdown".to_owned(), "poweroff".to_owned());
+        config
+            .actions
+            .insert("panic".to_owned(), "pause".to_owned());
+
+        let built = config.build_all();
+        let params = built.qemu_params.join(" ");
+        assert!(params.contains("-action panic=pause,reboot=shutdown,shutdown=poweroff"));
+        assert!(!params.contains("--no-reboot"));
+        assert!(!params.contains("--no-shutdown"));
+
+        config
+            .actions
+            .insert("panic".to_owned(), "explode".to_owned());
+        assert!(config.try_build_all().is_err());
+    }
+
+    #[test]
+    fn test_plugins() {
+        let mut plugin = Plugin {
+            path: "/path/lib.so".to_owned(),
+            ..Default::default()
+        };
+        plugin.args.insert("b".to_owned(), "2".to_owned());
+        plugin.args.insert("a".to_owned(), "1".to_owned());
+
+        let plugins = vec![plugin, Plugin::default()];
+        let config = QemuConfig::default().add_plugins(&plugins);
+        assert_eq!(config.qemu_params, vec!["-plugin", "/path/lib.so,a=1,b=2"]);
+    }
+
+    #[test]
+    fn test_recommended_seccomp() {
+        let mut config = QemuConfig::default();
+        assert_eq!(config.recommended_seccomp(), Seccomp::hardened());
+        assert_eq!(
+            Seccomp::hardened().param(),
+            "on,obsolete=deny,elevateprivileges=deny,spawn=deny,resourcecontrol=deny"
+        );
+
+        config.devices.push(Rc::new(VFIODevice {
+            driver: VFIOPCI.to_owned(),
+            bdf: "0000:01:00.0".to_owned(),
+            mdev_path: String::new(),
+            display: false,
+            ramfb: false,
+            x_igd_opregion: false,
+            x_vga: false,
+            pci: PciOptions::default(),
+        }));
+        let seccomp = config.recommended_seccomp();
+        assert!(!seccomp.deny_resource_control);
+        assert!(seccomp.deny_spawn);
+    }
+
+    #[test]
+    fn test_memory_devices_require_maxmem_and_slots() {
+        let mut config = QemuConfig {
+            memory: Memory {
+                size: "4G".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.devices.push(Rc::new(VirtioPmem {
+            id: "pmem0".to_owned(),
+            memdev: "mem1".to_owned(),
+            mem_path: "/pmem".to_owned(),
+            size: 4 << 30,
+        }));
+        assert!(config.try_build_all().is_err());
+
+        config.memory.max_memory = "16G".to_owned();
+        config.memory.slots = 2;
+        let built = config.try_build_all().unwrap();
+        assert!(built
+            .qemu_params
+            .contains(&"virtio-pmem-pci,memdev=mem1,id=pmem0".to_owned()));
+    }
+
+    #[test]
+    fn test_pc_dimms_fit_in_maxmem() {
+        let mut config = QemuConfig {
+            memory: Memory {
+                size: "2G".to_owned(),
+                slots: 2,
+                max_memory: "4G".to_owned(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for i in 0..2 {
+            config.devices.push(Rc::new(PcDimm {
+                id: format!("dimm{}", i),
+                memdev: format!("m{}", i),
+                size: "1G".to_owned(),
+                node: Some(0),
+            }));
+        }
+        let built = config.try_build_all().unwrap();
+        assert!(built
+            .qemu_params
+            .contains(&"memory-backend-ram,id=m0,size=1G".to_owned()));
+        assert!(built
+            .qemu_params
+            .contains(&"pc-dimm,id=dimm1,memdev=m1,node=0".to_owned()));
+
+        config.memory.max_memory = "3G".to_owned();
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("more than maxmem"));
+
+        config.memory.size = "2X".to_owned();
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("invalid memory.size"));
+    }
+
+    #[test]
+    fn test_add_disk_detects_format() {
+        let config = QemuConfig::default()
+            .add_disk("/images/root.qcow2")
+            .add_disk("/images/data.img")
+            .build_all();
+
+        assert!(config
+            .qemu_params
+            .contains(&"id=disk0,file=/images/root.qcow2,format=qcow2,if=none".to_owned()));
+        assert!(config
+            .qemu_params
+            .contains(&"id=disk1,file=/images/data.img,format=raw,if=none".to_owned()));
+        assert!(config
+            .qemu_params
+            .contains(&"virtio-blk-pci,drive=disk1".to_owned()));
+
+        // the id skips past drives other devices already took
+        let config = QemuConfig::default()
+            .add_device(Box::new(FloppyDevice {
+                id: "disk0".to_owned(),
+                file: "/images/boot.img".to_owned(),
+                unit: 0,
+                bus: String::new(),
+            }))
+            .add_disk("/images/root.qcow2")
+            .build_all();
+        assert!(config
+            .qemu_params
+            .contains(&"id=disk1,file=/images/root.qcow2,format=qcow2,if=none".to_owned()));
+    }
+
+    #[test]
+    fn test_incoming_rdma() {
+        let mut incoming = Incoming {
+            migration_type: MIGRATION_RDMA.to_owned(),
+            host: "192.168.1.2".to_owned(),
+            port: 4444,
+            ..Default::default()
+        };
+        let config = QemuConfig::default().add_incoming(&incoming);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-S", "-incoming", "rdma:192.168.1.2:4444"]
+        );
+
+        incoming.port = 0;
+        let config = QemuConfig::default().add_incoming(&incoming);
+        assert!(config.qemu_params.is_empty());
+    }
+
+    #[test]
+    fn test_incoming_tcp_and_unix() {
+        let mut incoming = Incoming {
+            migration_type: MIGRATION_TCP.to_owned(),
+            host: "0.0.0.0".to_owned(),
+            port: 4444,
+            ..Default::default()
+        };
+        let config = QemuConfig::default().add_incoming(&incoming);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-S", "-incoming", "tcp:0.0.0.0:4444"]
+        );
+
+        incoming.host.clear();
+        assert!(QemuConfig::default()
+            .add_incoming(&incoming)
+            .qemu_params
+            .is_empty());
+
+        let mut incoming = Incoming {
+            migration_type: MIGRATION_UNIX.to_owned(),
+            path: "/run/migrate.sock".to_owned(),
+            ..Default::default()
+        };
+        let config = QemuConfig::default().add_incoming(&incoming);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-S", "-incoming", "unix:/run/migrate.sock"]
+        );
+
+        incoming.path.clear();
+        let config = QemuConfig {
+            incoming,
+            ..Default::default()
+        };
+        let err = config.validate().err().unwrap();
+        assert!(err.to_string().contains("requires a path"));
+    }
+
+    #[test]
+    fn test_device_specs_from_toml() {
+        let path = std::env::temp_dir().join(format!("device-specs-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[device_specs]]
+            kind = "net"
+            id = "net0"
+            mac = "52:54:00:12:34:56"
+
+            [[device_specs]]
+            kind = "block"
+            id = "disk0"
+            file = "/images/root.qcow2"
+            format = "qcow2"
+            "#,
+        )
+        .unwrap();
+
+        let config = QemuConfig::from_toml(path.to_str().unwrap()).build_all();
+        let _ = std::fs::remove_file(&path);
+        assert!(config
+            .qemu_params
+            .contains(&"virtio-net-pci,netdev=net0,mac=52:54:00:12:34:56".to_owned()));
+        assert!(config
+            .qemu_params
+            .contains(&"id=disk0,file=/images/root.qcow2,format=qcow2,if=none".to_owned()));
+        assert!(config
+            .qemu_params
+            .contains(&"virtio-blk-pci,drive=disk0".to_owned()));
+    }
+
+    #[test]
+    fn test_boot_indices() {
+        let disk = |id: &str, boot_index| BlockDevice {
+            boot_index,
+            ..block(id, &format!("/images/{}.img", id))
+        };
+
+        let mut config = QemuConfig::default();
+        config.devices.push(Rc::new(disk("disk0", Some(0))));
+        config.devices.push(Rc::new(disk("disk1", Some(1))));
+        let built = config.try_build_all().unwrap();
+        assert!(built
+            .qemu_params
+            .contains(&"virtio-blk-pci,drive=disk0,bootindex=0".to_owned()));
+        assert!(built
+            .qemu_params
+            .contains(&"virtio-blk-pci,drive=disk1,bootindex=1".to_owned()));
+
+        config.devices.push(Rc::new(disk("disk2", Some(1))));
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("bootindex 1"));
+    }
+
+    #[test]
+    fn test_bios_search_paths() {
+        let paths = vec![
+            "/usr/share/seabios".to_owned(),
+            "/usr/share/qemu".to_owned(),
+        ];
+        let config = QemuConfig::default().add_bios_search_paths(&paths);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-L", "/usr/share/seabios", "-L", "/usr/share/qemu"]
+        );
+
+        let config = QemuConfig {
+            validate_paths: true,
+            ..Default::default()
+        };
+        let paths = vec!["/nonexistent/firmware".to_owned()];
+        assert!(config.add_bios_search_paths(&paths).qemu_params.is_empty());
+    }
+
+    #[test]
+    fn test_required_host_features() {
+        let mut config = QemuConfig::default().apply_preset(MachinePreset::PcLegacy);
+        config.knobs.hugepages = true;
+        assert_eq!(
+            config.required_host_features(),
+            HashSet::from([HostFeature::Kvm, HostFeature::Hugepages])
+        );
+
+        config.devices.push(Rc::new(VFIODevice {
+            driver: VFIOPCI.to_owned(),
+            bdf: "0000:01:00.0".to_owned(),
+            mdev_path: String::new(),
+            display: false,
+            ramfb: false,
+            x_igd_opregion: false,
+            x_vga: false,
+            pci: PciOptions::default(),
+        }));
+        assert_eq!(
+            config.required_host_features(),
+            HashSet::from([HostFeature::Kvm, HostFeature::Hugepages, HostFeature::Vfio])
+        );
+
+        config.devices.push(Rc::new(VhostUserDevice {
+            id: "net0".to_owned(),
+            device_type: VHOSTUSERNET.to_owned(),
+            socket_path: "/run/vhost-user-net.sock".to_owned(),
+            char_id: "char-net0".to_owned(),
+            tag: String::new(),
+            transport: String::new(),
+            virtio: VirtioOptions::default(),
+        }));
+        assert_eq!(
+            config.required_host_features(),
+            HashSet::from([
+                HostFeature::Kvm,
+                HostFeature::Hugepages,
+                HostFeature::Vfio,
+                HostFeature::Vhost
+            ])
+        );
+    }
+
+    #[test]
+    fn test_microvm_rejects_pci_devices() {
+        let net = |driver: &str| NetDevice {
+            driver: driver.to_owned(),
+            ..net("net0")
+        };
+
+        let mut config = QemuConfig::default().apply_preset(MachinePreset::Microvm);
+        config.devices.push(Rc::new(net(VIRTIONETPCI)));
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("virtio-net-pci is a pci device"));
+
+        let mut config = QemuConfig::default().apply_preset(MachinePreset::Microvm);
+        config.devices.push(Rc::new(net("virtio-net-device")));
+        assert!(config.try_build_all().is_ok());
+
+        let serial = |transport: &str| SerialDevice {
+            id: "serial0".to_owned(),
+            transport: transport.to_owned(),
+            ports: vec![],
+            pci: PciOptions::default(),
+        };
+        let mut config = QemuConfig::default().apply_preset(MachinePreset::Microvm);
+        config.devices.push(Rc::new(serial("")));
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("virtio-serial is a pci device"));
+
+        let mut config = QemuConfig::default().apply_preset(MachinePreset::Microvm);
+        config.devices.push(Rc::new(serial("mmio")));
+        assert!(config.try_build_all().is_ok());
+    }
+
+    #[test]
+    fn test_pci_bus_follows_the_resolved_transport() {
+        let rng = RngDevice {
+            id: "rng0".to_owned(),
+            filename: String::new(),
+            transport: String::new(),
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        };
+
+        let mut config = QemuConfig::default();
+        config.machine.machine_type = "q35".to_owned();
+        assert!(rng.on_pci_bus(&config));
+        config.machine.machine_type = "s390-ccw-virtio".to_owned();
+        assert!(!rng.on_pci_bus(&config));
+    }
+
+    #[test]
+    fn test_default_machine() {
+        let config = QemuConfig::default().build_all();
+        let machine = Arch::from_machine_or_host("").default_machine();
+        assert!(config
+            .qemu_params
+            .windows(2)
+            .any(|param| param == ["-machine", machine]));
+    }
+
+    #[test]
+    fn test_ccw_devnos_are_unique() {
+        let net = |id: &str, devno: &str| NetDevice {
+            driver: "virtio-net-ccw".to_owned(),
+            virtio: VirtioOptions {
+                devno: devno.to_owned(),
+                ..Default::default()
+            },
+            ..net(id)
+        };
+
+        let mut config = QemuConfig::default();
+        config.machine.machine_type = "s390-ccw-virtio".to_owned();
+        config.devices.push(Rc::new(net("net0", "fe.0.0001")));
+        config.devices.push(Rc::new(net("net1", "fe.0.0002")));
+        let built = config.try_build_all().unwrap();
+        assert!(built
+            .qemu_params
+            .contains(&"virtio-net-ccw,netdev=net0,devno=fe.0.0001".to_owned()));
+        assert!(built
+            .qemu_params
+            .contains(&"virtio-net-ccw,netdev=net1,devno=fe.0.0002".to_owned()));
+
+        config.devices.push(Rc::new(net("net2", "fe.0.0002")));
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("devno fe.0.0002"));
+    }
+
+    #[test]
+    fn test_user_and_tap_nics_from_toml() {
+        let path = std::env::temp_dir().join(format!("nics-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[device_specs]]
+            kind = "net"
+            id = "user0"
+            netdev_type = "user"
+
+            [[device_specs]]
+            kind = "net"
+            id = "tap0"
+            netdev_type = "tap"
+            ifname = "vmtap0"
+            script = "no"
+            downscript = "no"
+            mac = "52:54:00:12:34:56"
+            "#,
+        )
+        .unwrap();
+
+        let config = QemuConfig::from_toml(path.to_str().unwrap()).build_all();
+        let _ = std::fs::remove_file(&path);
+        let params = config.qemu_params.join(" ");
+        assert!(params.contains("-netdev user,id=user0 -device virtio-net-pci,netdev=user0"));
+        assert!(params.contains(
+            "-netdev tap,id=tap0,ifname=vmtap0,script=no,downscript=no \
+             -device virtio-net-pci,netdev=tap0,mac=52:54:00:12:34:56"
+        ));
+    }
+
+    #[test]
+    fn test_tap_nic_requires_ifname() {
+        let mut net = NetDevice {
+            netdev: NetdevBackend {
+                netdev_type: "tap".to_owned(),
+                ..Default::default()
+            },
+            ..net("tap0")
+        };
+        assert!(!net.valid());
+
+        net.netdev.ifname = "vm,tap0".to_owned();
+        let mut config = QemuConfig::default();
+        net.set_qemu_params(&mut config);
+        assert_eq!(config.qemu_params[1], "tap,id=tap0,ifname=vm,,tap0");
+    }
+
+    #[test]
+    fn test_tap_nic_with_passed_fds() {
+        use std::os::unix::io::AsRawFd;
+
+        let (tap, _tap_writer) = std::io::pipe().unwrap();
+        let mut net = NetDevice {
+            netdev: NetdevBackend {
+                netdev_type: "tap".to_owned(),
+                fds: vec![tap.as_raw_fd()],
+                ..Default::default()
+            },
+            ..net("n0")
+        };
+        assert!(net.valid());
+
+        let mut config = QemuConfig::default();
+        net.set_qemu_params(&mut config);
+        assert_eq!(config.qemu_params[..2], ["-netdev", "tap,id=n0,fd=3"]);
+        assert_eq!(config.fds, vec![tap.as_raw_fd()]);
+
+        // multiqueue, numbered after the fds already handed to qemu
+        let (queue, _queue_writer) = std::io::pipe().unwrap();
+        net.netdev.fds = vec![tap.as_raw_fd(), queue.as_raw_fd()];
+        net.set_qemu_params(&mut config);
+        assert!(config.qemu_params.contains(&"tap,id=n0,fds=4:5".to_owned()));
+
+        // an opened tap is not named or configured by qemu
+        net.netdev.ifname = "vmtap0".to_owned();
+        assert!(!net.valid());
+        net.netdev.ifname = String::new();
+        net.netdev.script = "no".to_owned();
+        assert!(!net.valid());
+    }
+
+    #[test]
+    fn test_virtio_scsi_iothread_must_be_declared() {
+        let mut config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "device_specs": [
+                { "kind": "scsi", "id": "scsi0", "iothread": "io0", "num_queues": 4 }
+            ]
+        }))
+        .unwrap();
+        assert!(config.clone().try_build_all().is_err());
+
+        config.io_threads.push(IoThread {
+            id: "io0".to_owned(),
+        });
+        let params = config.build_all().qemu_params.join(" ");
+        assert!(params.contains("-device virtio-scsi-pci,id=scsi0,iothread=io0,num_queues=4"));
+    }
+
+    #[test]
+    fn test_intel_iommu_needs_q35() {
+        let mut config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "machine": { "machine_type": "pc" },
+            "knobs": { "iommu_platform": true },
+            "device_specs": [
+                { "kind": "iommu", "intremap": true, "caching_mode": true },
+                { "kind": "net", "id": "net0" }
+            ]
+        }))
+        .unwrap();
+        assert!(config.clone().try_build_all().is_err());
+
+        config.machine.machine_type = "q35".to_owned();
+        let params = config.build_all().qemu_params.join(" ");
+        assert!(params.contains("-device intel-iommu,intremap=on,caching-mode=on"));
+        assert!(params.contains("-device virtio-net-pci,netdev=net0,iommu_platform=on"));
+    }
+
+    #[test]
+    fn test_fw_cfgs_from_toml() {
+        let path = std::env::temp_dir().join(format!("fwcfg-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            [[fw_cfgs]]
+            name = "opt/com.example/token"
+            str = "s3cr3t"
+            "#,
+        )
+        .unwrap();
+
+        let config = QemuConfig::from_toml(path.to_str().unwrap()).build_all();
+        let _ = std::fs::remove_file(&path);
+        assert!(config
+            .qemu_params
+            .windows(2)
+            .any(|w| w == ["-fw_cfg", "name=opt/com.example/token,string=s3cr3t"]));
+    }
+
+    #[test]
+    fn test_smp_topology_errors() {
+        // unset max_cpus, or a partial topology, is left to qemu
+        assert!(QemuConfig::default().add_smp(&smp(2, 1, 2, 1, 0)).is_ok());
+        assert!(QemuConfig::default().add_smp(&smp(2, 0, 2, 1, 8)).is_ok());
+
+        assert!(QemuConfig::default().add_smp(&smp(2, 2, 2, 1, 8)).is_err());
+        assert!(QemuConfig::default().add_smp(&smp(8, 2, 2, 2, 4)).is_err());
+        let config = QemuConfig::default().add_smp(&smp(2, 2, 2, 2, 8)).unwrap();
+        assert_eq!(
+            config.qemu_params,
+            vec!["-smp", "2,cores=2,threads=2,sockets=2,maxcpus=8"]
+        );
+    }
+
+    #[test]
+    fn test_try_from_toml_errors() {
+        let missing = std::env::temp_dir().join("nonexistent-qemu-launch.toml");
+        let err = QemuConfig::try_from_toml(missing.to_str().unwrap())
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("nonexistent-qemu-launch.toml"));
+
+        let broken = std::env::temp_dir().join(format!("broken-{}.toml", std::process::id()));
+        std::fs::write(&broken, "[machine\nmachine_type = q35").unwrap();
+        let err = QemuConfig::try_from_toml(broken.to_str().unwrap())
+            .err()
+            .unwrap();
+        let _ = std::fs::remove_file(&broken);
+        assert!(err.to_string().contains("failed to parse"));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(QemuConfig::default().validate().is_ok());
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "smp": { "cpus": 4, "max_cpus": 2 },
+            "knobs": { "hugepages": true, "file_backed_mem": true },
+            "incoming": { "migration_type": "fd", "fd": -1 },
+            "qmp_sockets": [{ "socket_type": "tcp", "host": "localhost" }],
+            "vga": "qxll",
+            "drives": [{ "file": "disk.img", "if": "usb" }],
+        }))
+        .unwrap();
+        let err = config.validate().err().unwrap().to_string();
+        for problem in [
+            "unknown vga type qxll",
+            "invalid drive file=disk.img,if=usb",
+            "smp.max_cpus",
+            "knobs.hugepages requires memory.size",
+            "knobs.file_backed_mem requires memory.path",
+            "fd -1",
+            "tcp qmp socket",
+        ] {
+            assert!(err.contains(problem), "{} not in {}", problem, err);
+        }
+
+        // only a strict config fails to build on them
+        let mut config = config;
+        config.smp.max_cpus = 0;
+        config.vga.clear();
+        config.drives.clear();
+        assert!(config.try_build_all().is_ok());
+        config.strict = true;
+        assert!(config.try_build_all().is_err());
+    }
+
+    #[test]
+    fn test_raw_args_from_toml() {
+        let content = r#"
+            raw_args = ["-device", "virtio-gpu-pci", "-drive", "file=disk.img,if=none"]
+        "#;
+        let config: QemuConfig = toml::from_str(content).unwrap();
+        // through a toml::Value, which puts the plain values before the tables
+        let value = toml::Value::try_from(&config).unwrap();
+        let reloaded: QemuConfig = toml::from_str(&toml::to_string(&value).unwrap()).unwrap();
+        assert_eq!(reloaded.raw_args, config.raw_args);
+
+        let built = reloaded.build_all();
+        assert!(built.qemu_params.ends_with(&[
+            "-device".to_owned(),
+            "virtio-gpu-pci".to_owned(),
+            "-drive".to_owned(),
+            "file=disk.img,if=none".to_owned(),
+        ]));
+    }
+
+    #[test]
+    fn test_drives() {
+        let config = QemuConfig::builder()
+            .add_cdrom("/isos/installer.iso")
+            .add_hda("/images/disk.img");
+        assert_eq!(
+            config.qemu_params,
+            vec!["-cdrom", "/isos/installer.iso", "-hda", "/images/disk.img"]
+        );
+
+        let config: QemuConfig = toml::from_str(
+            r#"
+            [[drives]]
+            file = "/images/disk,1.qcow2"
+            format = "qcow2"
+            if = "virtio"
+
+            [[drives]]
+            file = "/images/disk.img"
+            media = "tape"
+        "#,
+        )
+        .unwrap();
+        let config = QemuConfig::default().add_drives(&config.drives);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-drive",
+                "file=/images/disk,,1.qcow2,format=qcow2,if=virtio"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_boot() {
+        let config = QemuConfig::default().add_boot(&Boot::default()).unwrap();
+        assert!(config.qemu_params.is_empty());
+
+        let boot = Boot {
+            order: "dc".to_owned(),
+            menu: true,
+            reboot_timeout: Some(5000),
+            ..Default::default()
+        };
+        let config = QemuConfig::default().add_boot(&boot).unwrap();
+        assert_eq!(
+            config.qemu_params,
+            vec!["-boot", "order=dc,menu=on,reboot-timeout=5000"]
+        );
+
+        let boot = Boot {
+            order: "cx".to_owned(),
+            ..Default::default()
+        };
+        let err = QemuConfig::default().add_boot(&boot).err().unwrap();
+        assert!(err.to_string().contains("cx"));
+    }
+
+    #[test]
+    fn test_display() {
+        let config = QemuConfig::default().add_display("gtk,gl=on").unwrap();
+        assert_eq!(config.qemu_params, vec!["-display", "gtk,gl=on"]);
+
+        assert!(QemuConfig::default().add_display("vnc").is_err());
+        assert!(QemuConfig::default().add_display("vnc=:1").is_err());
+        assert!(QemuConfig::default().add_display("curses").is_err());
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "display": "vnc",
+            "vnc": { "display": "127.0.0.1:1" },
+        }))
+        .unwrap();
+        let config = config.build_all();
+        assert!(config
+            .qemu_params
+            .windows(2)
+            .any(|param| param == ["-vnc", "127.0.0.1:1"]));
+        assert!(!config.qemu_params.contains(&"-display".to_owned()));
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "display": "gtk",
+            "no_graphic": true,
+        }))
+        .unwrap();
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("conflicts with no_graphic"));
+    }
+
+    #[test]
+    fn test_no_graphic_once() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "no_graphic": true,
+            "knobs": {"no_graphic": true},
+        }))
+        .unwrap();
+        let config = config.build_all();
+        let count = config
+            .qemu_params
+            .iter()
+            .filter(|param| *param == "-nographic")
+            .count();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_iothread_pool() {
+        assert!(QemuConfig::builder().add_iothread_pool(0).is_err());
+
+        let config = QemuConfig::builder()
+            .add_iothread_pool(2)
+            .unwrap()
+            .build_all();
+        let iothreads: Vec<_> = config
+            .qemu_params
+            .iter()
+            .filter(|param| param.starts_with("iothread,"))
+            .collect();
+        assert_eq!(iothreads, vec!["iothread,id=io0", "iothread,id=io1"]);
+
+        let err = config.add_iothread_pool(1).err().unwrap();
+        assert!(err.to_string().contains("io0"));
+    }
+
+    #[test]
+    fn test_qemu_cfg() {
+        let config = QemuConfig::builder().add_name("vm0").add_raw_args(&[
+            "-object".to_owned(),
+            "rng-random,id=rng0,filename=/dev/urandom".to_owned(),
+            "-nographic".to_owned(),
+            "-chardev".to_owned(),
+            "socket,id=char0,path=/tmp/a,,b.sock,server,nowait".to_owned(),
+            "-device".to_owned(),
+            "virtio-rng-pci,rng=rng0".to_owned(),
+            "-device".to_owned(),
+            "loader,file=\"quoted\"".to_owned(),
+        ]);
+        let (cfg, params) = config.qemu_cfg_params("/run/vm0.cfg");
+        assert_eq!(
+            cfg,
+            "[chardev \"char0\"]\n  backend = \"socket\"\n  path = \"/tmp/a,b.sock\"\n  \
+             server = \"on\"\n  wait = \"off\"\n\n\
+             [device]\n  driver = \"virtio-rng-pci\"\n  rng = \"rng0\"\n"
+        );
+        // only the longest run of options is moved, the others keep their place
+        assert_eq!(
+            params,
+            vec![
+                "-name",
+                "vm0",
+                "-object",
+                "rng-random,id=rng0,filename=/dev/urandom",
+                "-nographic",
+                "-readconfig",
+                "/run/vm0.cfg",
+                "-device",
+                "loader,file=\"quoted\"",
+            ]
+        );
+        assert!(config
+            .to_qemu_cfg_string()
+            .unwrap()
+            .starts_with("[object \"rng0\"]\n"));
+    }
+
+    #[test]
+    fn test_usb_devices_need_a_controller() {
+        let usb_host = serde_json::json!({
+            "kind": "usb_host", "id": "key0", "bus": "xhci.0",
+            "vendor_id": 4176, "product_id": 1031,
+        });
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "device_specs": [usb_host, { "kind": "usb_controller", "id": "xhci" }]
+        }))
+        .unwrap();
+        let err = config.try_build_all().err().unwrap();
+        assert!(err.to_string().contains("usb bus xhci.0"));
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "device_specs": [{ "kind": "usb_controller", "id": "xhci" }, usb_host]
+        }))
+        .unwrap();
+        let params = config.build_all().qemu_params;
+        let xhci = params
+            .iter()
+            .position(|p| p == "qemu-xhci,id=xhci")
+            .unwrap();
+        let key = params
+            .iter()
+            .position(|p| p.starts_with("usb-host,"))
+            .unwrap();
+        assert!(xhci < key);
+    }
+
+    #[test]
+    fn test_globals() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "globals": [
+                { "driver": "kvm-pit", "property": "lost_tick_policy", "value": "discard" },
+                { "driver": "ICH9-LPC", "property": "disable_s3", "value": "1" },
+            ]
+        }))
+        .unwrap();
+        let config = QemuConfig::default().add_globals(&config.globals).unwrap();
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-global",
+                "kvm-pit.lost_tick_policy=discard",
+                "-global",
+                "ICH9-LPC.disable_s3=1",
+            ]
+        );
+
+        let global = GlobalParam::new("kvm-pit", "lost_tick_policy", "");
+        assert!(QemuConfig::default().add_global(&global).is_err());
+        let global = GlobalParam::new("kvm-pit.x", "lost_tick_policy", "discard");
+        assert!(QemuConfig::default().add_global(&global).is_err());
+        let global = GlobalParam::new("kvm-pit", "lost=tick", "discard");
+        assert!(QemuConfig::default().add_global(&global).is_err());
+
+        // the value is not split on commas
+        let global = GlobalParam::new("virtio-blk-device", "serial", "a,b");
+        let config = QemuConfig::default().add_global(&global).unwrap();
+        assert_eq!(config.qemu_params[1], "virtio-blk-device.serial=a,b");
+    }
+
+    #[test]
+    fn test_seccomp_knob() {
+        let sandbox = |knob: bool, sandbox: &str| {
+            let config: QemuConfig = serde_json::from_value(serde_json::json!({
+                "seccomp_sandbox": sandbox,
+                "knobs": { "seccomp": knob },
+            }))
+            .unwrap();
+            config.sandbox_param()
+        };
+        assert_eq!(sandbox(true, "").unwrap(), "on");
+        assert_eq!(sandbox(true, "on,spawn=deny").unwrap(), "on,spawn=deny");
+        assert_eq!(sandbox(false, "off").unwrap(), "off");
+        let err = sandbox(true, "off").err().unwrap();
+        assert!(err.to_string().contains("knobs.seccomp"));
+    }
+
+    #[test]
+    fn test_add_device_in_builder_chain() {
+        use crate::device::PVPanicDevice;
+
+        let config = QemuConfig::builder()
+            .add_name("vm0")
+            .add_device(Box::new(PVPanicDevice { ioport: None }))
+            .add_device(Box::new(PcieRootPortDevice {
+                id: "rp0".to_owned(),
+                bus: "pcie.0".to_owned(),
+                chassis: 1,
+                slot: 0,
+                multifunction: false,
+            }));
+        assert!(config.qemu_params.iter().all(|p| p != "pvpanic"));
+
+        let params = config.build_all().qemu_params.join(" ");
+        assert!(params.ends_with(
+            "-device pvpanic -device pcie-root-port,id=rp0,bus=pcie.0,chassis=1,slot=0"
+        ));
+    }
+
+    #[test]
+    fn test_build_all_twice() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "name": "vm0",
+            "memory": { "size": "1G" },
+            "device_specs": [{ "kind": "rng", "id": "rng0" }],
+            "incoming": { "migration_type": "fd", "fd": 7 },
+        }))
+        .unwrap();
+        let tap = NetDevice {
+            netdev: NetdevBackend {
+                netdev_type: "tap".to_owned(),
+                fds: vec![8],
+                ..Default::default()
+            },
+            ..net("n0")
+        };
+        let config = config.add_device(Box::new(tap));
+
+        let built = config.build_all();
+        assert_eq!(built.fds, vec![7, 8]);
+        let rebuilt = built.build_all();
+        assert_eq!(rebuilt.fds, built.fds);
+        assert_eq!(rebuilt.qemu_params, built.qemu_params);
+        let params = rebuilt.qemu_params.join(" ");
+        assert!(params.contains("-incoming fd:3"));
+        assert!(params.contains("tap,id=n0,fd=4"));
+        assert_eq!(
+            rebuilt.qemu_params.iter().filter(|p| *p == "-name").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rebuild_built_config() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "name": "vm0",
+            "vnc": { "display": "127.0.0.1:0", "dynamic_port": true },
+        }))
+        .unwrap();
+        let mut config = config.add_name("pushed");
+        config.qemu_params.push("-S".to_owned());
+        let built = config.build_all();
+
+        // the changes made since the build are reflected
+        let mut changed = built.clone().add_device(Box::new(RngDevice {
+            id: "rng0".to_owned(),
+            filename: String::new(),
+            transport: String::new(),
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        }));
+        let port = changed.pick_vnc_port().unwrap().unwrap();
+        let rebuilt = changed.build_all().qemu_params.join(" ");
+        assert!(rebuilt.contains(&format!("-vnc 127.0.0.1:{}", port - 5900)));
+        assert!(rebuilt.contains("-device virtio-rng-pci,rng=rng0-backend,id=rng0"));
+        assert_eq!(rebuilt.matches("-vnc").count(), 1);
+        assert_eq!(rebuilt.matches("-S").count(), 1);
+        assert_eq!(rebuilt.matches("-name pushed").count(), 1);
+
+        // neither are the params duplicated once saved and reloaded
+        let reloaded = QemuConfig::from_json_str(&built.to_json_string().unwrap()).unwrap();
+        assert_eq!(reloaded.build_all().qemu_params, built.qemu_params);
+    }
+
+    #[test]
+    fn test_monitors() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "monitors": [
+                { "socket_type": "unix", "name": "/run/vm/hmp.sock", "is_server": true, "no_wait": true },
+                { "socket_type": "stdio" },
+                { "socket_type": "tcp", "host": "127.0.0.1", "port": 4445, "is_server": true },
+                { "socket_type": "unix" },
+                { "socket_type": "tcp", "host": "127.0.0.1" },
+            ]
+        }))
+        .unwrap();
+        let params = config.build_all().qemu_params.join(" ");
+        assert!(params.contains(
+            "-monitor unix:/run/vm/hmp.sock,server=on,wait=off -monitor stdio \
+             -monitor tcp:127.0.0.1:4445,server=on"
+        ));
+        assert_eq!(params.matches("-monitor").count(), 3);
+    }
+
+    #[test]
+    fn test_json_config() {
+        let config = QemuConfig::from_json_str(
+            r#"{ "name": "vm0", "memory": { "size": "1G" }, "device_specs": [{ "kind": "rng", "id": "rng0" }] }"#,
+        )
+        .unwrap();
+        let json = config.to_json_string().unwrap();
+        let reloaded = QemuConfig::from_json_str(&json).unwrap();
+        // the uuid is generated at each build
+        let params = |config: &QemuConfig| {
+            let mut built = config.build_all();
+            built.strip_flag("-uuid");
+            built.qemu_params
+        };
+        assert_eq!(params(&reloaded), params(&config));
+
+        let path = std::env::temp_dir().join(format!("config-{}.json", std::process::id()));
+        std::fs::write(&path, &json).unwrap();
+        let from_file = QemuConfig::from_file(path.to_str().unwrap()).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(from_file.name, "vm0");
+        assert!(QemuConfig::from_file("vm.yaml").is_err());
     }
 }
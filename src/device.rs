@@ -1,14 +1,243 @@
 #![allow(dead_code)]
 
+use std::os::unix::prelude::RawFd;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
 use crate::config::QemuConfig;
 use crate::device_consts::*;
+use crate::qmp::QmpClient;
+use crate::types::{parse_size, qemu_escape, FwCfg, HostFeature, QemuVersion};
+
+/// the name of a virtio device on the given transport, e.g. virtio-serial-pci,
+/// pci is used when no transport is given
+fn virtio_device_name(driver: &str, transport: &str) -> String {
+    match transport {
+        "" => format!("{}-{}", driver, TRANSPORTPCI),
+        // virtio-mmio devices are named <driver>-device
+        TRANSPORTMMIO => format!("{}-device", driver),
+        _ => format!("{}-{}", driver, transport),
+    }
+}
 
 /// trait that Devices should implement
 pub trait Device {
     /// self.valid() returns whether the device can return a valid param format
     fn valid(&self) -> bool;
+    /// self.check() is valid() with the reason why a device is rejected
+    fn check(&self) -> Result<()> {
+        if self.valid() {
+            Ok(())
+        } else {
+            Err(anyhow!("invalid device {}", self.driver()))
+        }
+    }
     /// self.set_qemu_params(config) will plug the param into config
     fn set_qemu_params(&self, config: &mut QemuConfig);
+    /// self.host_paths() returns the host files the device needs, checked
+    /// to exist when the config's validate_paths is set
+    fn host_paths(&self) -> Vec<&str> {
+        vec![]
+    }
+    /// self.driver() returns the qemu device driver, empty if the device does not tell
+    fn driver(&self) -> &str {
+        ""
+    }
+    /// self.hotplug_memory() returns the bytes the device takes from the
+    /// hotplug memory region, which needs the memory's maxmem and slots
+    fn hotplug_memory(&self) -> Option<u64> {
+        None
+    }
+    /// self.cold_vcpus() returns the vcpus the device plugs at startup, each in
+    /// a slot smp.max_cpus leaves above smp.cpus
+    fn cold_vcpus(&self) -> u32 {
+        0
+    }
+    /// self.warnings() returns the settings of the device that qemu takes but
+    /// most likely not as meant, see `QemuConfig::warnings()`
+    fn warnings(&self) -> Vec<String> {
+        vec![]
+    }
+    /// self.boot_index() returns the bootindex of the device, unique across devices
+    fn boot_index(&self) -> Option<u32> {
+        None
+    }
+    /// self.devno() returns the ccw address of the device, unique across devices
+    fn devno(&self) -> Option<&str> {
+        None
+    }
+    /// self.drive_id() returns the id of the -drive the device emits, unique across drives
+    fn drive_id(&self) -> Option<&str> {
+        None
+    }
+    /// self.iothread() returns the id of the iothread the device runs its io in,
+    /// one of the config's io_threads
+    fn iothread(&self) -> Option<&str> {
+        None
+    }
+    /// self.usb_bus() returns the usb bus the device plugs to, which a usb
+    /// controller plugged before it must provide
+    fn usb_bus(&self) -> Option<&str> {
+        None
+    }
+    /// self.provided_usb_bus() returns the usb bus a usb controller provides
+    fn provided_usb_bus(&self) -> Option<String> {
+        None
+    }
+    /// self.supports_machine() returns whether the device can be plugged to
+    /// the machine_type machine
+    fn supports_machine(&self, _machine_type: &str) -> bool {
+        true
+    }
+    /// self.on_pci_bus(config) returns whether the device plugs to a pci bus of
+    /// the config's machine, told by the -pci suffix of its driver by default
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        self.driver().ends_with("-pci")
+    }
+    /// self.post_launch(qmp) applies the settings qemu only takes over qmp,
+    /// once it is launched with the device, see `QemuConfig::post_launch()`
+    fn post_launch(&self, _qmp: &mut QmpClient) -> Result<()> {
+        Ok(())
+    }
+    /// self.host_features() returns what the host must provide for the device,
+    /// told by its driver by default
+    fn host_features(&self) -> Vec<HostFeature> {
+        let driver = self.driver();
+        if driver.starts_with("vfio-") {
+            vec![HostFeature::Vfio]
+        } else if driver.starts_with("vhost-") {
+            vec![HostFeature::Vhost]
+        } else if driver == SEVGUEST || driver == SNPGUEST {
+            vec![HostFeature::Sev]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// PciOptions are the options shared by pci devices
+#[derive(Debug, Default, Clone)]
+pub struct PciOptions {
+    /// RomBar maps the option rom of the device when 1, hides it when 0
+    pub rombar: Option<u8>,
+
+    /// RomFile replaces the option rom of the device
+    pub romfile: String,
+
+    /// Bus is the pci bus the device is plugged to, e.g. pcie.0 or a root port
+    pub bus: String,
+
+    /// Addr is the fixed slot and function of the device on its bus, 0x<slot>[.<function>],
+    /// for guest device names that are stable across reboots
+    pub addr: String,
+}
+
+impl PciOptions {
+    /// the highest slot and function of a pci bus
+    const MAX_SLOT: u8 = 0x1f;
+    const MAX_FUNCTION: u8 = 7;
+
+    fn valid(&self) -> bool {
+        self.addr.is_empty() || Self::valid_addr(&self.addr)
+    }
+
+    /// valid() for the device_name device, whose ccw and mmio variants
+    /// are not on a pci bus and take no bus or addr
+    fn valid_for(&self, device_name: &str) -> bool {
+        let off_pci = device_name.ends_with("-ccw") || device_name.ends_with("-device");
+        self.valid() && !(off_pci && (!self.bus.is_empty() || !self.addr.is_empty()))
+    }
+
+    /// whether addr is written 0x<slot>[.<function>], e.g. 0x1f or 0x3.1
+    fn valid_addr(addr: &str) -> bool {
+        let Some(addr) = addr.strip_prefix("0x") else {
+            return false;
+        };
+        let (slot, function) = match addr.split_once('.') {
+            Some((slot, function)) => (slot, Some(function)),
+            None => (addr, None),
+        };
+        let slot_valid = slot.chars().all(|c| c.is_ascii_hexdigit())
+            && u8::from_str_radix(slot, 16).is_ok_and(|slot| slot <= Self::MAX_SLOT);
+        let function_valid = function.is_none_or(|function| {
+            function.chars().all(|c| c.is_ascii_digit())
+                && function
+                    .parse::<u8>()
+                    .is_ok_and(|function| function <= Self::MAX_FUNCTION)
+        });
+        slot_valid && function_valid
+    }
+
+    fn params(&self) -> Vec<String> {
+        let mut params = vec![];
+        if !self.bus.is_empty() {
+            params.push(format!("bus={}", self.bus));
+        }
+        if !self.addr.is_empty() {
+            params.push(format!("addr={}", self.addr));
+        }
+        if let Some(rombar) = self.rombar {
+            params.push(format!("rombar={}", rombar));
+        }
+        if !self.romfile.is_empty() {
+            params.push(format!("romfile={}", qemu_escape(&self.romfile)));
+        }
+        params
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        if self.romfile.is_empty() {
+            return vec![];
+        }
+        vec![&self.romfile]
+    }
+}
+
+/// VirtioOptions are the options shared by virtio devices
+#[derive(Debug, Default, Clone)]
+pub struct VirtioOptions {
+    /// DisableLegacy hides the legacy (virtio 0.9) interface of the device
+    pub disable_legacy: Option<bool>,
+
+    /// DisableModern hides the modern (virtio 1.0) interface of the device
+    pub disable_modern: Option<bool>,
+
+    /// DevNo is the ccw address of the device on s390x, e.g. fe.0.0001,
+    /// unique across devices
+    pub devno: String,
+}
+
+impl VirtioOptions {
+    /// the options of the device_name device in qemu property naming, devno only
+    /// applies to ccw devices and the iommu_platform knob to pci ones
+    fn params(&self, config: &QemuConfig, device_name: &str) -> Vec<String> {
+        let options = [
+            ("disable-legacy", self.disable_legacy),
+            ("disable-modern", self.disable_modern),
+        ];
+
+        let mut params: Vec<String> = options
+            .iter()
+            .filter_map(|(name, value)| {
+                value.map(|on| format!("{}={}", name, if on { "on" } else { "off" }))
+            })
+            .collect();
+        if device_name.ends_with("-ccw") && !self.devno.is_empty() {
+            params.push(format!("devno={}", self.devno));
+        }
+        if device_name.ends_with("-pci") && config.knobs.iommu_platform {
+            params.push("iommu_platform=on".to_owned());
+        }
+        params
+    }
+
+    fn devno(&self) -> Option<&str> {
+        if self.devno.is_empty() {
+            return None;
+        }
+        Some(&self.devno)
+    }
 }
 
 /// QEMU object
@@ -58,13 +287,71 @@ pub struct Object {
     pub prealloc: bool,
 }
 
+impl Object {
+    fn is_sev(&self) -> bool {
+        self.obj_type == SEVGUEST || self.obj_type == SNPGUEST
+    }
+}
+
 impl Device for Object {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut obj_params = vec![self.obj_type.to_owned(), format!("id={}", self.id)];
+        match self.obj_type.as_str() {
+            MEMORYBACKENDFILE => {
+                obj_params.push(format!("size={}", self.size));
+                obj_params.push(format!("mem-path={}", qemu_escape(&self.mem_path)));
+                if self.prealloc {
+                    obj_params.push("prealloc=on".to_owned());
+                }
+                if self.rd_only {
+                    obj_params.push("readonly=on".to_owned());
+                }
+            }
+            MEMORYBACKENDRAM | MEMORYBACKENDEPC => {
+                obj_params.push(format!("size={}", self.size));
+                if self.prealloc {
+                    obj_params.push("prealloc=on".to_owned());
+                }
+            }
+            SEVGUEST | SNPGUEST => {
+                obj_params.push(format!("cbitpos={}", self.c_bit_pos));
+                obj_params.push(format!("reduced-phys-bits={}", self.reduced_physical_bits));
+            }
+            _ => {}
+        }
+
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(obj_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        // the c-bit is one of the upper bits of a 64 bit page table entry, 47 or 51 on epyc
+        const C_BIT_POS_RANGE: std::ops::Range<u32> = 32..64;
+
+        if self.obj_type.is_empty() || self.id.is_empty() {
+            return false;
+        }
+        if self.obj_type == MEMORYBACKENDFILE && self.mem_path.is_empty() {
+            return false;
+        }
+        if self.is_sev() && !C_BIT_POS_RANGE.contains(&self.c_bit_pos) {
+            return false;
+        }
+        true
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        if self.obj_type == MEMORYBACKENDFILE {
+            return vec![&self.mem_path];
+        }
+        vec![]
+    }
+
+    fn host_features(&self) -> Vec<HostFeature> {
+        if self.is_sev() {
+            return vec![HostFeature::Sev];
+        }
+        vec![]
     }
 }
 
@@ -88,15 +375,12 @@ pub struct FSDevice {
 	/// SecurityModel is the security model for this filesystem device.
     pub security_model: SecurityModel,
 
-	/// DisableModern prevents qemu from relying on fast MMIO.
-    pub disabled_modern: bool,
+	/// Virtio holds the virtio options, disable_modern prevents qemu from relying on fast MMIO.
+    pub virtio: VirtioOptions,
 
 	/// ROMFile specifies the ROM file being used for this device.
     pub rom_file: String,
 
-	/// DevNo identifies the ccw devices for s390x architecture
-    pub devno: String,
-
 	/// Transport is the virtio transport for this device.
     pub transport: VirtioTransport,
 
@@ -105,103 +389,391 @@ pub struct FSDevice {
     pub multidev: Virtio9PMultiDev,
 }
 
+impl FSDevice {
+    fn device_name(&self, transport: &str) -> String {
+        let driver = driver_or(&self.driver, VIRTIO9P);
+        if driver == VIRTIO9P {
+            virtio_device_name(&driver, transport)
+        } else {
+            driver
+        }
+    }
+}
+
 impl Device for FSDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut fsdev_params = vec![
+            driver_or(&self.fs_driver, LOCAL),
+            format!("id={}", self.id),
+            format!("path={}", qemu_escape(&self.path)),
+        ];
+        if !self.security_model.is_empty() {
+            fsdev_params.push(format!("security_model={}", self.security_model));
+        }
+        if !self.multidev.is_empty() {
+            fsdev_params.push(format!("multidevs={}", self.multidev));
+        }
+
+        let transport = config.virtio_transport(&self.transport);
+        let mut dev_params = vec![
+            self.device_name(transport),
+            format!("fsdev={}", self.id),
+            format!("mount_tag={}", qemu_escape(&self.mount_tag)),
+        ];
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        if !self.rom_file.is_empty() {
+            dev_params.push(format!("romfile={}", qemu_escape(&self.rom_file)));
+        }
+
+        config.qemu_params.push("-fsdev".to_owned());
+        config.qemu_params.push(fsdev_params.join(","));
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        !self.id.is_empty() && !self.path.is_empty() && !self.mount_tag.is_empty()
     }
-}
 
-pub struct NetDevice {}
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.path]
+    }
 
-impl Device for NetDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        self.device_name(config.virtio_transport(&self.transport))
+            .ends_with("-pci")
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
     }
 }
 
-pub struct CharDevice {}
+/// NetDevice represents a guest networking device
+pub struct NetDevice {
+    /// ID is the id of the -netdev backend the device is plugged to
+    pub id: String,
 
-impl Device for CharDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
-    }
+    /// Driver is the qemu device driver, e.g. virtio-net-pci
+    pub driver: DeviceDriver,
 
-    fn valid(&self) -> bool {
-        unimplemented!();
-    }
+    /// Netdev is the host backend of the device, left to be added separately if its type is empty
+    pub netdev: NetdevBackend,
+
+    /// MAC is the guest mac address, qemu assigns one if empty
+    pub mac: String,
+
+    /// GenerateMac derives a stable mac from the vm name and ID when no MAC is given
+    pub generate_mac: bool,
+
+    /// Virtio holds the virtio options of a virtio-net device
+    pub virtio: VirtioOptions,
+
+    /// BootIndex is the position of the device in the boot order, lowest first
+    pub boot_index: Option<u32>,
+
+    /// Offloads toggles the checksum and segmentation offloads,
+    /// the ones left unset keep qemu's default
+    pub offloads: NetOffloads,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
 }
 
-pub struct LegacySerialDevice {}
+/// NetdevBackend is the -netdev host side of a net device
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetdevBackend {
+    /// NetdevType is the backend, tap, user or bridge
+    pub netdev_type: NetdevType,
 
-impl Device for LegacySerialDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// IfName is the host tap interface of a tap backend
+    pub ifname: String,
+
+    /// Script configures the tap interface once created, "no" to skip it
+    pub script: String,
+
+    /// DownScript deconfigures the tap interface, "no" to skip it
+    pub downscript: String,
+
+    /// Bridge is the host bridge of a bridge backend
+    pub bridge: String,
+
+    /// FDs are host fds of already opened taps, one per queue, e.g. from a privileged helper
+    pub fds: Vec<RawFd>,
+}
+
+impl NetdevBackend {
+    /// the -netdev params of the backend with the given ID, None without a backend
+    ///
+    /// The tap fds are handed to qemu through the config, the params carry the
+    /// numbers qemu sees them as.
+    fn params(&self, id: &str, config: &mut QemuConfig) -> Option<String> {
+        if self.netdev_type.is_empty() {
+            return None;
+        }
+
+        let mut params = vec![self.netdev_type.to_owned(), format!("id={}", id)];
+        match self.netdev_type.as_str() {
+            NETDEVTAP => {
+                if !self.ifname.is_empty() {
+                    params.push(format!("ifname={}", qemu_escape(&self.ifname)));
+                }
+                if !self.script.is_empty() {
+                    params.push(format!("script={}", qemu_escape(&self.script)));
+                }
+                if !self.downscript.is_empty() {
+                    params.push(format!("downscript={}", qemu_escape(&self.downscript)));
+                }
+                match config.append_fds(&self.fds).as_slice() {
+                    [] => {}
+                    [fd] => params.push(format!("fd={}", fd)),
+                    fds => params.push(format!(
+                        "fds={}",
+                        fds.iter()
+                            .map(|fd| fd.to_string())
+                            .collect::<Vec<_>>()
+                            .join(":")
+                    )),
+                }
+            }
+            NETDEVBRIDGE if !self.bridge.is_empty() => {
+                params.push(format!("br={}", self.bridge));
+            }
+            _ => {}
+        }
+        Some(params.join(","))
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        match self.netdev_type.as_str() {
+            "" | NETDEVUSER | NETDEVBRIDGE => true,
+            // qemu takes an opened tap as is, it neither names nor configures it
+            NETDEVTAP if !self.fds.is_empty() => {
+                self.ifname.is_empty() && self.script.is_empty() && self.downscript.is_empty()
+            }
+            NETDEVTAP => !self.ifname.is_empty() || !self.script.is_empty(),
+            _ => false,
+        }
     }
 }
 
-pub struct SerialDevice {}
+/// NetOffloads are the virtio-net offload features,
+/// the guest_* ones are offloads to the guest, the others to the host
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NetOffloads {
+    pub csum: Option<bool>,
+    pub gso: Option<bool>,
+    pub host_tso4: Option<bool>,
+    pub host_tso6: Option<bool>,
+    pub host_ecn: Option<bool>,
+    pub host_ufo: Option<bool>,
+    pub guest_csum: Option<bool>,
+    pub guest_tso4: Option<bool>,
+    pub guest_tso6: Option<bool>,
+    pub guest_ecn: Option<bool>,
+    pub guest_ufo: Option<bool>,
+}
 
-impl Device for SerialDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+impl NetOffloads {
+    /// the offloads that are explicitly set, in qemu property naming
+    fn params(&self) -> Vec<String> {
+        let offloads = [
+            ("csum", self.csum),
+            ("gso", self.gso),
+            ("host_tso4", self.host_tso4),
+            ("host_tso6", self.host_tso6),
+            ("host_ecn", self.host_ecn),
+            ("host_ufo", self.host_ufo),
+            ("guest_csum", self.guest_csum),
+            ("guest_tso4", self.guest_tso4),
+            ("guest_tso6", self.guest_tso6),
+            ("guest_ecn", self.guest_ecn),
+            ("guest_ufo", self.guest_ufo),
+        ];
+
+        offloads
+            .iter()
+            .filter_map(|(name, value)| {
+                value.map(|on| format!("{}={}", name, if on { "on" } else { "off" }))
+            })
+            .collect()
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    /// segmentation offloads explicitly enabled while the checksum offload
+    /// they depend on is disabled, qemu drops them silently
+    fn orphaned(&self) -> Vec<&'static str> {
+        let dependents = [
+            (self.csum, "host_tso4", self.host_tso4),
+            (self.csum, "host_tso6", self.host_tso6),
+            (self.csum, "host_ecn", self.host_ecn),
+            (self.csum, "host_ufo", self.host_ufo),
+            (self.guest_csum, "guest_tso4", self.guest_tso4),
+            (self.guest_csum, "guest_tso6", self.guest_tso6),
+            (self.guest_csum, "guest_ecn", self.guest_ecn),
+            (self.guest_csum, "guest_ufo", self.guest_ufo),
+        ];
+
+        dependents
+            .iter()
+            .filter(|(csum, _, offload)| *csum == Some(false) && *offload == Some(true))
+            .map(|(_, name, _)| *name)
+            .collect()
     }
 }
 
-pub struct BlockDevice {}
+/// a locally administered mac in qemu's 52:54:00 range, stable for the same vm name and device id
+pub fn generate_mac(name: &str, id: &str) -> String {
+    // fnv-1a, std's hasher is not guaranteed to be stable across releases
+    let hash = name
+        .bytes()
+        .chain(std::iter::once(b'/'))
+        .chain(id.bytes())
+        .fold(0xcbf29ce484222325u64, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        });
+    format!(
+        "52:54:00:{:02x}:{:02x}:{:02x}",
+        (hash >> 16) as u8,
+        (hash >> 8) as u8,
+        hash as u8
+    )
+}
 
-impl Device for BlockDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+impl Device for NetDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        if let Some(netdev_params) = self.netdev.params(&self.id, config) {
+            config.qemu_params.push("-netdev".to_owned());
+            config.qemu_params.push(netdev_params);
+        }
+
+        let mut dev_params = vec![self.driver.to_owned(), format!("netdev={}", self.id)];
+        if !self.mac.is_empty() {
+            dev_params.push(format!("mac={}", self.mac));
+        } else if self.generate_mac {
+            dev_params.push(format!("mac={}", generate_mac(&config.name, &self.id)));
+        }
+        if let Some(index) = self.boot_index {
+            dev_params.push(format!("bootindex={}", index));
+        }
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        dev_params.extend(self.offloads.params());
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        !self.id.is_empty()
+            && !self.driver.is_empty()
+            && self.netdev.valid()
+            && self.pci.valid_for(&self.driver)
     }
-}
 
-pub struct PVPanicDevice {}
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
 
-impl Device for PVPanicDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn driver(&self) -> &str {
+        &self.driver
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    fn warnings(&self) -> Vec<String> {
+        self.offloads
+            .orphaned()
+            .iter()
+            .map(|offload| {
+                format!(
+                    "net device {}: {}=on has no effect with its checksum offload off",
+                    self.id, offload
+                )
+            })
+            .collect()
+    }
+
+    fn boot_index(&self) -> Option<u32> {
+        self.boot_index
+    }
+
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
     }
 }
 
-pub struct LoaderDevice {}
+/// CharDevice is a -chardev host backend that guest devices plug to by ID
+pub struct CharDevice {
+    /// ID is the user defined chardev ID
+    pub id: String,
 
-impl Device for LoaderDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// Backend is the host side of the chardev, e.g. file
+    pub backend: CharDeviceBackend,
+
+    /// Path is the host path the backend writes to, or the unix socket of a socket backend
+    pub path: String,
+
+    /// Server makes qemu listen on the unix socket instead of connecting to it
+    pub server: bool,
+
+    /// NoWait lets a socket server start without waiting for a client
+    pub nowait: bool,
+
+    /// Mux lets several frontends, e.g. a serial and the monitor, share the chardev
+    pub mux: bool,
+
+    /// Append appends to the file of the file backend instead of truncating it
+    pub append: bool,
+
+    /// LogFile additionally logs all the data going through the chardev
+    pub logfile: String,
+
+    /// LogAppend appends to the logfile instead of truncating it
+    pub logappend: bool,
+}
+
+impl Device for CharDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut chardev_params = vec![self.backend.to_owned(), format!("id={}", self.id)];
+        if !self.path.is_empty() {
+            chardev_params.push(format!("path={}", qemu_escape(&self.path)));
+        }
+        if self.append && self.backend == CHARDEVFILE {
+            chardev_params.push("append=on".to_owned());
+        }
+        if self.server && self.backend == CHARDEVSOCKET {
+            chardev_params.push("server=on".to_owned());
+            if self.nowait {
+                chardev_params.push("wait=off".to_owned());
+            }
+        }
+        if self.mux {
+            chardev_params.push("mux=on".to_owned());
+        }
+        if !self.logfile.is_empty() {
+            chardev_params.push(format!("logfile={}", qemu_escape(&self.logfile)));
+            if self.logappend {
+                chardev_params.push("logappend=on".to_owned());
+            }
+        }
+
+        config.qemu_params.push("-chardev".to_owned());
+        config.qemu_params.push(chardev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        if self.id.is_empty() || self.backend.is_empty() {
+            return false;
+        }
+        match self.backend.as_str() {
+            CHARDEVFILE | CHARDEVSOCKET => !self.path.is_empty(),
+            _ => true,
+        }
     }
 }
 
-pub struct VhostUserDevice {}
+pub struct LegacySerialDevice {}
 
-impl Device for VhostUserDevice {
+impl Device for LegacySerialDevice {
     fn set_qemu_params(&self, _config: &mut QemuConfig) {
         unimplemented!();
     }
@@ -211,110 +783,3244 @@ impl Device for VhostUserDevice {
     }
 }
 
-pub struct PcieRootPortDevice {}
+/// SerialDevice is a virtio-serial bus along with the ports plugged to it
+pub struct SerialDevice {
+    /// ID is the user defined bus ID, ports are plugged to <id>.0
+    pub id: String,
 
-impl Device for PcieRootPortDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+
+    /// Ports are the virtserialports on the bus
+    pub ports: Vec<SerialPort>,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+/// SerialPort is a virtserialport on a virtio-serial bus
+pub struct SerialPort {
+    /// Chardev is the id of the chardev backing the port
+    pub chardev: String,
+
+    /// Name is the port name seen by the guest, e.g. org.qemu.guest_agent.0
+    pub name: String,
+
+    /// Nr is the port number on the bus, the unset ones
+    /// are assigned the lowest free numbers in order
+    pub nr: Option<u32>,
+}
+
+impl SerialDevice {
+    /// the port numbers after assigning the unset ones,
+    /// nr 0 is reserved for virtconsole
+    pub fn assigned_nrs(&self) -> Vec<u32> {
+        let taken: Vec<u32> = self.ports.iter().filter_map(|p| p.nr).collect();
+        let mut free = (1..).filter(|nr| !taken.contains(nr));
+
+        self.ports
+            .iter()
+            .map(|p| p.nr.unwrap_or_else(|| free.next().unwrap()))
+            .collect()
+    }
+}
+
+impl Device for SerialDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![
+            virtio_device_name(VIRTIOSERIAL, config.virtio_transport(&self.transport)),
+            format!("id={}", self.id),
+        ];
+        dev_params.extend(self.pci.params());
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+
+        for (port, nr) in self.ports.iter().zip(self.assigned_nrs()) {
+            let mut port_params = vec![
+                VIRTIOSERIALPORT.to_owned(),
+                format!("bus={}.0", self.id),
+                format!("nr={}", nr),
+                format!("chardev={}", port.chardev),
+            ];
+            if !port.name.is_empty() {
+                port_params.push(format!("name={}", port.name));
+            }
+
+            config.qemu_params.push("-device".to_owned());
+            config.qemu_params.push(port_params.join(","));
+        }
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        if self.id.is_empty() || self.ports.iter().any(|p| p.chardev.is_empty()) {
+            return false;
+        }
+
+        let mut nrs: Vec<u32> = self.ports.iter().filter_map(|p| p.nr).collect();
+        let given = nrs.len();
+        nrs.sort_unstable();
+        nrs.dedup();
+        nrs.len() == given
+            && !nrs.contains(&0)
+            && self
+                .pci
+                .valid_for(&virtio_device_name(VIRTIOSERIAL, &self.transport))
     }
-}
 
-pub struct VFIODevice {}
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
 
-impl Device for VFIODevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn driver(&self) -> &str {
+        VIRTIOSERIAL
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        virtio_device_name(VIRTIOSERIAL, config.virtio_transport(&self.transport)).ends_with("-pci")
     }
 }
 
-pub struct ScsiController {}
+/// BlockDevice represents a disk, a -drive backend plus the -device the guest sees
+pub struct BlockDevice {
+    /// ID is the drive ID
+    pub id: String,
 
-impl Device for ScsiController {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// File is the path of the disk image
+    pub file: String,
+
+    /// Format is the disk image format, e.g. qcow2 or raw
+    pub format: String,
+
+    /// Driver is the qemu device driver
+    pub driver: DeviceDriver,
+
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+
+    /// Bus is the bus the device is plugged to, e.g. scsi0.0 for a scsi-hd
+    pub bus: String,
+
+    /// LogicalBlockSize is the block size the guest addresses the disk with
+    pub logical_block_size: Option<u32>,
+
+    /// PhysicalBlockSize is the block size the disk reports to be built of
+    pub physical_block_size: Option<u32>,
+
+    /// ReadOnly attaches the disk read only
+    pub readonly: bool,
+
+    /// Cache is the host page cache mode, e.g. none or writeback
+    pub cache: String,
+
+    /// AIO is the host io backend, threads, native or io_uring
+    pub aio: String,
+
+    /// Virtio holds the virtio options of a virtio-blk device
+    pub virtio: VirtioOptions,
+
+    /// BootIndex is the position of the device in the boot order, lowest first
+    pub boot_index: Option<u32>,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+/// the disk image formats a -drive accepts
+const DRIVE_FORMATS: [&str; 8] = ["raw", "qcow2", "qcow", "qed", "vmdk", "vdi", "vhdx", "vpc"];
+/// the -drive cache modes
+const DRIVE_CACHE_MODES: [&str; 5] = ["none", "writeback", "writethrough", "directsync", "unsafe"];
+/// the -drive aio backends
+const DRIVE_AIO_BACKENDS: [&str; 3] = ["threads", "native", "io_uring"];
+
+impl BlockDevice {
+    fn device_name(&self, transport: &str) -> String {
+        if self.driver == VIRTIOBLOCK {
+            virtio_device_name(&self.driver, transport)
+        } else {
+            self.driver.to_owned()
+        }
+    }
+}
+
+impl Device for BlockDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut drive_params = vec![
+            format!("id={}", self.id),
+            format!("file={}", qemu_escape(&self.file)),
+        ];
+        if !self.format.is_empty() {
+            drive_params.push(format!("format={}", self.format));
+        }
+        drive_params.push("if=none".to_owned());
+        if self.readonly {
+            drive_params.push("readonly=on".to_owned());
+        }
+        if !self.cache.is_empty() {
+            drive_params.push(format!("cache={}", self.cache));
+        }
+        if !self.aio.is_empty() {
+            drive_params.push(format!("aio={}", self.aio));
+        }
+
+        let device_name = self.device_name(config.virtio_transport(&self.transport));
+        let mut dev_params = vec![device_name, format!("drive={}", self.id)];
+        if !self.bus.is_empty() {
+            dev_params.push(format!("bus={}", self.bus));
+        }
+        if let Some(size) = self.logical_block_size {
+            dev_params.push(format!("logical_block_size={}", size));
+        }
+        if let Some(size) = self.physical_block_size {
+            dev_params.push(format!("physical_block_size={}", size));
+        }
+        if let Some(index) = self.boot_index {
+            dev_params.push(format!("bootindex={}", index));
+        }
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-drive".to_owned());
+        config.qemu_params.push(drive_params.join(","));
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        const MIN_BLOCK_SIZE: u32 = 512;
+
+        if self.id.is_empty() || self.file.is_empty() || self.driver.is_empty() {
+            return false;
+        }
+        // bus is the one of the device, pci.bus can't tell another
+        if !self.bus.is_empty() && !self.pci.bus.is_empty() {
+            return false;
+        }
+        if !self.pci.valid_for(&self.device_name(&self.transport)) {
+            return false;
+        }
+        if !self.format.is_empty() && !DRIVE_FORMATS.contains(&self.format.as_str()) {
+            return false;
+        }
+        if !self.cache.is_empty() && !DRIVE_CACHE_MODES.contains(&self.cache.as_str()) {
+            return false;
+        }
+        if !self.aio.is_empty() && !DRIVE_AIO_BACKENDS.contains(&self.aio.as_str()) {
+            return false;
+        }
+        // native aio needs O_DIRECT, i.e. a cache mode bypassing the host page cache
+        if self.aio == "native" && !matches!(self.cache.as_str(), "none" | "directsync") {
+            return false;
+        }
+
+        let sizes_valid = [self.logical_block_size, self.physical_block_size]
+            .iter()
+            .flatten()
+            .all(|size| size.is_power_of_two() && *size >= MIN_BLOCK_SIZE);
+        let sizes_ordered = match (self.logical_block_size, self.physical_block_size) {
+            (Some(logical), Some(physical)) => logical <= physical,
+            _ => true,
+        };
+        sizes_valid && sizes_ordered
     }
-}
 
-pub struct BridgeDevice {}
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
 
-impl Device for BridgeDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn boot_index(&self) -> Option<u32> {
+        self.boot_index
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        self.device_name(config.virtio_transport(&self.transport))
+            .ends_with("-pci")
+    }
+
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
+    }
+
+    fn drive_id(&self) -> Option<&str> {
+        Some(&self.id)
     }
 }
 
-pub struct VSockDevice {}
+/// PVPanicDevice lets the guest report its panics to qemu, see the panic action
+pub struct PVPanicDevice {
+    /// IOPort is the isa io port of the device, qemu picks 0x505 if None
+    pub ioport: Option<u16>,
+}
 
-impl Device for VSockDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+impl Device for PVPanicDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![PVPANIC.to_owned()];
+        if let Some(ioport) = self.ioport {
+            dev_params.push(format!("ioport={:#x}", ioport));
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        true
+    }
+
+    fn driver(&self) -> &str {
+        PVPANIC
     }
 }
 
-pub struct RngDevice {}
+/// LoaderDevice loads a file into the guest memory before it starts, e.g. a bootloader
+pub struct LoaderDevice {
+    /// File is the host path of the image to load
+    pub file: String,
+
+    /// Addr is the guest physical address to load a raw image at, elf and
+    /// uimage files carry their own
+    pub addr: Option<u64>,
 
-impl Device for RngDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// CPUNum is the cpu whose program counter is set to the image entry point
+    pub cpu_num: Option<u32>,
+
+    /// ForceRaw loads the file as a raw image even if it is an elf or uimage
+    pub force_raw: bool,
+}
+
+impl Device for LoaderDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![
+            LOADER.to_owned(),
+            format!("file={}", qemu_escape(&self.file)),
+        ];
+        if let Some(addr) = self.addr {
+            dev_params.push(format!("addr={:#x}", addr));
+        }
+        if let Some(cpu_num) = self.cpu_num {
+            dev_params.push(format!("cpu-num={}", cpu_num));
+        }
+        if self.force_raw {
+            dev_params.push("force-raw=on".to_owned());
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        !self.file.is_empty()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.file]
+    }
+
+    fn driver(&self) -> &str {
+        LOADER
     }
 }
 
-pub struct BalloonDevice {}
+/// VhostUserDevice is a device served by a vhost-user backend process, e.g. virtiofsd
+///
+/// The backend maps the guest memory, which must be shared, e.g. a memory-backend-file
+/// with share=on.
+pub struct VhostUserDevice {
+    /// ID is the user defined device ID, the -netdev ID of a net device
+    pub id: String,
 
-impl Device for BalloonDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    /// DeviceType is the vhost-user device type, e.g. vhost-user-fs
+    pub device_type: DeviceDriver,
+
+    /// SocketPath is the unix socket the backend listens on
+    pub socket_path: String,
+
+    /// CharID is the ID of the chardev connecting to the socket
+    pub char_id: String,
+
+    /// Tag is the mount tag of a vhost-user-fs device
+    pub tag: String,
+
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+
+    /// Virtio holds the virtio options of the device
+    pub virtio: VirtioOptions,
+}
+
+impl Device for VhostUserDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-chardev".to_owned());
+        config.qemu_params.push(format!(
+            "{},id={},path={}",
+            CHARDEVSOCKET,
+            self.char_id,
+            qemu_escape(&self.socket_path)
+        ));
+
+        let transport = config.virtio_transport(&self.transport);
+        let mut dev_params = vec![virtio_device_name(&self.device_type, transport)];
+        if self.device_type == VHOSTUSERNET {
+            // vhost-user-net is a virtio-net device on a vhost-user netdev
+            config.qemu_params.push("-netdev".to_owned());
+            config.qemu_params.push(format!(
+                "type=vhost-user,id={},chardev={}",
+                self.id, self.char_id
+            ));
+            dev_params.push(format!("netdev={}", self.id));
+        } else {
+            dev_params.push(format!("id={}", self.id));
+            dev_params.push(format!("chardev={}", self.char_id));
+        }
+        if self.device_type == VHOSTUSERFS {
+            dev_params.push(format!("tag={}", qemu_escape(&self.tag)));
+        }
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
     }
 
     fn valid(&self) -> bool {
-        unimplemented!();
+        const VHOST_USER_TYPES: [&str; 4] =
+            [VHOSTUSERFS, VHOSTUSERBLK, VHOSTUSERNET, VHOSTUSERSCSI];
+
+        if self.id.is_empty() || self.socket_path.is_empty() || self.char_id.is_empty() {
+            return false;
+        }
+        if !VHOST_USER_TYPES.contains(&self.device_type.as_str()) {
+            return false;
+        }
+        self.device_type != VHOSTUSERFS || !self.tag.is_empty()
     }
-}
 
-pub struct IommuDevice {}
+    fn driver(&self) -> &str {
+        &self.device_type
+    }
 
-impl Device for IommuDevice {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        virtio_device_name(&self.device_type, config.virtio_transport(&self.transport))
+            .ends_with("-pci")
     }
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
+    }
+
+    // the driver of a vhost-user-net device is virtio-net, which does not tell
+    fn host_features(&self) -> Vec<HostFeature> {
+        vec![HostFeature::Vhost]
     }
 }
 
-pub struct FwConfig {}
+/// PcieRootPortDevice is a pcie root port, pcie devices are hotplugged into it
+pub struct PcieRootPortDevice {
+    /// ID is the user defined device ID, the port's bus name
+    pub id: String,
 
-impl Device for FwConfig {
-    fn set_qemu_params(&self, _config: &mut QemuConfig) {
-        unimplemented!();
-    }
+    /// Bus is the bus the port is plugged to, e.g. pcie.0
+    pub bus: String,
 
-    fn valid(&self) -> bool {
-        unimplemented!();
+    /// Chassis is the chassis number of the port
+    pub chassis: u32,
+
+    /// Slot is the slot number of the port within the chassis
+    pub slot: u32,
+
+    /// MultiFunction lets other functions share the port's slot, e.g. ports at addr=2.1
+    pub multifunction: bool,
+}
+
+impl Device for PcieRootPortDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![PCIEROOTPORT.to_owned(), format!("id={}", self.id)];
+        if !self.bus.is_empty() {
+            dev_params.push(format!("bus={}", self.bus));
+        }
+        dev_params.push(format!("chassis={}", self.chassis));
+        dev_params.push(format!("slot={}", self.slot));
+        if self.multifunction {
+            dev_params.push("multifunction=on".to_owned());
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && self.chassis != 0
+    }
+
+    fn driver(&self) -> &str {
+        PCIEROOTPORT
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+}
+
+/// VFIODevice represents a host device passed through to the guest
+pub struct VFIODevice {
+    /// Driver is the qemu device driver, e.g. vfio-pci
+    pub driver: DeviceDriver,
+
+    /// BDF is the host PCI address of the device, e.g. 0000:01:00.0
+    pub bdf: String,
+
+    /// MdevPath is the sysfs path of a mediated device, e.g. a vGPU at
+    /// /sys/bus/mdev/devices/<uuid>, it replaces BDF
+    pub mdev_path: String,
+
+    /// Display exposes the device's display to qemu, for vGPUs
+    pub display: bool,
+
+    /// RamFB adds a ramfb boot display, only usable along with Display
+    pub ramfb: bool,
+
+    /// XIgdOpRegion exposes the Intel IGD OpRegion to the guest
+    pub x_igd_opregion: bool,
+
+    /// XVga exposes the legacy VGA ranges of the device to the guest
+    pub x_vga: bool,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl Device for VFIODevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let host = if self.mdev_path.is_empty() {
+            format!("host={}", self.bdf)
+        } else {
+            format!("sysfsdev={}", qemu_escape(&self.mdev_path))
+        };
+        let mut dev_params = vec![self.driver.to_owned(), host];
+        let suboptions = [
+            ("display", self.display),
+            ("ramfb", self.ramfb),
+            ("x-igd-opregion", self.x_igd_opregion),
+            ("x-vga", self.x_vga),
+        ];
+        for (name, _) in suboptions.iter().filter(|(_, on)| *on) {
+            dev_params.push(format!("{}=on", name));
+        }
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        // the device is either a host pci device or a mediated one
+        if self.driver.is_empty() || self.bdf.is_empty() == self.mdev_path.is_empty() {
+            return false;
+        }
+        // ramfb is drawn through the device's display
+        (!self.ramfb || self.display) && self.pci.valid()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        let mut paths = self.pci.host_paths();
+        if !self.mdev_path.is_empty() {
+            paths.push(&self.mdev_path);
+        }
+        paths
+    }
+
+    fn driver(&self) -> &str {
+        &self.driver
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        self.driver == VFIOPCI
+    }
+}
+
+/// ScsiController is a scsi host bus adapter, disks are plugged to its <id>.0 bus
+pub struct ScsiController {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// Driver is the controller model, virtio-scsi or an emulated hba like megasas
+    pub driver: DeviceDriver,
+
+    /// Transport is the virtio transport for virtio-scsi, pci if empty
+    pub transport: VirtioTransport,
+
+    /// IOThread is the id of the iothread virtio-scsi runs its io in
+    pub iothread: String,
+
+    /// NumQueues is the number of request queues of virtio-scsi, qemu picks if 0
+    pub num_queues: u32,
+
+    /// Virtio holds the virtio options of virtio-scsi
+    pub virtio: VirtioOptions,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl ScsiController {
+    fn device_name(&self, transport: &str) -> String {
+        if self.driver == VIRTIOSCSI {
+            virtio_device_name(&self.driver, transport)
+        } else {
+            self.driver.to_owned()
+        }
+    }
+}
+
+impl Device for ScsiController {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let transport = config.virtio_transport(&self.transport);
+        let mut dev_params = vec![self.device_name(transport), format!("id={}", self.id)];
+        if !self.iothread.is_empty() {
+            dev_params.push(format!("iothread={}", self.iothread));
+        }
+        if self.num_queues > 0 {
+            dev_params.push(format!("num_queues={}", self.num_queues));
+        }
+        if self.driver == VIRTIOSCSI {
+            dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        }
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        const SCSI_CONTROLLERS: [&str; 4] = [VIRTIOSCSI, MEGASAS, LSI53C895A, PVSCSI];
+
+        if self.id.is_empty() || !SCSI_CONTROLLERS.contains(&self.driver.as_str()) {
+            return false;
+        }
+        // the emulated hbas have neither iothreads nor multiple queues
+        (self.driver == VIRTIOSCSI || (self.iothread.is_empty() && self.num_queues == 0))
+            && self.pci.valid()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
+
+    fn driver(&self) -> &str {
+        &self.driver
+    }
+
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        // the emulated hbas are all pci devices
+        self.driver != VIRTIOSCSI
+            || self
+                .device_name(config.virtio_transport(&self.transport))
+                .ends_with("-pci")
+    }
+
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
+    }
+
+    fn iothread(&self) -> Option<&str> {
+        Some(self.iothread.as_str()).filter(|id| !id.is_empty())
+    }
+}
+
+/// the kind of bus a BridgeDevice exposes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeType {
+    /// a pci-bridge, a conventional pci bus behind a pci bus
+    #[default]
+    Pci,
+
+    /// a pcie-pci-bridge, a conventional pci bus behind a pcie port
+    PciePci,
+}
+
+impl BridgeType {
+    fn driver(&self) -> DeviceDriverRef<'static> {
+        match self {
+            BridgeType::Pci => PCIBRIDGEDRIVER,
+            BridgeType::PciePci => PCIEPCIBRIDGEDRIVER,
+        }
+    }
+}
+
+/// BridgeDevice is a pci bridge, devices are plugged to the bus named by its ID
+pub struct BridgeDevice {
+    /// ID is the user defined device ID, the bridge's bus name
+    pub id: String,
+
+    /// BridgeType tells the bridge driver
+    pub bridge_type: BridgeType,
+
+    /// Bus is the bus the bridge is plugged to, e.g. pci.0 or a pcie root port
+    pub bus: String,
+
+    /// Address is the slot of the bridge on its bus, e.g. 0x1f
+    pub address: String,
+
+    /// ChassisNr is the chassis number of a pci-bridge, unique across bridges
+    pub chassis_nr: u32,
+
+    /// SHPC enables the standard hotplug controller of a pci-bridge
+    pub shpc: bool,
+}
+
+impl Device for BridgeDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![
+            self.bridge_type.driver().to_owned(),
+            format!("id={}", self.id),
+            format!("bus={}", self.bus),
+        ];
+        // pcie-pci-bridge has neither a chassis nor an shpc
+        if self.bridge_type == BridgeType::Pci {
+            dev_params.push(format!("chassis_nr={}", self.chassis_nr));
+        }
+        if !self.address.is_empty() {
+            dev_params.push(format!("addr={}", self.address));
+        }
+        if self.bridge_type == BridgeType::Pci {
+            dev_params.push(format!("shpc={}", if self.shpc { "on" } else { "off" }));
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        if self.id.is_empty() || self.bus.is_empty() {
+            return false;
+        }
+        // qemu refuses a pci-bridge without a chassis number
+        self.bridge_type != BridgeType::Pci || self.chassis_nr != 0
+    }
+
+    fn driver(&self) -> &str {
+        self.bridge_type.driver()
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+}
+
+/// the model of a UsbController
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UsbControllerType {
+    /// a qemu-xhci, usb 3.0
+    #[default]
+    Xhci,
+
+    /// a usb-ehci, usb 2.0
+    Ehci,
+
+    /// the machine's default uhci controller added by -usb, usb 1.1
+    Uhci,
+}
+
+/// UsbController is a usb host controller, usb devices are plugged to its <id>.0 bus
+pub struct UsbController {
+    /// ID is the user defined device ID, unused by the uhci controller
+    pub id: String,
+
+    /// ControllerType tells the controller model
+    pub controller_type: UsbControllerType,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl UsbController {
+    /// the bus of the controller added by -usb
+    pub const UHCI_BUS: &'static str = "usb-bus.0";
+}
+
+impl Device for UsbController {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        if self.controller_type == UsbControllerType::Uhci {
+            config.qemu_params.push("-usb".to_owned());
+            return;
+        }
+
+        let mut dev_params = vec![self.driver().to_owned(), format!("id={}", self.id)];
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        (self.controller_type == UsbControllerType::Uhci || !self.id.is_empty()) && self.pci.valid()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
+
+    fn driver(&self) -> &str {
+        match self.controller_type {
+            UsbControllerType::Xhci => QEMUXHCI,
+            UsbControllerType::Ehci => USBEHCI,
+            UsbControllerType::Uhci => "",
+        }
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+
+    fn provided_usb_bus(&self) -> Option<String> {
+        match self.controller_type {
+            UsbControllerType::Uhci => Some(Self::UHCI_BUS.to_owned()),
+            _ => Some(format!("{}.0", self.id)),
+        }
+    }
+}
+
+/// UsbHostDevice passes a host usb device through to the guest, picked
+/// either by its host bus and address or by its vendor and product ids
+#[derive(Debug, Default, Clone)]
+pub struct UsbHostDevice {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// Bus is the usb bus of a UsbController, e.g. xhci.0
+    pub bus: String,
+
+    /// HostBus is the host usb bus number of the device
+    pub hostbus: Option<u32>,
+
+    /// HostAddr is the address of the device on its host usb bus
+    pub hostaddr: Option<u32>,
+
+    /// VendorID is the usb vendor id of the device
+    pub vendor_id: Option<u16>,
+
+    /// ProductID is the usb product id of the device
+    pub product_id: Option<u16>,
+}
+
+impl Device for UsbHostDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![
+            USBHOST.to_owned(),
+            format!("id={}", self.id),
+            format!("bus={}", self.bus),
+        ];
+        if let (Some(hostbus), Some(hostaddr)) = (self.hostbus, self.hostaddr) {
+            dev_params.push(format!("hostbus={}", hostbus));
+            dev_params.push(format!("hostaddr={}", hostaddr));
+        }
+        if let (Some(vendor_id), Some(product_id)) = (self.vendor_id, self.product_id) {
+            dev_params.push(format!("vendorid={:#06x}", vendor_id));
+            dev_params.push(format!("productid={:#06x}", product_id));
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        let by_address = self.hostbus.is_some() && self.hostaddr.is_some();
+        let by_ids = self.vendor_id.is_some() && self.product_id.is_some();
+        !self.id.is_empty() && !self.bus.is_empty() && (by_address || by_ids)
+    }
+
+    fn driver(&self) -> &str {
+        USBHOST
+    }
+
+    fn usb_bus(&self) -> Option<&str> {
+        Some(&self.bus)
+    }
+}
+
+/// VSockDevice is a vhost-vsock-pci device, a socket channel between host and guest
+pub struct VSockDevice {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// GuestCID is the context ID of the guest, host side sockets connect to it
+    pub guest_cid: u32,
+
+    /// Virtio holds the virtio options of the device
+    pub virtio: VirtioOptions,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl VSockDevice {
+    /// the lowest cid a guest may use, 0 to 2 are the hypervisor, local and host ones
+    pub const MIN_GUEST_CID: u32 = 3;
+}
+
+impl Device for VSockDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![
+            VHOSTVSOCKPCI.to_owned(),
+            format!("id={}", self.id),
+            format!("guest-cid={}", self.guest_cid),
+        ];
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && self.guest_cid >= Self::MIN_GUEST_CID && self.pci.valid()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
+
+    fn driver(&self) -> &str {
+        VHOSTVSOCKPCI
+    }
+}
+
+/// RngDevice is a virtio-rng device fed by a host random device
+pub struct RngDevice {
+    /// ID is the user defined device ID, the rng-random object is <id>-backend
+    pub id: String,
+
+    /// Filename is the host random device, /dev/urandom if empty
+    pub filename: String,
+
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+
+    /// Virtio holds the virtio options of the device
+    pub virtio: VirtioOptions,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl RngDevice {
+    fn filename(&self) -> &str {
+        if self.filename.is_empty() {
+            "/dev/urandom"
+        } else {
+            &self.filename
+        }
+    }
+}
+
+impl Device for RngDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let backend = format!("{}-backend", self.id);
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(format!(
+            "{},id={},filename={}",
+            RNGRANDOM,
+            backend,
+            qemu_escape(self.filename())
+        ));
+
+        let transport = config.virtio_transport(&self.transport);
+        let mut dev_params = vec![
+            virtio_device_name(VIRTIORNG, transport),
+            format!("rng={}", backend),
+            format!("id={}", self.id),
+        ];
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty()
+            && self
+                .pci
+                .valid_for(&virtio_device_name(VIRTIORNG, &self.transport))
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        let mut paths = self.pci.host_paths();
+        paths.push(self.filename());
+        paths
+    }
+
+    fn driver(&self) -> &str {
+        VIRTIORNG
+    }
+
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        virtio_device_name(VIRTIORNG, config.virtio_transport(&self.transport)).ends_with("-pci")
+    }
+
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
+    }
+}
+
+/// BalloonDevice is the virtio memory balloon
+pub struct BalloonDevice {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+
+    /// DeflateOnOOM lets the guest deflate the balloon when it runs out of memory
+    pub deflate_on_oom: bool,
+
+    /// FreePageReporting reports the guest free pages to the host, needs qemu 5.1
+    pub free_page_reporting: bool,
+
+    /// StatsPollingInterval is how often in seconds the guest memory stats are
+    /// polled, it can only be set at runtime, see `post_launch()`
+    pub stats_polling_interval: u64,
+
+    /// Virtio holds the virtio options of the device
+    pub virtio: VirtioOptions,
+
+    /// Pci holds the pci options of the device
+    pub pci: PciOptions,
+}
+
+impl BalloonDevice {
+    const FREE_PAGE_REPORTING_VERSION: QemuVersion = (5, 1, 0);
+}
+
+impl Device for BalloonDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let transport = config.virtio_transport(&self.transport);
+        let mut dev_params = vec![
+            virtio_device_name(VIRTIOBALLOON, transport),
+            format!("id={}", self.id),
+        ];
+        if self.deflate_on_oom {
+            dev_params.push("deflate-on-oom=on".to_owned());
+        }
+        if self.free_page_reporting {
+            if config.supports(Self::FREE_PAGE_REPORTING_VERSION) {
+                dev_params.push("free-page-reporting=on".to_owned());
+            } else {
+                log::warn!(
+                    "balloon {}: free-page-reporting needs qemu 5.1.0, found {:?}",
+                    self.id,
+                    config.qemu_version
+                );
+            }
+        }
+        dev_params.extend(self.virtio.params(config, &dev_params[0]));
+        dev_params.extend(self.pci.params());
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty()
+            && self
+                .pci
+                .valid_for(&virtio_device_name(VIRTIOBALLOON, &self.transport))
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        self.pci.host_paths()
+    }
+
+    fn driver(&self) -> &str {
+        VIRTIOBALLOON
+    }
+
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        virtio_device_name(VIRTIOBALLOON, config.virtio_transport(&self.transport))
+            .ends_with("-pci")
+    }
+
+    fn devno(&self) -> Option<&str> {
+        self.virtio.devno()
+    }
+
+    fn post_launch(&self, qmp: &mut QmpClient) -> Result<()> {
+        if self.stats_polling_interval == 0 {
+            return Ok(());
+        }
+        qmp.qom_set(
+            &format!("/machine/peripheral/{}", self.id),
+            "guest-stats-polling-interval",
+            self.stats_polling_interval.into(),
+        )
+    }
+}
+
+/// the iommu model of an IommuDevice
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IommuKind {
+    /// the emulated Intel VT-d, only on q35
+    #[default]
+    Intel,
+
+    /// the paravirtualized virtio-iommu
+    Virtio,
+}
+
+/// IommuDevice is the guest iommu, devices opt in with the iommu_platform knob
+pub struct IommuDevice {
+    /// Kind tells the iommu model
+    pub kind: IommuKind,
+
+    /// IntRemap enables interrupt remapping, which needs kernel_irqchip=split
+    pub intremap: bool,
+
+    /// CachingMode reports the mappings to qemu, needed by vfio devices
+    pub caching_mode: bool,
+
+    /// DeviceIotlb enables the device iotlb, for devices with ats
+    pub device_iotlb: bool,
+}
+
+impl Device for IommuDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![self.driver().to_owned()];
+        let options = [
+            ("intremap", self.intremap),
+            ("caching-mode", self.caching_mode),
+            ("device-iotlb", self.device_iotlb),
+        ];
+        for (name, _) in options.iter().filter(|(_, on)| *on) {
+            dev_params.push(format!("{}=on", name));
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        // the options are all vt-d ones
+        self.kind == IommuKind::Intel || !(self.intremap || self.caching_mode || self.device_iotlb)
+    }
+
+    fn driver(&self) -> &str {
+        match self.kind {
+            IommuKind::Intel => INTELIOMMU,
+            IommuKind::Virtio => VIRTIOIOMMUPCI,
+        }
+    }
+
+    fn supports_machine(&self, machine_type: &str) -> bool {
+        // qemu's default x86 machine is pc, vt-d is only emulated by q35
+        self.kind != IommuKind::Intel
+            || machine_type == "q35"
+            || machine_type.starts_with("pc-q35-")
+    }
+}
+
+/// FwConfig is a fw_cfg entry in the device list, the same as one in the config's fw_cfgs
+pub struct FwConfig {
+    pub fw_cfg: FwCfg,
+}
+
+impl Device for FwConfig {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        self.fw_cfg.qemu_params(config);
+    }
+
+    fn valid(&self) -> bool {
+        self.fw_cfg.valid()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        if self.fw_cfg.file.is_empty() {
+            vec![]
+        } else {
+            vec![&self.fw_cfg.file]
+        }
+    }
+}
+
+/// isa-debug-exit, lets test harnesses report pass/fail from the guest
+///
+/// A guest write of `value` to `iobase` makes qemu exit with status
+/// `(value << 1) | 1`, so a debug exit status is always odd, and a plain
+/// qemu exit (0, or 1 on error) cannot be told apart from `value == 0`.
+/// Use `DebugExitDevice::decode_exit_code` on the exit status of qemu
+/// to recover the value written by the guest.
+pub struct DebugExitDevice {
+    /// io port the guest writes to, 0xf4 by convention
+    pub iobase: u16,
+
+    /// width of the io port in bytes, 1, 2 or 4
+    pub iosize: u16,
+}
+
+impl Default for DebugExitDevice {
+    fn default() -> Self {
+        Self {
+            iobase: 0xf4,
+            iosize: 0x04,
+        }
+    }
+}
+
+impl DebugExitDevice {
+    /// returns the value the guest wrote to the debug exit port,
+    /// or None if qemu exited in another way
+    pub fn decode_exit_code(code: i32) -> Option<u32> {
+        if code & 1 == 0 {
+            return None;
+        }
+        Some((code >> 1) as u32)
+    }
+}
+
+impl Device for DebugExitDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(format!(
+            "{},iobase={:#x},iosize={:#04x}",
+            ISADEBUGEXIT, self.iobase, self.iosize
+        ));
+    }
+
+    fn valid(&self) -> bool {
+        matches!(self.iosize, 1 | 2 | 4)
+    }
+}
+
+/// isa-debugcon, captures what the guest writes to an io port into a chardev
+pub struct DebugConDevice {
+    /// io port the guest writes to, 0xe9 by convention
+    pub iobase: u16,
+
+    /// id of the chardev receiving the output
+    pub chardev: String,
+}
+
+impl Device for DebugConDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(format!(
+            "{},iobase={:#x},chardev={}",
+            ISADEBUGCON, self.iobase, self.chardev
+        ));
+    }
+
+    fn valid(&self) -> bool {
+        !self.chardev.is_empty()
+    }
+}
+
+/// AudioCard is an intel-hda controller with a codec playing to an -audiodev backend
+pub struct AudioCard {
+    /// ID is the user defined controller ID
+    pub id: String,
+
+    /// Codec is the hda codec plugged to the controller, hda-duplex if empty
+    pub codec: DeviceDriver,
+
+    /// AudioDev is the ID of the -audiodev backend the codec plays to
+    pub audiodev: String,
+}
+
+impl Device for AudioCard {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let codec = if self.codec.is_empty() {
+            HDADUPLEX
+        } else {
+            &self.codec
+        };
+
+        config.qemu_params.push("-device".to_owned());
+        config
+            .qemu_params
+            .push(format!("{},id={}", INTELHDA, self.id));
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(format!(
+            "{},bus={}.0,audiodev={}",
+            codec, self.id, self.audiodev
+        ));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && !self.audiodev.is_empty()
+    }
+
+    fn driver(&self) -> &str {
+        INTELHDA
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+}
+
+/// FloppyController is an isa-fdc, floppy drives are plugged to its <id>.0 bus
+pub struct FloppyController {
+    /// ID is the user defined device ID
+    pub id: String,
+}
+
+impl Device for FloppyController {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-device".to_owned());
+        config
+            .qemu_params
+            .push(format!("{},id={}", ISAFDC, self.id));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty()
+    }
+
+    fn driver(&self) -> &str {
+        ISAFDC
+    }
+}
+
+/// FloppyDevice is a floppy drive backed by a raw image
+pub struct FloppyDevice {
+    /// ID is the drive ID
+    pub id: String,
+
+    /// File is the path of the floppy image
+    pub file: String,
+
+    /// Unit is the drive number on the controller, 0 or 1
+    pub unit: u8,
+
+    /// Bus is the floppy bus of the controller, e.g. fdc0.0, the default one if empty
+    pub bus: String,
+}
+
+impl Device for FloppyDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-drive".to_owned());
+        config.qemu_params.push(format!(
+            "if=none,id={},format=raw,file={}",
+            self.id,
+            qemu_escape(&self.file)
+        ));
+
+        let mut dev_params = vec![
+            FLOPPY.to_owned(),
+            format!("drive={}", self.id),
+            format!("unit={}", self.unit),
+        ];
+        if !self.bus.is_empty() {
+            dev_params.push(format!("bus={}", self.bus));
+        }
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && !self.file.is_empty() && self.unit <= 1
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.file]
+    }
+
+    fn driver(&self) -> &str {
+        FLOPPY
+    }
+
+    fn drive_id(&self) -> Option<&str> {
+        Some(&self.id)
+    }
+}
+
+/// EduDevice is qemu's educational pci device, to exercise pci drivers
+pub struct EduDevice {
+    /// DmaMask limits the addresses the device can dma to
+    pub dma_mask: Option<u64>,
+}
+
+impl Device for EduDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![EDU.to_owned()];
+        if let Some(dma_mask) = self.dma_mask {
+            dev_params.push(format!("dma_mask={:#x}", dma_mask));
+        }
+
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        true
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+}
+
+/// PciTestDevice is qemu's pci test device, to exercise pci drivers
+pub struct PciTestDevice {}
+
+impl Device for PciTestDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(PCITESTDEV.to_owned());
+    }
+
+    fn valid(&self) -> bool {
+        true
+    }
+
+    fn on_pci_bus(&self, _config: &QemuConfig) -> bool {
+        true
+    }
+}
+
+/// VirtioInputHost passes a host input device, e.g. a keyboard, to the guest
+pub struct VirtioInputHost {
+    /// Evdev is the host event device, e.g. /dev/input/event3
+    pub evdev: String,
+
+    /// Transport is the virtio transport for this device, pci if empty
+    pub transport: VirtioTransport,
+}
+
+impl Device for VirtioInputHost {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(format!(
+            "{},evdev={}",
+            virtio_device_name(VIRTIOINPUTHOST, config.virtio_transport(&self.transport)),
+            qemu_escape(&self.evdev)
+        ));
+    }
+
+    fn valid(&self) -> bool {
+        !self.evdev.is_empty()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.evdev]
+    }
+
+    fn on_pci_bus(&self, config: &QemuConfig) -> bool {
+        virtio_device_name(VIRTIOINPUTHOST, config.virtio_transport(&self.transport))
+            .ends_with("-pci")
+    }
+}
+
+/// InputLinux forwards a host evdev device to the guest's emulated keyboard/mouse
+pub struct InputLinux {
+    /// ID is the user defined object ID
+    pub id: String,
+
+    /// Evdev is the host event device, e.g. /dev/input/event0
+    pub evdev: String,
+
+    /// GrabAll grabs all the host input devices on grab-toggle, not only this one
+    pub grab_all: bool,
+
+    /// Repeat enables the host's key repeat for the device
+    pub repeat: bool,
+}
+
+impl Device for InputLinux {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut obj_params = vec![
+            INPUTLINUX.to_owned(),
+            format!("id={}", self.id),
+            format!("evdev={}", qemu_escape(&self.evdev)),
+        ];
+        if self.grab_all {
+            obj_params.push("grab_all=on".to_owned());
+        }
+        if self.repeat {
+            obj_params.push("repeat=on".to_owned());
+        }
+
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(obj_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && !self.evdev.is_empty()
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.evdev]
+    }
+}
+
+/// VirtioPmem is a persistent memory device backed by a host file, for dax
+pub struct VirtioPmem {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// MemDev is the ID of the memory-backend-file object
+    pub memdev: String,
+
+    /// MemPath is the host file backing the memory
+    pub mem_path: String,
+
+    /// Size is the memory size in bytes
+    pub size: u64,
+}
+
+impl Device for VirtioPmem {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(format!(
+            "{},id={},share=on,mem-path={},size={}",
+            MEMORYBACKENDFILE,
+            self.memdev,
+            qemu_escape(&self.mem_path),
+            self.size
+        ));
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(format!(
+            "{},memdev={},id={}",
+            VIRTIOPMEMPCI, self.memdev, self.id
+        ));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && !self.memdev.is_empty() && !self.mem_path.is_empty() && self.size > 0
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![&self.mem_path]
+    }
+
+    fn hotplug_memory(&self) -> Option<u64> {
+        Some(self.size)
+    }
+
+    fn driver(&self) -> &str {
+        VIRTIOPMEMPCI
+    }
+}
+
+/// PcDimm is a dimm plugged into the hotplug memory region, backed by anonymous memory
+pub struct PcDimm {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// MemDev is the ID of the memory-backend-ram object
+    pub memdev: String,
+
+    /// Size is the dimm size, suffixed with M or G
+    pub size: String,
+
+    /// Node is the numa node the dimm belongs to
+    pub node: Option<u32>,
+}
+
+impl Device for PcDimm {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(format!(
+            "{},id={},size={}",
+            MEMORYBACKENDRAM, self.memdev, self.size
+        ));
+
+        let mut dev_params = vec![
+            PCDIMM.to_owned(),
+            format!("id={}", self.id),
+            format!("memdev={}", self.memdev),
+        ];
+        if let Some(node) = self.node {
+            dev_params.push(format!("node={}", node));
+        }
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && !self.memdev.is_empty() && self.hotplug_memory().is_some()
+    }
+
+    fn driver(&self) -> &str {
+        PCDIMM
+    }
+
+    fn hotplug_memory(&self) -> Option<u64> {
+        parse_size(&self.size, 0).filter(|size| *size > 0)
+    }
+}
+
+/// CpuDevice is a vcpu cold-plugged with -device, into a slot smp.max_cpus
+/// leaves above smp.cpus, so that it can be hot-unplugged later on
+pub struct CpuDevice {
+    /// ID is the user defined device ID
+    pub id: String,
+
+    /// Driver is the cpu type, e.g. host-x86_64-cpu
+    pub driver: DeviceDriver,
+
+    /// SocketID, CoreID and ThreadID locate the slot of the vcpu in the smp topology
+    pub socket_id: Option<u32>,
+    pub core_id: Option<u32>,
+    pub thread_id: Option<u32>,
+}
+
+impl Device for CpuDevice {
+    fn set_qemu_params(&self, config: &mut QemuConfig) {
+        let mut dev_params = vec![self.driver.to_owned(), format!("id={}", self.id)];
+        let slot = [
+            ("socket-id", self.socket_id),
+            ("core-id", self.core_id),
+            ("thread-id", self.thread_id),
+        ];
+        for (key, id) in slot {
+            if let Some(id) = id {
+                dev_params.push(format!("{}={}", key, id));
+            }
+        }
+        config.qemu_params.push("-device".to_owned());
+        config.qemu_params.push(dev_params.join(","));
+    }
+
+    fn valid(&self) -> bool {
+        !self.id.is_empty() && self.driver.ends_with("-cpu")
+    }
+
+    fn driver(&self) -> &str {
+        &self.driver
+    }
+
+    fn cold_vcpus(&self) -> u32 {
+        1
+    }
+}
+
+/// DeviceSpec declares a device in the config file, see `QemuConfig::build_devices()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeviceSpec {
+    /// a virtio-net-pci device unless driver is set
+    Net {
+        id: String,
+        #[serde(default)]
+        driver: DeviceDriver,
+        #[serde(flatten)]
+        netdev: NetdevBackend,
+        #[serde(default)]
+        mac: String,
+        #[serde(default)]
+        generate_mac: bool,
+        #[serde(default)]
+        boot_index: Option<u32>,
+    },
+
+    /// a virtio-blk disk unless driver is set
+    Block {
+        id: String,
+        file: String,
+        #[serde(default)]
+        format: String,
+        #[serde(default)]
+        driver: DeviceDriver,
+        #[serde(default)]
+        transport: VirtioTransport,
+        #[serde(default)]
+        bus: String,
+        #[serde(default)]
+        readonly: bool,
+        #[serde(default)]
+        cache: String,
+        #[serde(default)]
+        aio: String,
+        #[serde(default)]
+        boot_index: Option<u32>,
+    },
+
+    Rng {
+        id: String,
+        #[serde(default)]
+        filename: String,
+        #[serde(default)]
+        transport: VirtioTransport,
+    },
+
+    Balloon {
+        id: String,
+        #[serde(default)]
+        transport: VirtioTransport,
+        #[serde(default)]
+        deflate_on_oom: bool,
+        #[serde(default)]
+        free_page_reporting: bool,
+        #[serde(default)]
+        stats_polling_interval: u64,
+        #[serde(default)]
+        disable_modern: Option<bool>,
+    },
+
+    /// a virtio-scsi controller unless driver is set
+    Scsi {
+        id: String,
+        #[serde(default)]
+        driver: DeviceDriver,
+        #[serde(default)]
+        transport: VirtioTransport,
+        #[serde(default)]
+        iothread: String,
+        #[serde(default)]
+        num_queues: u32,
+    },
+
+    Bridge {
+        id: String,
+        #[serde(default)]
+        bridge_type: BridgeType,
+        bus: String,
+        #[serde(default)]
+        address: String,
+        #[serde(default)]
+        chassis_nr: u32,
+        #[serde(default)]
+        shpc: bool,
+    },
+
+    VhostUser {
+        id: String,
+        device_type: DeviceDriver,
+        socket_path: String,
+        char_id: String,
+        #[serde(default)]
+        tag: String,
+        #[serde(default)]
+        transport: VirtioTransport,
+    },
+
+    /// an intel-iommu unless model is set, kind being the spec's tag
+    Iommu {
+        #[serde(default)]
+        model: IommuKind,
+        #[serde(default)]
+        intremap: bool,
+        #[serde(default)]
+        caching_mode: bool,
+        #[serde(default)]
+        device_iotlb: bool,
+    },
+
+    Loader {
+        file: String,
+        #[serde(default)]
+        addr: Option<u64>,
+        #[serde(default)]
+        cpu_num: Option<u32>,
+        #[serde(default)]
+        force_raw: bool,
+    },
+
+    RootPort {
+        id: String,
+        #[serde(default)]
+        bus: String,
+        chassis: u32,
+        #[serde(default)]
+        slot: u32,
+        #[serde(default)]
+        multifunction: bool,
+    },
+
+    Vsock {
+        id: String,
+        guest_cid: u32,
+    },
+
+    /// a qemu-xhci unless controller_type is set
+    UsbController {
+        #[serde(default)]
+        id: String,
+        #[serde(default)]
+        controller_type: UsbControllerType,
+    },
+
+    UsbHost {
+        id: String,
+        bus: String,
+        #[serde(default)]
+        hostbus: Option<u32>,
+        #[serde(default)]
+        hostaddr: Option<u32>,
+        #[serde(default)]
+        vendor_id: Option<u16>,
+        #[serde(default)]
+        product_id: Option<u16>,
+    },
+
+    Chardev {
+        id: String,
+        backend: CharDeviceBackend,
+        #[serde(default)]
+        path: String,
+        #[serde(default)]
+        server: bool,
+        #[serde(default)]
+        nowait: bool,
+        #[serde(default)]
+        mux: bool,
+    },
+
+    /// a vcpu cold-plugged into a free slot, e.g. host-x86_64-cpu
+    Cpu {
+        id: String,
+        driver: DeviceDriver,
+        #[serde(default)]
+        socket_id: Option<u32>,
+        #[serde(default)]
+        core_id: Option<u32>,
+        #[serde(default)]
+        thread_id: Option<u32>,
+    },
+}
+
+/// the given driver, or the default one if it is empty
+fn driver_or(driver: &str, default: &str) -> DeviceDriver {
+    if driver.is_empty() {
+        default.to_owned()
+    } else {
+        driver.to_owned()
+    }
+}
+
+impl DeviceSpec {
+    /// instantiate the declared device
+    pub fn build(&self) -> Box<dyn Device> {
+        match self {
+            DeviceSpec::Net {
+                id,
+                driver,
+                netdev,
+                mac,
+                generate_mac,
+                boot_index,
+            } => Box::new(NetDevice {
+                id: id.to_owned(),
+                driver: driver_or(driver, VIRTIONETPCI),
+                netdev: netdev.clone(),
+                mac: mac.to_owned(),
+                generate_mac: *generate_mac,
+                virtio: VirtioOptions::default(),
+                boot_index: *boot_index,
+                offloads: NetOffloads::default(),
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::Block {
+                id,
+                file,
+                format,
+                driver,
+                transport,
+                bus,
+                readonly,
+                cache,
+                aio,
+                boot_index,
+            } => Box::new(BlockDevice {
+                id: id.to_owned(),
+                file: file.to_owned(),
+                format: format.to_owned(),
+                driver: driver_or(driver, VIRTIOBLOCK),
+                transport: transport.to_owned(),
+                bus: bus.to_owned(),
+                logical_block_size: None,
+                physical_block_size: None,
+                readonly: *readonly,
+                cache: cache.to_owned(),
+                aio: aio.to_owned(),
+                virtio: VirtioOptions::default(),
+                boot_index: *boot_index,
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::Rng {
+                id,
+                filename,
+                transport,
+            } => Box::new(RngDevice {
+                id: id.to_owned(),
+                filename: filename.to_owned(),
+                transport: transport.to_owned(),
+                virtio: VirtioOptions::default(),
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::Balloon {
+                id,
+                transport,
+                deflate_on_oom,
+                free_page_reporting,
+                stats_polling_interval,
+                disable_modern,
+            } => Box::new(BalloonDevice {
+                id: id.to_owned(),
+                transport: transport.to_owned(),
+                deflate_on_oom: *deflate_on_oom,
+                free_page_reporting: *free_page_reporting,
+                stats_polling_interval: *stats_polling_interval,
+                virtio: VirtioOptions {
+                    disable_modern: *disable_modern,
+                    ..Default::default()
+                },
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::Scsi {
+                id,
+                driver,
+                transport,
+                iothread,
+                num_queues,
+            } => Box::new(ScsiController {
+                id: id.to_owned(),
+                driver: driver_or(driver, VIRTIOSCSI),
+                transport: transport.to_owned(),
+                iothread: iothread.to_owned(),
+                num_queues: *num_queues,
+                virtio: VirtioOptions::default(),
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::Bridge {
+                id,
+                bridge_type,
+                bus,
+                address,
+                chassis_nr,
+                shpc,
+            } => Box::new(BridgeDevice {
+                id: id.to_owned(),
+                bridge_type: *bridge_type,
+                bus: bus.to_owned(),
+                address: address.to_owned(),
+                chassis_nr: *chassis_nr,
+                shpc: *shpc,
+            }),
+            DeviceSpec::VhostUser {
+                id,
+                device_type,
+                socket_path,
+                char_id,
+                tag,
+                transport,
+            } => Box::new(VhostUserDevice {
+                id: id.to_owned(),
+                device_type: device_type.to_owned(),
+                socket_path: socket_path.to_owned(),
+                char_id: char_id.to_owned(),
+                tag: tag.to_owned(),
+                transport: transport.to_owned(),
+                virtio: VirtioOptions::default(),
+            }),
+            DeviceSpec::Iommu {
+                model,
+                intremap,
+                caching_mode,
+                device_iotlb,
+            } => Box::new(IommuDevice {
+                kind: *model,
+                intremap: *intremap,
+                caching_mode: *caching_mode,
+                device_iotlb: *device_iotlb,
+            }),
+            DeviceSpec::Loader {
+                file,
+                addr,
+                cpu_num,
+                force_raw,
+            } => Box::new(LoaderDevice {
+                file: file.to_owned(),
+                addr: *addr,
+                cpu_num: *cpu_num,
+                force_raw: *force_raw,
+            }),
+            DeviceSpec::RootPort {
+                id,
+                bus,
+                chassis,
+                slot,
+                multifunction,
+            } => Box::new(PcieRootPortDevice {
+                id: id.to_owned(),
+                bus: bus.to_owned(),
+                chassis: *chassis,
+                slot: *slot,
+                multifunction: *multifunction,
+            }),
+            DeviceSpec::Vsock { id, guest_cid } => Box::new(VSockDevice {
+                id: id.to_owned(),
+                guest_cid: *guest_cid,
+                virtio: VirtioOptions::default(),
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::UsbController {
+                id,
+                controller_type,
+            } => Box::new(UsbController {
+                id: id.to_owned(),
+                controller_type: *controller_type,
+                pci: PciOptions::default(),
+            }),
+            DeviceSpec::UsbHost {
+                id,
+                bus,
+                hostbus,
+                hostaddr,
+                vendor_id,
+                product_id,
+            } => Box::new(UsbHostDevice {
+                id: id.to_owned(),
+                bus: bus.to_owned(),
+                hostbus: *hostbus,
+                hostaddr: *hostaddr,
+                vendor_id: *vendor_id,
+                product_id: *product_id,
+            }),
+            DeviceSpec::Chardev {
+                id,
+                backend,
+                path,
+                server,
+                nowait,
+                mux,
+            } => Box::new(CharDevice {
+                id: id.to_owned(),
+                backend: backend.to_owned(),
+                path: path.to_owned(),
+                server: *server,
+                nowait: *nowait,
+                mux: *mux,
+                append: false,
+                logfile: String::new(),
+                logappend: false,
+            }),
+            DeviceSpec::Cpu {
+                id,
+                driver,
+                socket_id,
+                core_id,
+                thread_id,
+            } => Box::new(CpuDevice {
+                id: id.to_owned(),
+                driver: driver.to_owned(),
+                socket_id: *socket_id,
+                core_id: *core_id,
+                thread_id: *thread_id,
+            }),
+        }
+    }
+}
+
+/// BluetoothDevice stands for a usb-bt-dongle, it is always rejected as
+/// qemu removed its bluetooth emulation
+pub struct BluetoothDevice {}
+
+impl Device for BluetoothDevice {
+    fn set_qemu_params(&self, _config: &mut QemuConfig) {}
+
+    fn valid(&self) -> bool {
+        false
+    }
+
+    fn check(&self) -> Result<()> {
+        Err(anyhow!(
+            "bluetooth emulation (usb-bt-dongle, -bt) was removed in qemu 3.1, \
+             pass a host bluetooth adapter through with usb-host or vfio-pci instead"
+        ))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::qmp::tests::{serve_session, socket_path};
+    use std::os::unix::net::UnixListener;
+
+    /// a virtio-net-pci device without backend, for the tests to adjust
+    pub(crate) fn net(id: &str) -> NetDevice {
+        NetDevice {
+            id: id.to_owned(),
+            driver: VIRTIONETPCI.to_owned(),
+            netdev: NetdevBackend::default(),
+            mac: String::new(),
+            generate_mac: false,
+            virtio: VirtioOptions::default(),
+            boot_index: None,
+            offloads: NetOffloads::default(),
+            pci: PciOptions::default(),
+        }
+    }
+
+    /// a virtio-blk-pci device on the raw image file, for the tests to adjust
+    pub(crate) fn block(id: &str, file: &str) -> BlockDevice {
+        BlockDevice {
+            id: id.to_owned(),
+            file: file.to_owned(),
+            format: "raw".to_owned(),
+            driver: VIRTIOBLOCK.to_owned(),
+            transport: String::new(),
+            bus: String::new(),
+            logical_block_size: None,
+            physical_block_size: None,
+            readonly: false,
+            cache: String::new(),
+            aio: String::new(),
+            virtio: VirtioOptions::default(),
+            boot_index: None,
+            pci: PciOptions::default(),
+        }
+    }
+
+    /// an object of obj_type with none of its options set
+    fn object(obj_type: &str, id: &str) -> Object {
+        Object {
+            driver: String::new(),
+            obj_type: obj_type.to_owned(),
+            id: id.to_owned(),
+            device_id: String::new(),
+            mem_path: String::new(),
+            size: 0,
+            debug: false,
+            file: String::new(),
+            firmware_volume: String::new(),
+            c_bit_pos: 0,
+            reduced_physical_bits: 0,
+            rd_only: false,
+            prealloc: false,
+        }
+    }
+
+    /// a virtio serial port on chardev
+    fn port(chardev: &str, nr: Option<u32>) -> SerialPort {
+        SerialPort {
+            chardev: chardev.to_owned(),
+            name: String::new(),
+            nr,
+        }
+    }
+
+    #[test]
+    fn test_debug_exit_device() {
+        let dev = DebugExitDevice::default();
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "isa-debug-exit,iobase=0xf4,iosize=0x04"]
+        );
+
+        // guest wrote 0x10
+        assert_eq!(DebugExitDevice::decode_exit_code(0x21), Some(0x10));
+        assert_eq!(DebugExitDevice::decode_exit_code(0), None);
+    }
+
+    #[test]
+    fn test_debugcon_device() {
+        let mut dev = DebugConDevice {
+            iobase: 0xe9,
+            chardev: String::new(),
+        };
+        assert!(!dev.valid());
+
+        dev.chardev = "debugcon0".to_owned();
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "isa-debugcon,iobase=0xe9,chardev=debugcon0"]
+        );
+    }
+
+    #[test]
+    fn test_net_device_offloads() {
+        let dev = NetDevice {
+            offloads: NetOffloads {
+                csum: Some(false),
+                guest_csum: Some(false),
+                gso: Some(false),
+                host_tso4: Some(false),
+                ..Default::default()
+            },
+            ..net("net0")
+        };
+        assert!(dev.valid());
+        assert!(dev.offloads.orphaned().is_empty());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "virtio-net-pci,netdev=net0,csum=off,gso=off,host_tso4=off,guest_csum=off"
+            ]
+        );
+
+        let orphaned = NetOffloads {
+            guest_csum: Some(false),
+            guest_tso4: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(orphaned.orphaned(), vec!["guest_tso4"]);
+        assert!(dev.warnings().is_empty());
+
+        let dev = NetDevice {
+            offloads: orphaned,
+            ..net("net0")
+        };
+        assert!(dev.valid());
+        assert_eq!(
+            dev.warnings(),
+            vec!["net device net0: guest_tso4=on has no effect with its checksum offload off"]
+        );
+    }
+
+    #[test]
+    fn test_serial_port_nr_assignment() {
+        let mut dev = SerialDevice {
+            id: "vser0".to_owned(),
+            transport: String::new(),
+            ports: vec![port("ch0", None), port("ch1", Some(1)), port("ch2", None)],
+            pci: PciOptions::default(),
+        };
+        assert!(dev.valid());
+        assert_eq!(dev.assigned_nrs(), vec![2, 1, 3]);
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(config.qemu_params[1], "virtio-serial-pci,id=vser0");
+        assert_eq!(
+            config.qemu_params[3],
+            "virtserialport,bus=vser0.0,nr=2,chardev=ch0"
+        );
+
+        dev.ports[2].nr = Some(1);
+        assert!(!dev.valid());
+    }
+
+    #[test]
+    fn test_block_device_block_sizes() {
+        let mut dev = BlockDevice {
+            logical_block_size: Some(4096),
+            physical_block_size: Some(4096),
+            ..block("d0", "/var/lib/disk.img")
+        };
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-drive",
+                "id=d0,file=/var/lib/disk.img,format=raw,if=none",
+                "-device",
+                "virtio-blk-pci,drive=d0,logical_block_size=4096,physical_block_size=4096",
+            ]
+        );
+
+        dev.physical_block_size = Some(512);
+        assert!(!dev.valid());
+
+        dev.physical_block_size = Some(3000);
+        assert!(!dev.valid());
+    }
+
+    #[test]
+    fn test_qcow2_disk_cache_and_aio() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "block",
+            "id": "root",
+            "file": "/var/lib/vm/root.qcow2",
+            "format": "qcow2",
+            "cache": "none",
+            "aio": "native",
+        }))
+        .unwrap();
+        let dev = spec.build();
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-drive",
+                "id=root,file=/var/lib/vm/root.qcow2,format=qcow2,if=none,cache=none,aio=native",
+                "-device",
+                "virtio-blk-pci,drive=root",
+            ]
+        );
+
+        let disk = |format: &str, cache: &str| BlockDevice {
+            format: format.to_owned(),
+            readonly: true,
+            cache: cache.to_owned(),
+            aio: "native".to_owned(),
+            ..block("d0", "/var/lib/disk.img")
+        };
+        assert!(!disk("qcow3", "none").valid());
+        // native aio goes around the host page cache
+        assert!(!disk("raw", "writeback").valid());
+        assert!(disk("raw", "directsync").valid());
+
+        // the device bus and the pci bus are the same property
+        let mut dev = disk("raw", "none");
+        dev.pci.addr = "0x6".to_owned();
+        assert!(dev.valid());
+        dev.pci.bus = "pcie.0".to_owned();
+        assert!(dev.valid());
+        dev.bus = "pcie.1".to_owned();
+        assert!(!dev.valid());
+    }
+
+    #[test]
+    fn test_vfio_display_ramfb() {
+        let mut dev = VFIODevice {
+            driver: VFIOPCI.to_owned(),
+            bdf: "0000:00:02.0".to_owned(),
+            mdev_path: String::new(),
+            display: false,
+            ramfb: true,
+            x_igd_opregion: true,
+            x_vga: false,
+            pci: PciOptions::default(),
+        };
+        assert!(!dev.valid());
+
+        dev.display = true;
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "vfio-pci,host=0000:00:02.0,display=on,ramfb=on,x-igd-opregion=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vfio_mdev() {
+        let mut dev = VFIODevice {
+            driver: VFIOPCI.to_owned(),
+            bdf: "0000:01:00.0".to_owned(),
+            mdev_path: "/sys/bus/mdev/devices/c2e2c1e4-7f5a-4f3e-9a0c-5d3b7b0e6a11".to_owned(),
+            display: false,
+            ramfb: false,
+            x_igd_opregion: false,
+            x_vga: false,
+            pci: PciOptions::default(),
+        };
+        assert!(!dev.valid());
+
+        dev.bdf.clear();
+        assert!(dev.valid());
+        assert_eq!(dev.host_paths(), vec![dev.mdev_path.as_str()]);
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "vfio-pci,sysfsdev=/sys/bus/mdev/devices/c2e2c1e4-7f5a-4f3e-9a0c-5d3b7b0e6a11"
+            ]
+        );
+
+        dev.mdev_path.clear();
+        assert!(!dev.valid());
+    }
+
+    #[test]
+    fn test_usb_controllers() {
+        let mut config = QemuConfig::default();
+        let xhci = UsbController {
+            id: "xhci".to_owned(),
+            controller_type: UsbControllerType::Xhci,
+            pci: PciOptions::default(),
+        };
+        let uhci = UsbController {
+            id: String::new(),
+            controller_type: UsbControllerType::Uhci,
+            pci: PciOptions::default(),
+        };
+        assert!(xhci.valid() && uhci.valid());
+        assert_eq!(xhci.provided_usb_bus().unwrap(), "xhci.0");
+        assert_eq!(uhci.provided_usb_bus().unwrap(), UsbController::UHCI_BUS);
+
+        let dev = UsbHostDevice {
+            id: "yubikey".to_owned(),
+            bus: "xhci.0".to_owned(),
+            vendor_id: Some(0x1050),
+            product_id: Some(0x407),
+            ..Default::default()
+        };
+        assert!(dev.valid());
+
+        xhci.set_qemu_params(&mut config);
+        uhci.set_qemu_params(&mut config);
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "qemu-xhci,id=xhci",
+                "-usb",
+                "-device",
+                "usb-host,id=yubikey,bus=xhci.0,vendorid=0x1050,productid=0x0407",
+            ]
+        );
+
+        // a host device needs both halves of its address
+        let dev = UsbHostDevice {
+            hostbus: Some(1),
+            ..dev
+        };
+        assert!(dev.valid());
+        let dev = UsbHostDevice {
+            vendor_id: None,
+            ..dev
+        };
+        assert!(!dev.valid());
+    }
+
+    #[test]
+    fn test_driver_development_devices() {
+        let mut config = QemuConfig::default();
+        let edu = EduDevice { dma_mask: None };
+        let testdev = PciTestDevice {};
+        assert!(edu.valid() && testdev.valid());
+
+        edu.set_qemu_params(&mut config);
+        testdev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "edu", "-device", "pci-testdev"]
+        );
+
+        let mut config = QemuConfig::default();
+        let edu = EduDevice {
+            dma_mask: Some(0xffffffff),
+        };
+        edu.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "edu,dma_mask=0xffffffff"]
+        );
+    }
+
+    #[test]
+    fn test_net_device_rombar() {
+        let mut dev = NetDevice {
+            pci: PciOptions {
+                rombar: Some(0),
+                ..Default::default()
+            },
+            ..net("net0")
+        };
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "virtio-net-pci,netdev=net0,rombar=0"]
+        );
+
+        dev.pci.romfile = "/nonexistent/efi-virtio.rom".to_owned();
+        assert_eq!(dev.host_paths(), vec!["/nonexistent/efi-virtio.rom"]);
+    }
+
+    #[test]
+    fn test_pci_bus_and_addr() {
+        let mut controller = UsbController {
+            id: "xhci".to_owned(),
+            controller_type: UsbControllerType::Xhci,
+            pci: PciOptions {
+                bus: "pcie.0".to_owned(),
+                addr: "0x1d.7".to_owned(),
+                ..Default::default()
+            },
+        };
+        assert!(controller.valid());
+
+        let mut config = QemuConfig::default();
+        controller.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "qemu-xhci,id=xhci,bus=pcie.0,addr=0x1d.7"]
+        );
+
+        for addr in ["0x3", "0x1f", "0x0.0"] {
+            controller.pci.addr = addr.to_owned();
+            assert!(controller.valid(), "{}", addr);
+        }
+        for addr in ["3", "0x", "0x20", "0x3.8", "0x3.", "0x+3", "0xg"] {
+            controller.pci.addr = addr.to_owned();
+            assert!(!controller.valid(), "{}", addr);
+        }
+    }
+
+    #[test]
+    fn test_pci_options_on_virtio_devices() {
+        let pci = PciOptions {
+            bus: "pcie.0".to_owned(),
+            addr: "0x5".to_owned(),
+            ..Default::default()
+        };
+        let mut balloon = BalloonDevice {
+            id: "balloon0".to_owned(),
+            transport: String::new(),
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            stats_polling_interval: 0,
+            virtio: VirtioOptions::default(),
+            pci: pci.clone(),
+        };
+        assert!(balloon.valid());
+
+        let mut config = QemuConfig::default();
+        balloon.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "virtio-balloon-pci,id=balloon0,bus=pcie.0,addr=0x5"
+            ]
+        );
+
+        // ccw and mmio devices are not on a pci bus
+        balloon.transport = TRANSPORTCCW.to_owned();
+        assert!(!balloon.valid());
+        balloon.transport = TRANSPORTMMIO.to_owned();
+        assert!(!balloon.valid());
+
+        let mut net = NetDevice {
+            driver: "virtio-net-ccw".to_owned(),
+            pci: pci.clone(),
+            ..net("net0")
+        };
+        assert!(!net.valid());
+        net.driver = "virtio-net-device".to_owned();
+        assert!(!net.valid());
+        net.driver = VIRTIONETPCI.to_owned();
+        assert!(net.valid());
+
+        let mut vsock = VSockDevice {
+            id: "vsock0".to_owned(),
+            guest_cid: 3,
+            virtio: VirtioOptions::default(),
+            pci,
+        };
+        let mut config = QemuConfig::default();
+        vsock.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params[1],
+            "vhost-vsock-pci,id=vsock0,guest-cid=3,bus=pcie.0,addr=0x5"
+        );
+        vsock.pci.addr = "0x20".to_owned();
+        assert!(!vsock.valid());
+    }
+
+    #[test]
+    fn test_virtio_input_host() {
+        let mut dev = VirtioInputHost {
+            evdev: String::new(),
+            transport: String::new(),
+        };
+        assert!(!dev.valid());
+
+        dev.evdev = "/dev/input/event3".to_owned();
+        assert!(dev.valid());
+        assert_eq!(dev.host_paths(), vec!["/dev/input/event3"]);
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "virtio-input-host-pci,evdev=/dev/input/event3"]
+        );
+    }
+
+    #[test]
+    fn test_virtio_pmem() {
+        let mut dev = VirtioPmem {
+            id: "pmem0".to_owned(),
+            memdev: "mem1".to_owned(),
+            mem_path: String::new(),
+            size: 4 << 30,
+        };
+        assert!(!dev.valid());
+
+        dev.mem_path = "/pmem".to_owned();
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-object",
+                "memory-backend-file,id=mem1,share=on,mem-path=/pmem,size=4294967296",
+                "-device",
+                "virtio-pmem-pci,memdev=mem1,id=pmem0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_megasas_with_scsi_hd() {
+        let controller = ScsiController {
+            id: "scsi0".to_owned(),
+            driver: MEGASAS.to_owned(),
+            transport: String::new(),
+            iothread: String::new(),
+            num_queues: 0,
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        };
+        let disk = BlockDevice {
+            driver: SCSIHD.to_owned(),
+            bus: "scsi0.0".to_owned(),
+            ..block("d0", "/var/lib/old-os.img")
+        };
+        assert!(controller.valid() && disk.valid());
+
+        let mut config = QemuConfig::default();
+        controller.set_qemu_params(&mut config);
+        disk.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "megasas,id=scsi0",
+                "-drive",
+                "id=d0,file=/var/lib/old-os.img,format=raw,if=none",
+                "-device",
+                "scsi-hd,drive=d0,bus=scsi0.0",
+            ]
+        );
+
+        let lsi = ScsiController {
+            driver: LSI53C895A.to_owned(),
+            ..controller
+        };
+        assert!(lsi.valid());
+    }
+
+    #[test]
+    fn test_bluetooth_device_is_rejected() {
+        let dev = BluetoothDevice {};
+        assert!(!dev.valid());
+        let err = dev.check().unwrap_err().to_string();
+        assert!(err.contains("removed in qemu"));
+        assert!(err.contains("usb-host"));
+
+        let config = QemuConfig::default().add_devices(&[Box::new(dev) as Box<dyn Device>]);
+        assert!(config.qemu_params.is_empty());
+    }
+
+    #[test]
+    fn test_generate_mac() {
+        let mac = generate_mac("vm0", "net0");
+        assert!(mac.starts_with("52:54:00:"));
+        assert_eq!(mac.len(), 17);
+        assert_eq!(mac, generate_mac("vm0", "net0"));
+        assert_ne!(mac, generate_mac("vm0", "net1"));
+        assert_ne!(mac, generate_mac("vm1", "net0"));
+
+        let dev = NetDevice {
+            generate_mac: true,
+            ..net("net0")
+        };
+        let mut config = QemuConfig::default();
+        config.name = "vm0".to_owned();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params[1],
+            format!("virtio-net-pci,netdev=net0,mac={}", mac)
+        );
+    }
+
+    #[test]
+    fn test_balloon_free_page_reporting_gated_by_version() {
+        let dev = BalloonDevice {
+            id: "balloon0".to_owned(),
+            transport: String::new(),
+            deflate_on_oom: false,
+            free_page_reporting: true,
+            stats_polling_interval: 0,
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        };
+
+        let mut config = QemuConfig::default().set_qemu_version((5, 0, 0));
+        dev.set_qemu_params(&mut config);
+        assert_eq!(config.qemu_params[1], "virtio-balloon-pci,id=balloon0");
+
+        let mut config = QemuConfig::default().set_qemu_version((5, 1, 0));
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params[1],
+            "virtio-balloon-pci,id=balloon0,free-page-reporting=on"
+        );
+    }
+
+    #[test]
+    fn test_balloon_from_spec() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "balloon",
+            "id": "balloon0",
+            "deflate_on_oom": true,
+            "disable_modern": true,
+        }))
+        .unwrap();
+        let mut config = QemuConfig::default();
+        spec.build().set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "virtio-balloon-pci,id=balloon0,deflate-on-oom=on,disable-modern=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pvscsi_with_scsi_hd() {
+        let controller = ScsiController {
+            id: "scsi0".to_owned(),
+            driver: PVSCSI.to_owned(),
+            transport: String::new(),
+            iothread: String::new(),
+            num_queues: 0,
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        };
+        let disk = BlockDevice {
+            format: "vmdk".to_owned(),
+            driver: SCSIHD.to_owned(),
+            bus: "scsi0.0".to_owned(),
+            ..block("d0", "/var/lib/vmware.vmdk")
+        };
+        assert!(controller.valid() && disk.valid());
+
+        let mut config = QemuConfig::default();
+        controller.set_qemu_params(&mut config);
+        disk.set_qemu_params(&mut config);
+        assert_eq!(config.qemu_params[1], "pvscsi,id=scsi0");
+        assert_eq!(config.qemu_params[5], "scsi-hd,drive=d0,bus=scsi0.0");
+    }
+
+    #[test]
+    fn test_paths_with_commas_are_escaped() {
+        let disk = block("d0", "/a,b/disk.img");
+        let mut config = QemuConfig::default();
+        disk.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params[1],
+            "id=d0,file=/a,,b/disk.img,format=raw,if=none"
+        );
+    }
+
+    #[test]
+    fn test_input_linux() {
+        let mut obj = InputLinux {
+            id: "kbd".to_owned(),
+            evdev: String::new(),
+            grab_all: true,
+            repeat: true,
+        };
+        assert!(!obj.valid());
+
+        obj.evdev = "/dev/input/event0".to_owned();
+        assert!(obj.valid());
+        let mut config = QemuConfig::default();
+        obj.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-object",
+                "input-linux,id=kbd,evdev=/dev/input/event0,grab_all=on,repeat=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_virtio_options() {
+        let dev = NetDevice {
+            virtio: VirtioOptions {
+                disable_legacy: Some(true),
+                disable_modern: Some(false),
+                ..Default::default()
+            },
+            ..net("net0")
+        };
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params[1],
+            "virtio-net-pci,netdev=net0,disable-legacy=on,disable-modern=off"
+        );
+
+        let rng = RngDevice {
+            id: "rng0".to_owned(),
+            filename: String::new(),
+            transport: String::new(),
+            virtio: VirtioOptions {
+                disable_legacy: Some(true),
+                ..Default::default()
+            },
+            pci: PciOptions::default(),
+        };
+        let mut config = QemuConfig::default();
+        rng.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-object",
+                "rng-random,id=rng0-backend,filename=/dev/urandom",
+                "-device",
+                "virtio-rng-pci,rng=rng0-backend,id=rng0,disable-legacy=on",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_balloon_stats_polling_interval_post_launch() {
+        let path = socket_path("balloon");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || serve_session(&listener, &[r#"{"return": {}}"#]).0);
+
+        let dev = BalloonDevice {
+            id: "balloon0".to_owned(),
+            transport: String::new(),
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            stats_polling_interval: 5,
+            virtio: VirtioOptions::default(),
+            pci: PciOptions::default(),
+        };
+        let mut qmp = QmpClient::connect(&path).unwrap();
+        dev.post_launch(&mut qmp).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[1],
+            serde_json::json!({
+                "execute": "qom-set",
+                "arguments": {
+                    "path": "/machine/peripheral/balloon0",
+                    "property": "guest-stats-polling-interval",
+                    "value": 5,
+                },
+            })
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_chardev_append_and_logfile() {
+        let mut dev = CharDevice {
+            id: "serial0".to_owned(),
+            backend: CHARDEVFILE.to_owned(),
+            path: String::new(),
+            server: false,
+            nowait: false,
+            mux: false,
+            append: true,
+            logfile: "/var/log/vm/serial0.log".to_owned(),
+            logappend: true,
+        };
+        assert!(!dev.valid());
+
+        dev.path = "/var/log/vm/console".to_owned();
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-chardev",
+                "file,id=serial0,path=/var/log/vm/console,append=on,\
+                 logfile=/var/log/vm/serial0.log,logappend=on",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_socket_chardev_server() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "chardev",
+            "id": "console0",
+            "backend": "socket",
+            "server": true,
+            "nowait": true,
+        }))
+        .unwrap();
+        // a socket needs a path
+        assert!(!spec.build().valid());
+
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "chardev",
+            "id": "console0",
+            "backend": "socket",
+            "path": "/run/vm/console.sock",
+            "server": true,
+            "nowait": true,
+        }))
+        .unwrap();
+        let dev = spec.build();
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-chardev",
+                "socket,id=console0,path=/run/vm/console.sock,server=on,wait=off",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vsock_guest_cid() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "vsock",
+            "id": "vsock0",
+            "guest_cid": 2,
+        }))
+        .unwrap();
+        // cid 2 is the host
+        assert!(!spec.build().valid());
+
+        let dev = VSockDevice {
+            id: "vsock0".to_owned(),
+            guest_cid: 3,
+            virtio: VirtioOptions {
+                disable_modern: Some(false),
+                ..Default::default()
+            },
+            pci: PciOptions::default(),
+        };
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "vhost-vsock-pci,id=vsock0,guest-cid=3,disable-modern=off"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pcie_root_port_from_spec() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "root_port",
+            "id": "rp0",
+            "bus": "pcie.0",
+            "chassis": 0,
+        }))
+        .unwrap();
+        assert!(!spec.build().valid());
+
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "root_port",
+            "id": "rp0",
+            "bus": "pcie.0",
+            "chassis": 1,
+            "slot": 2,
+            "multifunction": true,
+        }))
+        .unwrap();
+        let dev = spec.build();
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "pcie-root-port,id=rp0,bus=pcie.0,chassis=1,slot=2,multifunction=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bridges() {
+        let mut bridge = BridgeDevice {
+            id: "br0".to_owned(),
+            bridge_type: BridgeType::Pci,
+            bus: "pci.0".to_owned(),
+            address: "0x1f".to_owned(),
+            chassis_nr: 0,
+            shpc: true,
+        };
+        assert!(!bridge.valid());
+
+        bridge.chassis_nr = 1;
+        assert!(bridge.valid());
+        let mut config = QemuConfig::default();
+        bridge.set_qemu_params(&mut config);
+
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "bridge",
+            "id": "br1",
+            "bridge_type": "pcie_pci",
+            "bus": "rp0",
+        }))
+        .unwrap();
+        let bridge = spec.build();
+        assert!(bridge.valid());
+        bridge.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "pci-bridge,id=br0,bus=pci.0,chassis_nr=1,addr=0x1f,shpc=on",
+                "-device",
+                "pcie-pci-bridge,id=br1,bus=rp0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vhost_user_fs_and_net() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "vhost_user",
+            "id": "fs0",
+            "device_type": "vhost-user-fs",
+            "socket_path": "/run/virtiofsd.sock",
+            "char_id": "char-fs0",
+        }))
+        .unwrap();
+        // virtio-fs needs a mount tag
+        assert!(!spec.build().valid());
+
+        let fs = VhostUserDevice {
+            id: "fs0".to_owned(),
+            device_type: VHOSTUSERFS.to_owned(),
+            socket_path: "/run/virtiofsd.sock".to_owned(),
+            char_id: "char-fs0".to_owned(),
+            tag: "rootfs".to_owned(),
+            transport: String::new(),
+            virtio: VirtioOptions::default(),
+        };
+        let net = VhostUserDevice {
+            id: "net0".to_owned(),
+            device_type: VHOSTUSERNET.to_owned(),
+            socket_path: "/run/vhost-net.sock".to_owned(),
+            char_id: "char-net0".to_owned(),
+            tag: String::new(),
+            transport: String::new(),
+            virtio: VirtioOptions::default(),
+        };
+        assert!(fs.valid() && net.valid());
+
+        let mut config = QemuConfig::default();
+        fs.set_qemu_params(&mut config);
+        net.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-chardev",
+                "socket,id=char-fs0,path=/run/virtiofsd.sock",
+                "-device",
+                "vhost-user-fs-pci,id=fs0,chardev=char-fs0,tag=rootfs",
+                "-chardev",
+                "socket,id=char-net0,path=/run/vhost-net.sock",
+                "-netdev",
+                "type=vhost-user,id=net0,chardev=char-net0",
+                "-device",
+                "virtio-net-pci,netdev=net0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loader_raw_blob() {
+        let spec: DeviceSpec = serde_json::from_value(serde_json::json!({
+            "kind": "loader",
+            "file": "/var/lib/vm/boot.bin",
+            "addr": 0x40000000u64,
+            "cpu_num": 0,
+            "force_raw": true,
+        }))
+        .unwrap();
+        let dev = spec.build();
+        assert!(dev.valid());
+
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "loader,file=/var/lib/vm/boot.bin,addr=0x40000000,cpu-num=0,force-raw=on"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fw_config_device() {
+        let mut dev = FwConfig {
+            fw_cfg: FwCfg {
+                name: "opt/com.example/config".to_owned(),
+                ..Default::default()
+            },
+        };
+        // neither a file nor a string
+        assert!(!dev.valid());
+
+        dev.fw_cfg.str = "debug=1".to_owned();
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-fw_cfg", "name=opt/com.example/config,string=debug=1"]
+        );
+    }
+
+    #[test]
+    fn test_pvpanic_ioport() {
+        let mut config = QemuConfig::default();
+        PVPanicDevice { ioport: None }.set_qemu_params(&mut config);
+        PVPanicDevice {
+            ioport: Some(0x506),
+        }
+        .set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec!["-device", "pvpanic", "-device", "pvpanic,ioport=0x506"]
+        );
+    }
+
+    #[test]
+    fn test_floppy_on_isa_fdc() {
+        let controller = FloppyController {
+            id: "fdc0".to_owned(),
+        };
+        let mut floppy = FloppyDevice {
+            id: "f0".to_owned(),
+            file: "/images/dos.img".to_owned(),
+            unit: 2,
+            bus: "fdc0.0".to_owned(),
+        };
+        assert!(!floppy.valid());
+
+        floppy.unit = 0;
+        assert!(controller.valid() && floppy.valid());
+        let mut config = QemuConfig::default();
+        controller.set_qemu_params(&mut config);
+        floppy.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "isa-fdc,id=fdc0",
+                "-drive",
+                "if=none,id=f0,format=raw,file=/images/dos.img",
+                "-device",
+                "floppy,drive=f0,unit=0,bus=fdc0.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audio_card() {
+        let mut card = AudioCard {
+            id: "hda0".to_owned(),
+            codec: String::new(),
+            audiodev: String::new(),
+        };
+        assert!(!card.valid());
+
+        card.audiodev = "snd0".to_owned();
+        assert!(card.valid());
+        let mut config = QemuConfig::default();
+        card.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-device",
+                "intel-hda,id=hda0",
+                "-device",
+                "hda-duplex,bus=hda0.0,audiodev=snd0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_and_sev_objects() {
+        let mut mem = object(MEMORYBACKENDFILE, "mem0");
+        mem.size = 1 << 30;
+        mem.prealloc = true;
+        assert!(!mem.valid());
+        mem.mem_path = "/dev/hugepages".to_owned();
+        assert!(mem.valid());
+
+        let mut sev = object(SEVGUEST, "sev0");
+        assert!(!sev.valid());
+        sev.c_bit_pos = 47;
+        sev.reduced_physical_bits = 1;
+        assert!(sev.valid());
+        assert_eq!(sev.host_features(), vec![HostFeature::Sev]);
+
+        let devices: Vec<Box<dyn Device>> = vec![Box::new(mem), Box::new(sev)];
+        let config = QemuConfig::default().add_devices(&devices);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-object",
+                "memory-backend-file,id=mem0,size=1073741824,mem-path=/dev/hugepages,prealloc=on",
+                "-object",
+                "sev-guest,id=sev0,cbitpos=47,reduced-phys-bits=1",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fs_device_9p() {
+        let mut dev = FSDevice {
+            driver: String::new(),
+            fs_driver: LOCAL.to_owned(),
+            id: "fs0".to_owned(),
+            path: "/srv/share".to_owned(),
+            mount_tag: String::new(),
+            security_model: MAPPEDXATTR.to_owned(),
+            virtio: VirtioOptions {
+                disable_modern: Some(true),
+                ..Default::default()
+            },
+            rom_file: String::new(),
+            transport: String::new(),
+            multidev: REMAP.to_owned(),
+        };
+        assert!(!dev.valid());
+
+        dev.mount_tag = "share".to_owned();
+        assert!(dev.valid());
+        let mut config = QemuConfig::default();
+        dev.set_qemu_params(&mut config);
+        assert_eq!(
+            config.qemu_params,
+            vec![
+                "-fsdev",
+                "local,id=fs0,path=/srv/share,security_model=mapped-xattr,multidevs=remap",
+                "-device",
+                "virtio-9p-pci,fsdev=fs0,mount_tag=share,disable-modern=on",
+            ]
+        );
     }
 }
@@ -3,8 +3,14 @@ pub type ObjectType = String;
 
 // MemoryBackendFile represents a guest memory mapped file.
 pub const MEMORYBACKENDFILE: ObjectTypeRef = "memory-backend-file";
+// MemoryBackendRAM represents a guest memory backed by anonymous host memory.
+pub const MEMORYBACKENDRAM: ObjectTypeRef = "memory-backend-ram";
 // MemoryBackendEPC represents a guest memory backend EPC for SGX.
 pub const MEMORYBACKENDEPC: ObjectTypeRef = "memory-backend-epc";
+// InputLinux represents a host evdev input device forwarded to the guest.
+pub const INPUTLINUX: ObjectTypeRef = "input-linux";
+// RngRandom represents a random number generator backed by a host device.
+pub const RNGRANDOM: ObjectTypeRef = "rng-random";
 // TDXGuest represents a TDX object
 pub const TDXGUEST: ObjectTypeRef = "tdx-guest";
 // SEVGuest represents an SEV guest object
@@ -21,6 +27,8 @@ pub type DeviceDriver = String;
 
 // LegacySerial is the legacy serial device driver
 pub const LEGACYSERIAL: DeviceDriverRef = "serial";
+// PCDIMM is the hotpluggable DIMM device driver.
+pub const PCDIMM: DeviceDriverRef = "pc-dimm";
 // NVDIMM is the Non Volatile DIMM device driver.
 pub const NVDIMM: DeviceDriverRef = "nvdimm";
 // VirtioNet is the virtio networking device driver.
@@ -41,6 +49,20 @@ pub const VIRTIOSERIAL: DeviceDriverRef = "virtio-serial";
 pub const VIRTIOSERIALPORT: DeviceDriverRef = "virtserialport";
 // VirtioRng is the paravirtualized RNG device driver.
 pub const VIRTIORNG: DeviceDriverRef = "virtio-rng";
+// VirtioInputHost passes a host evdev input device through to the guest.
+pub const VIRTIOINPUTHOST: DeviceDriverRef = "virtio-input-host";
+// VirtioPmemPCI is the virtio persistent memory device driver.
+pub const VIRTIOPMEMPCI: DeviceDriverRef = "virtio-pmem-pci";
+// VirtioSCSI is the virtio scsi controller device driver.
+pub const VIRTIOSCSI: DeviceDriverRef = "virtio-scsi";
+// Megasas is the emulated LSI MegaRAID SAS scsi controller.
+pub const MEGASAS: DeviceDriverRef = "megasas";
+// LSI53C895A is the emulated LSI 53C895A scsi controller.
+pub const LSI53C895A: DeviceDriverRef = "lsi53c895a";
+// PVSCSI is the VMware paravirtual scsi controller.
+pub const PVSCSI: DeviceDriverRef = "pvscsi";
+// SCSIHD is the scsi disk device driver.
+pub const SCSIHD: DeviceDriverRef = "scsi-hd";
 // VirtioBalloon is the memory balloon device driver.
 pub const VIRTIOBALLOON: DeviceDriverRef = "virtio-balloon";
 //VhostUserSCSI represents a SCSI vhostuser device type.
@@ -63,12 +85,40 @@ pub const VFIOCCW: DeviceDriverRef = "vfio-ccw";
 pub const VFIOAP: DeviceDriverRef = "vfio-ap";
 // VHostVSockPCI is a generic Vsock vhost device with PCI transport.
 pub const VHOSTVSOCKPCI: DeviceDriverRef = "vhost-vsock-pci";
+// IntelIOMMU is the emulated Intel VT-d iommu, q35 only.
+pub const INTELIOMMU: DeviceDriverRef = "intel-iommu";
+// VirtioIOMMUPCI is the paravirtualized iommu with PCI transport.
+pub const VIRTIOIOMMUPCI: DeviceDriverRef = "virtio-iommu-pci";
+// QemuXHCI is the usb 3.0 xhci host controller.
+pub const QEMUXHCI: DeviceDriverRef = "qemu-xhci";
+// UsbEHCI is the usb 2.0 ehci host controller.
+pub const USBEHCI: DeviceDriverRef = "usb-ehci";
+// UsbHost passes a host usb device through to the guest.
+pub const USBHOST: DeviceDriverRef = "usb-host";
 // PCIeRootPort is a PCIe Root Port, the PCIe device should be hotplugged to this port.
 pub const PCIEROOTPORT: DeviceDriverRef = "pcie-root-port";
 // Loader is the Loader device driver.
 pub const LOADER: DeviceDriverRef = "loader";
 // SpaprTPMProxy is used for enabling guest to run in secure mode on ppc64le.
 pub const SPAPRTPMPROXY: DeviceDriverRef = "spapr-tpm-proxy";
+// IsaDebugExit lets the guest terminate qemu with an exit code through an io port.
+pub const ISADEBUGEXIT: DeviceDriverRef = "isa-debug-exit";
+// IsaDebugCon is the debug console on an io port, e.g. for early boot output.
+pub const ISADEBUGCON: DeviceDriverRef = "isa-debugcon";
+// PVPanic is the isa device the guest reports its kernel panics through.
+pub const PVPANIC: DeviceDriverRef = "pvpanic";
+// IntelHDA is the intel high definition audio controller.
+pub const INTELHDA: DeviceDriverRef = "intel-hda";
+// HDADuplex is the hda codec with a line-out and a line-in.
+pub const HDADUPLEX: DeviceDriverRef = "hda-duplex";
+// IsaFdc is the isa floppy disk controller.
+pub const ISAFDC: DeviceDriverRef = "isa-fdc";
+// Floppy is the floppy drive device driver.
+pub const FLOPPY: DeviceDriverRef = "floppy";
+// Edu is the educational pci device, for driver development.
+pub const EDU: DeviceDriverRef = "edu";
+// PCITestDev is the pci test device, for driver development.
+pub const PCITESTDEV: DeviceDriverRef = "pci-testdev";
 
 pub type FsDriverRef<'a> = &'a str;
 pub type FsDriver = String;
@@ -109,4 +159,26 @@ pub const REMAP: Virtio9PMultiDevRef = "remap";
 // This is the default behaviour.
 pub const WARN: Virtio9PMultiDevRef = "warn";
 // Forbid like "warn" but also deny access to additional devices on guest.
-pub const FORBID: Virtio9PMultiDevRef = "forbid";
\ No newline at end of file
+pub const FORBID: Virtio9PMultiDevRef = "forbid";
+
+pub type CharDeviceBackendRef<'a> = &'a str;
+pub type CharDeviceBackend = String;
+
+// Socket is the unix socket character device backend.
+pub const CHARDEVSOCKET: CharDeviceBackendRef = "socket";
+// File is the character device backend writing to a host file.
+pub const CHARDEVFILE: CharDeviceBackendRef = "file";
+// Pty is the character device backend allocating a host pseudo terminal.
+pub const CHARDEVPTY: CharDeviceBackendRef = "pty";
+// Stdio is the character device backend connected to qemu's stdio.
+pub const CHARDEVSTDIO: CharDeviceBackendRef = "stdio";
+
+pub type NetdevTypeRef<'a> = &'a str;
+pub type NetdevType = String;
+
+// Tap is the netdev backend plugged to a host tap interface.
+pub const NETDEVTAP: NetdevTypeRef = "tap";
+// User is the netdev backend of qemu's user mode network stack.
+pub const NETDEVUSER: NetdevTypeRef = "user";
+// Bridge is the netdev backend plugging a tap to a host bridge through qemu-bridge-helper.
+pub const NETDEVBRIDGE: NetdevTypeRef = "bridge";
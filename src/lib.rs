@@ -2,14 +2,14 @@
 
 pub mod device_consts;
 pub mod config;
-mod device;
+pub mod device;
 pub mod qemu;
+pub mod qmp;
 mod types;
+pub mod vm;
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
     fn it_works() {}
 }
@@ -1,8 +1,15 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use uuid::Uuid;
 
 use crate::config::QemuConfig;
+use crate::qmp::{QmpClient, ShutdownReason};
+use crate::types::{QemuVersion, QmpRole, QmpSocket};
 
-use std::process::Command;
+use std::io;
+use std::os::unix::prelude::{CommandExt, RawFd};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
 
 /// the delimiter between parameters
 const QEMU_PARAM_DELIMITER: &str = " ";
@@ -12,31 +19,428 @@ pub struct Qemu {
     bin_path: String,
 
     args: Vec<String>,
+
+    /// -pidfile qemu writes its pid to
+    pid_file: String,
+
+    /// whether qemu forks into the background with -daemonize
+    daemonize: bool,
+
+    /// the spawned qemu process
+    child: Option<Child>,
+
+    /// the tcp port of the vnc server
+    vnc_port: Option<u16>,
+
+    /// the qmp sockets qemu serves
+    qmp_sockets: Vec<QmpSocket>,
+
+    /// host fds handed to qemu as fd 3 onwards, see `QemuConfig::append_fds`
+    fds: Vec<RawFd>,
+
+    /// user qemu runs as, the caller's one if 0
+    uid: u32,
+
+    /// group qemu runs as, the caller's one if 0
+    gid: u32,
+
+    /// supplementary groups qemu runs with
+    groups: Vec<u32>,
+
+    /// path and content of the -readconfig file written at launch, removed once
+    /// qemu is reaped, or when dropped unless qemu is left running
+    qemu_cfg: Option<(String, String)>,
+
+    /// ids of the devices hotplugged with `device_add()`, the only ones
+    /// `device_del()` unplugs, until qemu reports them deleted
+    hotplugged: Vec<String>,
+
+    /// ids of the hotplugged devices whose unplug was requested,
+    /// which the guest has not released yet
+    unplugging: Vec<String>,
 }
 
 impl Qemu {
     /// new qemu instance
     pub fn new(bin_path: String, args: Vec<String>) -> Self {
-        Self { bin_path, args }
+        Self {
+            bin_path,
+            args,
+            pid_file: String::new(),
+            daemonize: false,
+            child: None,
+            vnc_port: None,
+            qmp_sockets: vec![],
+            fds: vec![],
+            uid: 0,
+            gid: 0,
+            groups: vec![],
+            qemu_cfg: None,
+            hotplugged: vec![],
+            unplugging: vec![],
+        }
     }
 
-    pub fn from_config(config: QemuConfig) -> Self {
-        let config = config.build_all();
+    /// build the qemu command line of config, after picking the dynamic ports
+    pub fn from_config(mut config: QemuConfig) -> Result<Self> {
+        let vnc_port = config
+            .pick_vnc_port()
+            .context("failed to pick a vnc port")?;
+        config
+            .pick_qmp_ports()
+            .context("failed to pick a qmp port")?;
+        let config = config.try_build_all()?;
 
-        Self {
+        let (args, qemu_cfg) = if config.knobs.write_config {
+            let path = std::env::temp_dir().join(format!("qemu-launch-{}.cfg", Uuid::new_v4()));
+            let path = path.to_string_lossy().into_owned();
+            let (cfg, args) = config.qemu_cfg_params(&path);
+            (args, Some((path, cfg)))
+        } else {
+            (config.qemu_params.clone(), None)
+        };
+
+        Ok(Self {
             bin_path: config.bin_path,
-            args: config.qemu_params,
-        }
+            args,
+            pid_file: config.pid_file,
+            daemonize: config.knobs.demonized,
+            child: None,
+            vnc_port,
+            qmp_sockets: config
+                .qmp_sockets
+                .iter()
+                .filter(|socket| socket.valid())
+                .cloned()
+                .collect(),
+            fds: config.fds,
+            uid: config.uid,
+            gid: config.gid,
+            groups: config.groups,
+            qemu_cfg,
+            hotplugged: vec![],
+            unplugging: vec![],
+        })
     }
 
     /// launch qemu process with expected parameters
-    pub fn launch(&self) -> Result<()> {
-        Command::new(&self.bin_path)
-            .args(&self.args)
+    ///
+    /// The child is kept to be tracked by `pid()`, `try_wait()` and `kill()`,
+    /// a handle to it is returned to wait on or signal it directly.
+    pub fn launch(&mut self) -> Result<&mut Child> {
+        self.write_qemu_cfg()?;
+        let child = self.command().spawn().context("failed to spawn qemu")?;
+        Ok(self.child.insert(child))
+    }
+
+    /// run qemu to completion, without tracking it
+    pub fn launch_and_wait(&self) -> Result<ExitStatus> {
+        self.write_qemu_cfg()?;
+        let status = self
+            .command()
             .spawn()
-            .expect("Failed to spawn QEMU process");
+            .context("failed to spawn qemu")
+            .and_then(|mut child| Ok(child.wait()?));
+        self.remove_qemu_cfg();
+        status
+    }
+
+    /// write the -readconfig file, which qemu reads as it starts
+    fn write_qemu_cfg(&self) -> Result<()> {
+        if let Some((path, cfg)) = &self.qemu_cfg {
+            std::fs::write(path, cfg).with_context(|| format!("failed to write {}", path))?;
+        }
+        Ok(())
+    }
+
+    /// remove the -readconfig file once qemu is done with it
+    fn remove_qemu_cfg(&self) {
+        if let Some((path, _)) = &self.qemu_cfg {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    log::warn!("failed to remove {}: {}", path, e)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.bin_path);
+        command.args(&self.args);
+        if !self.fds.is_empty() {
+            let fds = self.fds.clone();
+            // allocated here, the child must not allocate between fork and exec
+            let mut moved = vec![0; fds.len()];
+            // SAFETY: only async-signal-safe calls between fork and exec
+            unsafe { command.pre_exec(move || inherit_fds(&fds, &mut moved)) };
+        }
+
+        if self.groups.is_empty() {
+            // std drops the supplementary groups of root along with the uid
+            if self.gid != 0 {
+                command.gid(self.gid);
+            }
+            if self.uid != 0 {
+                command.uid(self.uid);
+            }
+        } else {
+            // std switches the uid before running pre_exec, after which
+            // setgroups is not permitted anymore, so all of it is done here
+            let (uid, gid, groups) = (self.uid, self.gid, self.groups.clone());
+            // SAFETY: only async-signal-safe calls between fork and exec
+            unsafe { command.pre_exec(move || drop_privileges(uid, gid, &groups)) };
+        }
+        command
+    }
+
+    /// tcp port of the vnc server, the picked one with vnc's dynamic_port
+    pub fn vnc_port(&self) -> Option<u16> {
+        self.vnc_port
+    }
+
+    /// tcp port of the control qmp socket, the picked one with dynamic_port
+    pub fn qmp_port(&self) -> Option<u16> {
+        self.qmp_socket(QmpRole::Control)
+            .ok()
+            .filter(|socket| socket.socket_type == QmpSocket::TCP_SOCKET)
+            .map(|socket| socket.port)
+    }
+
+    /// connect to the qmp socket configured for role
+    pub fn qmp_client_for_role(&self, role: QmpRole) -> Result<QmpClient> {
+        QmpClient::connect(self.qmp_socket_path(role)?)
+    }
+
+    /// path of the unix qmp socket configured for role
+    fn qmp_socket_path(&self, role: QmpRole) -> Result<&str> {
+        let socket = self.qmp_socket(role)?;
+        if socket.socket_type != QmpSocket::UNIX_SOCKET {
+            return Err(anyhow!(
+                "qmp client only connects to unix sockets, not {}",
+                socket.address()
+            ));
+        }
+        Ok(&socket.name)
+    }
+
+    /// the qmp socket configured for role
+    pub(crate) fn qmp_socket(&self, role: QmpRole) -> Result<&QmpSocket> {
+        self.qmp_sockets
+            .iter()
+            .find(|socket| socket.role == role)
+            .ok_or_else(|| anyhow!("no qmp socket for role {:?}", role))
+    }
+
+    /// hotplug a device through qmp and keep track of its id,
+    /// args are its -device suboptions and must hold an id
+    pub fn device_add(&mut self, qmp: &mut QmpClient, args: Value) -> Result<()> {
+        let id = args["id"]
+            .as_str()
+            .ok_or_else(|| anyhow!("hotplugged devices need an id: {}", args))?
+            .to_owned();
+        if self.hotplugged.contains(&id) {
+            return Err(anyhow!("device {} is already hotplugged", id));
+        }
+        qmp.device_add(args)?;
+        self.hotplugged.push(id);
+        Ok(())
+    }
+
+    /// request the unplug of a device hotplugged by `device_add()`, the devices
+    /// of the config are refused as they may not be hot-unpluggable
+    ///
+    /// The guest releases the device asynchronously, its id is kept until
+    /// `wait_for_device_deleted()` sees qemu report the device deleted.
+    pub fn device_del(&mut self, qmp: &mut QmpClient, id: &str) -> Result<()> {
+        if !self.hotplugged.iter().any(|hotplugged| hotplugged == id) {
+            return Err(anyhow!("device {} was not hotplugged", id));
+        }
+        if self.unplugging.iter().any(|unplugging| unplugging == id) {
+            return Err(anyhow!("device {} is already being unplugged", id));
+        }
+        qmp.device_del(id)?;
+        self.unplugging.push(id.to_owned());
+        Ok(())
+    }
+
+    /// wait at most timeout for qemu to report the device id unplugged by
+    /// `device_del()` deleted, its id can be hotplugged again afterwards
+    pub fn wait_for_device_deleted(
+        &mut self,
+        qmp: &mut QmpClient,
+        id: &str,
+        timeout: Duration,
+    ) -> Result<()> {
+        let pos = self
+            .unplugging
+            .iter()
+            .position(|unplugging| unplugging == id)
+            .ok_or_else(|| anyhow!("device {} is not being unplugged", id))?;
+        qmp.wait_for_event("DEVICE_DELETED", timeout, |data| data["device"] == id)?;
+        self.unplugging.remove(pos);
+        self.hotplugged.retain(|hotplugged| hotplugged != id);
+        Ok(())
+    }
+
+    /// ids of the devices hotplugged by `device_add()` and not deleted since,
+    /// the ones being unplugged included
+    pub fn hotplugged_devices(&self) -> &[String] {
+        &self.hotplugged
+    }
+
+    /// ids of the devices unplugged by `device_del()` the guest has not released yet
+    pub fn unplugging_devices(&self) -> &[String] {
+        &self.unplugging
+    }
+
+    /// wait on the control qmp socket for qemu to shut down, and tell why
+    ///
+    /// A new session is opened, which qemu only serves once no other client
+    /// holds the socket, a `Vm` waits on its own session with `Vm::wait_for_shutdown()`.
+    pub fn wait_for_shutdown(&self, timeout: Duration) -> Result<Option<ShutdownReason>> {
+        let deadline = Instant::now() + timeout;
+        let mut qmp = QmpClient::connect_timeout(self.qmp_socket_path(QmpRole::Control)?, timeout)?;
+        qmp.wait_for_shutdown(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// pid of the qemu process, None if it is not launched
+    ///
+    /// With -daemonize the spawned process forks and exits, so the pid
+    /// written to the pidfile is preferred over the one of the child.
+    pub fn pid(&self) -> Option<u32> {
+        let child_pid = self.child.as_ref().map(|child| child.id());
+        let file_pid = self.read_pid_file();
+
+        if let (Some(child_pid), Some(file_pid)) = (child_pid, file_pid) {
+            // with -daemonize the child is the parent qemu, which forks and exits
+            if child_pid != file_pid && !self.daemonize {
+                log::warn!(
+                    "qemu pid {} differs from the pid {} in {}",
+                    child_pid,
+                    file_pid,
+                    self.pid_file
+                );
+            }
+        }
+
+        if self.daemonize {
+            file_pid.or(child_pid)
+        } else {
+            child_pid.or(file_pid)
+        }
+    }
+
+    /// poll the qemu process without blocking, reaping it if it has exited
+    ///
+    /// Returns None while qemu is still running.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        let child = self
+            .child
+            .as_mut()
+            .ok_or_else(|| anyhow!("qemu is not launched"))?;
+        let status = child.try_wait()?;
+        if status.is_some() {
+            self.remove_qemu_cfg();
+        }
+        Ok(status)
+    }
+
+    /// kill the qemu process and reap it
+    pub fn kill(&mut self) -> Result<()> {
+        let child = self
+            .child
+            .as_mut()
+            .ok_or_else(|| anyhow!("qemu is not launched"))?;
+        child.kill()?;
+        child.wait()?;
+        self.remove_qemu_cfg();
         Ok(())
     }
+
+    /// version of the qemu binary, from the output of -version
+    pub fn version(&self) -> Result<QemuVersion> {
+        let output = Command::new(&self.bin_path).arg("-version").output()?;
+        parse_version(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn read_pid_file(&self) -> Option<u32> {
+        if self.pid_file.is_empty() {
+            return None;
+        }
+        std::fs::read_to_string(&self.pid_file)
+            .ok()
+            .and_then(|pid| pid.trim().parse().ok())
+    }
+}
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        // a running qemu may not have read its -readconfig file yet
+        let running = self
+            .child
+            .as_mut()
+            .is_some_and(|child| matches!(child.try_wait(), Ok(None)));
+        if !running {
+            self.remove_qemu_cfg();
+        }
+    }
+}
+
+/// dup fds into the slots 3, 4, ... qemu is told about, in the forked child
+///
+/// The fds are first moved above the target slots, so that a fd already sitting
+/// in the slot of another one is not overwritten before being dup'ed.
+fn inherit_fds(fds: &[RawFd], moved: &mut [RawFd]) -> io::Result<()> {
+    const FIRST_FD: RawFd = 3;
+
+    let above = FIRST_FD + fds.len() as RawFd;
+    for (fd, copy) in fds.iter().zip(moved.iter_mut()) {
+        // the copies are close-on-exec, only the slots are inherited
+        *copy = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above) };
+        if *copy < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    for (slot, fd) in (FIRST_FD..).zip(moved.iter()) {
+        if unsafe { libc::dup2(*fd, slot) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// switch to the supplementary groups, gid and uid, in the forked child,
+/// a 0 gid or uid is left unchanged
+fn drop_privileges(uid: u32, gid: u32, groups: &[u32]) -> io::Result<()> {
+    if unsafe { libc::setgroups(groups.len(), groups.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if gid != 0 && unsafe { libc::setgid(gid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if uid != 0 && unsafe { libc::setuid(uid) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// parse the version out of qemu's -version output, e.g.
+/// "QEMU emulator version 8.2.0 (Debian 1:8.2.0+ds-1)"
+fn parse_version(output: &str) -> Result<QemuVersion> {
+    let version = output
+        .split("version ")
+        .nth(1)
+        .and_then(|rest| rest.split_whitespace().next())
+        .ok_or_else(|| anyhow!("no version in qemu output: {}", output))?;
+
+    let mut numbers = version.split('.').map(|n| n.parse::<u32>());
+    match (numbers.next(), numbers.next(), numbers.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), micro) => {
+            Ok((major, minor, micro.and_then(|m| m.ok()).unwrap_or(0)))
+        }
+        _ => Err(anyhow!("invalid qemu version {}", version)),
+    }
 }
 
 // utils
@@ -45,3 +449,323 @@ impl Qemu {
         println!("Binary path: {}\nargs: {:?}", self.bin_path, self.args);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::tests::{event, executed, serve_session, socket_path};
+    use std::io::Write;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_pid_of_launched_child() {
+        let mut qemu = Qemu::new("/bin/true".to_owned(), vec![]);
+        assert_eq!(qemu.pid(), None);
+
+        let child_pid = qemu.launch().unwrap().id();
+        assert_eq!(qemu.pid(), Some(child_pid));
+
+        qemu.child.as_mut().unwrap().wait().unwrap();
+    }
+
+    #[test]
+    fn test_launch_and_wait() {
+        let status = Qemu::new("/bin/false".to_owned(), vec![])
+            .launch_and_wait()
+            .unwrap();
+        assert!(!status.success());
+
+        let missing = Qemu::new("/nonexistent/qemu-system-x86_64".to_owned(), vec![]);
+        assert!(missing.launch_and_wait().is_err());
+    }
+
+    #[test]
+    fn test_write_config_at_launch() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // a qemu that copies its -readconfig file next to itself
+        let bin = std::env::temp_dir().join(format!("readconfig-qemu-{}", std::process::id()));
+        std::fs::write(
+            &bin,
+            "#!/bin/sh\nwhile [ \"$1\" != -readconfig ]; do shift; done\ncp \"$2\" \"$0.cfg\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "bin_path": bin,
+            "knobs": {"write_config": true},
+            "raw_args": ["-device", "virtio-rng-pci"],
+        }))
+        .unwrap();
+        let qemu = Qemu::from_config(config).unwrap();
+        assert!(!qemu.args.contains(&"virtio-rng-pci".to_owned()));
+        let (path, _) = qemu.qemu_cfg.clone().unwrap();
+        assert!(qemu.args.contains(&path));
+
+        assert!(qemu.launch_and_wait().unwrap().success());
+        let copy = format!("{}.cfg", bin.display());
+        let cfg = std::fs::read_to_string(&copy).unwrap();
+        assert_eq!(cfg, "[device]\n  driver = \"virtio-rng-pci\"\n");
+        // removed once qemu exited
+        assert!(!std::path::Path::new(&path).exists());
+
+        let _ = std::fs::remove_file(&copy);
+        let _ = std::fs::remove_file(&bin);
+    }
+
+    #[test]
+    fn test_fds_are_inherited() {
+        use std::io::Read;
+        use std::os::unix::io::AsRawFd;
+
+        let (mut reader, writer) = std::io::pipe().unwrap();
+        let mut qemu = Qemu::new(
+            "/bin/sh".to_owned(),
+            vec!["-c".to_owned(), "echo inherited >&3".to_owned()],
+        );
+        qemu.fds = vec![writer.as_raw_fd()];
+        qemu.launch().unwrap();
+        drop(writer);
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "inherited\n");
+        assert!(qemu.child.as_mut().unwrap().wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_launch_as_unprivileged_user() {
+        // switching users needs root
+        if unsafe { libc::geteuid() } != 0 {
+            return;
+        }
+
+        let check = |uid: u32, gid: u32, groups: Vec<u32>, expected: &str| {
+            let mut qemu = Qemu::new(
+                "/bin/sh".to_owned(),
+                vec![
+                    "-c".to_owned(),
+                    format!(r#"test "$(id -u):$(id -G)" = "{}""#, expected),
+                ],
+            );
+            qemu.uid = uid;
+            qemu.gid = gid;
+            qemu.groups = groups;
+            qemu.launch_and_wait().unwrap().success()
+        };
+        assert!(check(65534, 65534, vec![], "65534:65534"));
+        assert!(check(65534, 65534, vec![100], "65534:65534 100"));
+    }
+
+    #[test]
+    fn test_try_wait_reaps_exited_child() {
+        let mut qemu = Qemu::new("/bin/true".to_owned(), vec![]);
+        assert!(qemu.try_wait().is_err());
+
+        qemu.launch().unwrap();
+        let status = loop {
+            if let Some(status) = qemu.try_wait().unwrap() {
+                break status;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        let output = "QEMU emulator version 8.2.0 (Debian 1:8.2.0+ds-1)\n\
+                      Copyright (c) 2003-2023 Fabrice Bellard and the QEMU Project developers\n";
+        assert_eq!(parse_version(output).unwrap(), (8, 2, 0));
+        assert_eq!(
+            parse_version("QEMU emulator version 6.1\n").unwrap(),
+            (6, 1, 0)
+        );
+        assert!(parse_version("qemu-system-x86_64: command not found").is_err());
+    }
+
+    #[test]
+    fn test_dynamic_vnc_ports_do_not_collide() {
+        let config: QemuConfig = serde_json::from_str(
+            r#"{ "vnc": { "display": "127.0.0.1:0", "dynamic_port": true } }"#,
+        )
+        .unwrap();
+
+        let first = Qemu::from_config(config.clone()).unwrap();
+        let first_port = first.vnc_port().unwrap();
+        assert!(first
+            .args
+            .contains(&format!("127.0.0.1:{}", first_port - 5900)));
+
+        // the first qemu holds its port, the second one must not pick it
+        let _held = std::net::TcpListener::bind(("127.0.0.1", first_port)).unwrap();
+        let second = Qemu::from_config(config).unwrap();
+        assert_ne!(second.vnc_port().unwrap(), first_port);
+    }
+
+    #[test]
+    fn test_from_config_errors() {
+        let config: QemuConfig = serde_json::from_str(
+            r#"{ "vnc": { "display": "unix:/run/vnc.sock", "dynamic_port": true } }"#,
+        )
+        .unwrap();
+        let err = Qemu::from_config(config).err().unwrap();
+        assert!(err.to_string().contains("failed to pick a vnc port"));
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [{ "socket_type": "tcp", "host": "", "dynamic_port": true }]
+        }))
+        .unwrap();
+        let err = Qemu::from_config(config).err().unwrap();
+        assert!(err.to_string().contains("failed to pick a qmp port"));
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "smp": { "cpus": 8, "max_cpus": 4 }
+        }))
+        .unwrap();
+        assert!(Qemu::from_config(config).is_err());
+    }
+
+    #[test]
+    fn test_tcp_qmp_sockets() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [
+                { "socket_type": "tcp", "host": "127.0.0.1", "port": 4444, "is_server": true, "no_wait": true },
+                { "socket_type": "tcp", "host": "127.0.0.1", "is_server": true, "role": "monitor" },
+                { "socket_type": "tcp", "host": "::1", "port": 4445, "is_server": true, "role": "monitor" },
+                { "socket_type": "tcp", "host": "[::1]", "port": 4446, "role": "monitor" },
+            ]
+        }))
+        .unwrap();
+        // a tcp socket without port is left out, ipv6 hosts are bracketed
+        let params = config.build_all().qemu_params;
+        assert_eq!(
+            params
+                .windows(2)
+                .filter(|w| w[0] == "-qmp")
+                .map(|w| w[1].as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "tcp:127.0.0.1:4444,server=on,wait=off",
+                "tcp:[::1]:4445,server=on",
+                "tcp:[::1]:4446",
+            ]
+        );
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [
+                { "socket_type": "tcp", "host": "127.0.0.1", "is_server": true, "dynamic_port": true }
+            ]
+        }))
+        .unwrap();
+        let qemu = Qemu::from_config(config).unwrap();
+        let port = qemu.qmp_port().unwrap();
+        assert_ne!(port, 0);
+        assert!(qemu
+            .args
+            .contains(&format!("tcp:127.0.0.1:{},server=on", port)));
+        assert!(qemu.qmp_client_for_role(QmpRole::Control).is_err());
+    }
+
+    #[test]
+    fn test_qmp_client_for_role() {
+        let monitor = socket_path("monitor");
+        let control = socket_path("control");
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [
+                { "socket_type": "unix", "name": control, "is_server": true, "role": "control" },
+                { "socket_type": "unix", "name": monitor, "is_server": true, "role": "monitor" },
+            ]
+        }))
+        .unwrap();
+        let qemu = Qemu::from_config(config).unwrap();
+
+        // only the monitor socket is served, connecting to control would fail
+        let listener = UnixListener::bind(&monitor).unwrap();
+        let server = std::thread::spawn(move || serve_session(&listener, &[]));
+
+        let client = qemu.qmp_client_for_role(QmpRole::Monitor).unwrap();
+        assert_eq!(client.path(), monitor.as_path());
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&monitor);
+
+        assert!(qemu.qmp_client_for_role(QmpRole::Control).is_err());
+    }
+
+    #[test]
+    fn test_hotplugged_devices() {
+        let path = socket_path("hotplug");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let ok = r#"{"return": {}}"#;
+            let (requests, mut writer) = serve_session(&listener, &[ok, ok]);
+            let deleted =
+                serde_json::json!({ "device": "disk1", "path": "/machine/peripheral/disk1" });
+            writeln!(writer, "{}", event("DEVICE_DELETED", deleted)).unwrap();
+            requests
+        });
+
+        let mut qemu = Qemu::new("/bin/true".to_owned(), vec![]);
+        let mut qmp = QmpClient::connect(&path).unwrap();
+        let disk = serde_json::json!({ "driver": "virtio-blk-pci", "id": "disk1" });
+        qemu.device_add(&mut qmp, disk.clone()).unwrap();
+        assert_eq!(qemu.hotplugged_devices(), ["disk1"]);
+
+        // refused before reaching qmp
+        assert!(qemu.device_add(&mut qmp, disk).is_err());
+        let no_id = serde_json::json!({ "driver": "virtio-rng-pci" });
+        assert!(qemu.device_add(&mut qmp, no_id).is_err());
+        assert!(qemu.device_del(&mut qmp, "net0").is_err());
+
+        qemu.device_del(&mut qmp, "disk1").unwrap();
+        // kept until the guest released it
+        assert_eq!(qemu.hotplugged_devices(), ["disk1"]);
+        assert_eq!(qemu.unplugging_devices(), ["disk1"]);
+        let disk = serde_json::json!({ "driver": "virtio-blk-pci", "id": "disk1" });
+        assert!(qemu.device_add(&mut qmp, disk).is_err());
+        assert!(qemu.device_del(&mut qmp, "disk1").is_err());
+
+        qemu.wait_for_device_deleted(&mut qmp, "disk1", Duration::from_secs(5))
+            .unwrap();
+        assert!(qemu.hotplugged_devices().is_empty());
+        assert!(qemu.unplugging_devices().is_empty());
+        assert_eq!(
+            executed(&server.join().unwrap()),
+            vec!["qmp_capabilities", "device_add", "device_del"]
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_wait_for_shutdown() {
+        let control = socket_path("qemu-shutdown");
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [{ "socket_type": "unix", "name": control, "is_server": true }]
+        }))
+        .unwrap();
+        let qemu = Qemu::from_config(config).unwrap();
+
+        let listener = UnixListener::bind(&control).unwrap();
+        let server = std::thread::spawn(move || {
+            let (_, mut writer) = serve_session(&listener, &[]);
+            let shutdown = serde_json::json!({ "guest": true, "reason": "guest-shutdown" });
+            writeln!(writer, "{}", event("SHUTDOWN", shutdown)).unwrap();
+        });
+
+        let reason = qemu.wait_for_shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(reason, Some(ShutdownReason::GuestShutdown));
+        server.join().unwrap();
+
+        // qemu does not greet a second client while another one holds the socket
+        let _ = std::fs::remove_file(&control);
+        let listener = UnixListener::bind(&control).unwrap();
+        let server = std::thread::spawn(move || listener.accept().unwrap());
+        let err = qemu
+            .wait_for_shutdown(Duration::from_millis(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("no qmp greeting"));
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&control);
+    }
+}
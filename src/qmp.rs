@@ -0,0 +1,634 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::types::QemuVersion;
+
+/// an asynchronous event emitted by qemu, e.g. SHUTDOWN or DEVICE_DELETED
+#[derive(Debug, Clone, Deserialize)]
+pub struct QmpEvent {
+    /// name of the event
+    pub event: String,
+
+    /// event specific payload, Value::Null if the event has none
+    #[serde(default)]
+    pub data: Value,
+
+    /// when qemu emitted the event
+    pub timestamp: QmpTimestamp,
+}
+
+/// time of an event since the epoch
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct QmpTimestamp {
+    pub seconds: i64,
+    pub microseconds: i64,
+}
+
+/// why qemu shut down, the reason of the SHUTDOWN event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShutdownReason {
+    /// the guest powered itself off
+    GuestShutdown,
+    /// the guest rebooted with -no-reboot
+    GuestReset,
+    /// the guest panicked with -action panic=shutdown
+    GuestPanic,
+    /// qemu received a signal, e.g. SIGTERM
+    HostSignal,
+    /// the quit qmp command
+    HostQmpQuit,
+    /// the system_reset qmp command with -no-reboot
+    HostQmpSystemReset,
+    /// the window of the display was closed
+    HostUi,
+    /// an error on the host side
+    HostError,
+    /// a reset requested by an emulated device
+    SubsystemReset,
+    /// a reason unknown to this version of the crate
+    #[serde(other)]
+    Other,
+}
+
+/// the greeting qemu sends once a client connects
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QmpGreeting {
+    #[serde(default)]
+    pub version: QmpVersion,
+
+    /// capabilities the client may enable, e.g. oob
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+/// version of the qemu serving the qmp socket
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QmpVersion {
+    #[serde(default)]
+    pub qemu: QmpQemuVersion,
+
+    /// the version of the distribution package, e.g. "Debian 1:8.2.0+ds-1"
+    #[serde(default)]
+    pub package: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct QmpQemuVersion {
+    #[serde(default)]
+    pub major: u32,
+    #[serde(default)]
+    pub minor: u32,
+    #[serde(default)]
+    pub micro: u32,
+}
+
+impl From<QmpQemuVersion> for QemuVersion {
+    fn from(version: QmpQemuVersion) -> Self {
+        (version.major, version.minor, version.micro)
+    }
+}
+
+/// the error response of a failed command
+#[derive(Debug, Clone, Deserialize)]
+pub struct QmpError {
+    /// error class, e.g. GenericError or CommandNotFound
+    pub class: String,
+
+    /// human readable description
+    pub desc: String,
+}
+
+impl fmt::Display for QmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.class, self.desc)
+    }
+}
+
+impl std::error::Error for QmpError {}
+
+/// client of the QEMU Machine Protocol over a unix socket
+pub struct QmpClient {
+    /// path of the qmp socket, kept to be able to reconnect
+    path: PathBuf,
+
+    reader: BufReader<UnixStream>,
+
+    writer: UnixStream,
+
+    /// events received while waiting for a command response
+    pending_events: VecDeque<QmpEvent>,
+
+    /// the greeting of the server
+    greeting: QmpGreeting,
+
+    /// the start of a message whose read timed out, completed by the next read
+    partial: Vec<u8>,
+}
+
+impl QmpClient {
+    /// connect to the qmp socket and negotiate the capabilities
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::connect_with_timeout(path.as_ref(), None)
+    }
+
+    /// same as `connect()`, but fails if qemu does not greet within timeout,
+    /// e.g. as it serves a single session at a time and another client holds it
+    pub fn connect_timeout<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self> {
+        if timeout.is_zero() {
+            return Err(anyhow!("no qmp greeting within {:?}", timeout));
+        }
+        Self::connect_with_timeout(path.as_ref(), Some(timeout))
+    }
+
+    fn connect_with_timeout(path: &Path, timeout: Option<Duration>) -> Result<Self> {
+        let path = path.to_path_buf();
+        let stream = UnixStream::connect(&path)
+            .with_context(|| format!("failed to connect to qmp socket {}", path.display()))?;
+        stream.set_read_timeout(timeout)?;
+        let writer = stream.try_clone()?;
+
+        let mut client = Self {
+            path,
+            reader: BufReader::new(stream),
+            writer,
+            pending_events: VecDeque::new(),
+            greeting: QmpGreeting::default(),
+            partial: vec![],
+        };
+
+        // the server greets first, then waits for the capabilities negotiation
+        let greeting = client.read_message().map_err(|e| {
+            if is_timed_out(&e) {
+                e.context(format!(
+                    "no qmp greeting within {:?}",
+                    timeout.unwrap_or_default()
+                ))
+            } else {
+                e
+            }
+        })?;
+        let greeting = greeting
+            .get("QMP")
+            .ok_or_else(|| anyhow!("unexpected qmp greeting: {}", greeting))?;
+        client.greeting = serde_json::from_value(greeting.clone())?;
+        client.execute("qmp_capabilities", Value::Null)?;
+        client.reader.get_ref().set_read_timeout(None)?;
+        Ok(client)
+    }
+
+    /// path of the qmp socket the client is connected to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// the greeting qemu sent on connection, with its version
+    pub fn greeting(&self) -> &QmpGreeting {
+        &self.greeting
+    }
+
+    /// execute a qmp command and return the content of its "return"
+    /// args of Value::Null means the command takes no arguments
+    pub fn execute(&mut self, cmd: &str, args: Value) -> Result<Value> {
+        let request = if args.is_null() {
+            json!({ "execute": cmd })
+        } else {
+            json!({ "execute": cmd, "arguments": args })
+        };
+        self.write_message(&request)?;
+
+        loop {
+            let mut response = self.read_message()?;
+            if let Some(ret) = response.get_mut("return") {
+                return Ok(ret.take());
+            }
+            if let Some(error) = response.get_mut("error") {
+                let error: QmpError = serde_json::from_value(error.take())?;
+                return Err(
+                    anyhow::Error::new(error).context(format!("qmp command {} failed", cmd))
+                );
+            }
+            if response.get("event").is_some() {
+                self.pending_events
+                    .push_back(serde_json::from_value(response)?);
+            }
+        }
+    }
+
+    /// the run state of the vm, e.g. running or paused
+    pub fn query_status(&mut self) -> Result<String> {
+        let status = self.execute("query-status", Value::Null)?;
+        status["status"]
+            .as_str()
+            .map(|status| status.to_owned())
+            .ok_or_else(|| anyhow!("no status in query-status response: {}", status))
+    }
+
+    /// press the power button of the vm, the guest decides to shut down
+    pub fn system_powerdown(&mut self) -> Result<()> {
+        self.execute("system_powerdown", Value::Null)?;
+        Ok(())
+    }
+
+    /// resume a paused vm, e.g. one started with -S
+    pub fn cont(&mut self) -> Result<()> {
+        self.execute("cont", Value::Null)?;
+        Ok(())
+    }
+
+    /// hotplug a device, args are its -device suboptions, e.g.
+    /// {"driver": "virtio-blk-pci", "id": "disk1", "drive": "drive1"}
+    pub fn device_add(&mut self, args: Value) -> Result<()> {
+        self.execute("device_add", args)?;
+        Ok(())
+    }
+
+    /// request the unplug of device id, the guest completes it asynchronously
+    /// and qemu emits DEVICE_DELETED once it is gone
+    pub fn device_del(&mut self, id: &str) -> Result<()> {
+        self.execute("device_del", json!({ "id": id }))?;
+        Ok(())
+    }
+
+    /// set a property of a qom object, e.g. a device under /machine/peripheral/<id>
+    pub fn qom_set(&mut self, path: &str, property: &str, value: Value) -> Result<()> {
+        self.execute(
+            "qom-set",
+            json!({ "path": path, "property": property, "value": value }),
+        )?;
+        Ok(())
+    }
+
+    /// start an outgoing migration to uri, e.g. tcp:host:port or rdma:host:port
+    pub fn migrate(&mut self, uri: &str) -> Result<()> {
+        check_migration_uri(uri)?;
+        self.execute("migrate", json!({ "uri": uri }))?;
+        Ok(())
+    }
+
+    /// block until qemu emits the SHUTDOWN event, at most for timeout,
+    /// the reason is None with qemu before 4.0, which does not tell it
+    pub fn wait_for_shutdown(&mut self, timeout: Duration) -> Result<Option<ShutdownReason>> {
+        let event = self.wait_for_event("SHUTDOWN", timeout, |_| true)?;
+        Ok(serde_json::from_value(event.data["reason"].clone())?)
+    }
+
+    /// block until qemu emits the event name with data matching, at most for timeout,
+    /// the other events received meanwhile are left to `events()`
+    pub fn wait_for_event(
+        &mut self,
+        name: &str,
+        timeout: Duration,
+        matches: impl Fn(&Value) -> bool,
+    ) -> Result<QmpEvent> {
+        let read_timeout = self.reader.get_ref().read_timeout()?;
+        let mut skipped = vec![];
+        let result = self.find_event(name, timeout, matches, &mut skipped);
+
+        // restored whatever the outcome, the skipped events go first
+        for event in skipped.into_iter().rev() {
+            self.pending_events.push_front(event);
+        }
+        self.reader.get_ref().set_read_timeout(read_timeout)?;
+        result
+    }
+
+    fn find_event(
+        &mut self,
+        name: &str,
+        timeout: Duration,
+        matches: impl Fn(&Value) -> bool,
+        skipped: &mut Vec<QmpEvent>,
+    ) -> Result<QmpEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow!("no {} event within {:?}", name, timeout));
+            }
+            self.reader.get_ref().set_read_timeout(Some(remaining))?;
+
+            let event = match self.next_event() {
+                Err(e) if is_timed_out(&e) => continue,
+                event => event?,
+            };
+            match event {
+                Some(event) if event.event == name && matches(&event.data) => return Ok(event),
+                Some(event) => skipped.push(event),
+                None => return Err(anyhow!("qmp socket closed before the {} event", name)),
+            }
+        }
+    }
+
+    /// the events emitted by qemu, including the ones received while executing
+    /// commands, blocks until the next event and ends once qemu closes the socket
+    pub fn events(&mut self) -> impl Iterator<Item = Result<QmpEvent>> + '_ {
+        std::iter::from_fn(move || self.next_event().transpose())
+    }
+
+    fn next_event(&mut self) -> Result<Option<QmpEvent>> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Ok(Some(event));
+        }
+
+        loop {
+            let message = match self.read_message() {
+                Err(e) if is_connection_dropped(&e) => return Ok(None),
+                message => message?,
+            };
+            if message.get("event").is_some() {
+                return Ok(Some(serde_json::from_value(message)?));
+            }
+            // a response to a command nobody is waiting for
+        }
+    }
+
+    fn write_message(&mut self, message: &Value) -> Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// read the next message, a read that times out keeps what it read in partial
+    fn read_message(&mut self) -> Result<Value> {
+        if self.reader.read_until(b'\n', &mut self.partial)? == 0 {
+            return Err(
+                io::Error::new(io::ErrorKind::UnexpectedEof, "qmp socket closed by qemu").into(),
+            );
+        }
+        let line = std::mem::take(&mut self.partial);
+        serde_json::from_slice(&line)
+            .with_context(|| format!("malformed qmp message: {}", String::from_utf8_lossy(&line)))
+    }
+}
+
+/// a qmp session that survives the socket being dropped,
+/// e.g. while qemu is paused or restarted
+///
+/// On a dropped connection the command is sent again on a new session,
+/// so a command that was executed before the drop may run twice.
+pub struct PersistentQmp {
+    client: QmpClient,
+
+    /// how many times a single command may reconnect
+    max_retries: u32,
+}
+
+impl PersistentQmp {
+    pub fn connect<P: AsRef<Path>>(path: P, max_retries: u32) -> Result<Self> {
+        Ok(Self {
+            client: QmpClient::connect(path)?,
+            max_retries,
+        })
+    }
+
+    /// same as `QmpClient::execute`, reconnecting and re-handshaking on a dropped socket
+    pub fn execute(&mut self, cmd: &str, args: Value) -> Result<Value> {
+        let mut retries = 0;
+        loop {
+            match self.client.execute(cmd, args.clone()) {
+                Err(e) if is_connection_dropped(&e) && retries < self.max_retries => {
+                    retries += 1;
+                    log::warn!(
+                        "qmp connection dropped, reconnecting ({}/{})",
+                        retries,
+                        self.max_retries
+                    );
+                    self.client = QmpClient::connect(self.client.path.clone())?;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// rdma migration needs an explicit host and port, rdma:host:port
+fn check_migration_uri(uri: &str) -> Result<()> {
+    if let Some(addr) = uri.strip_prefix("rdma:") {
+        let valid = addr
+            .rsplit_once(':')
+            .map(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p > 0))
+            .unwrap_or(false);
+        if !valid {
+            return Err(anyhow!("rdma migration uri {} requires host and port", uri));
+        }
+    }
+    Ok(())
+}
+
+fn is_timed_out(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+}
+
+fn is_connection_dropped(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::UnexpectedEof
+            )
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    /// the greeting of a qemu whose version does not matter
+    const GREETING: &str = r#"{"QMP": {"version": {}, "capabilities": []}}"#;
+
+    /// serve one qmp session: greet, answer qmp_capabilities, then each command
+    /// with the next of responses, which may hold events written before it
+    ///
+    /// Returns the requests received and the stream, to write events to
+    /// or to hang up by dropping it.
+    pub(crate) fn serve_session(
+        listener: &UnixListener,
+        responses: &[&str],
+    ) -> (Vec<Value>, UnixStream) {
+        serve_greeted_session(listener, GREETING, responses)
+    }
+
+    /// same as `serve_session()`, greeting with greeting
+    fn serve_greeted_session(
+        listener: &UnixListener,
+        greeting: &str,
+        responses: &[&str],
+    ) -> (Vec<Value>, UnixStream) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        writeln!(writer, "{}", greeting).unwrap();
+        let mut requests = vec![];
+        for response in std::iter::once(&r#"{"return": {}}"#).chain(responses) {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            requests.push(serde_json::from_str(&line).unwrap());
+            writeln!(writer, "{}", response).unwrap();
+        }
+        (requests, writer)
+    }
+
+    /// the line of the event name carrying data
+    pub(crate) fn event(name: &str, data: Value) -> String {
+        json!({
+            "event": name,
+            "data": data,
+            "timestamp": { "seconds": 1, "microseconds": 2 },
+        })
+        .to_string()
+    }
+
+    /// the commands of requests, qmp_capabilities first
+    pub(crate) fn executed(requests: &[Value]) -> Vec<&str> {
+        requests
+            .iter()
+            .map(|request| request["execute"].as_str().unwrap())
+            .collect()
+    }
+
+    /// a socket path of the test name, free to bind
+    pub(crate) fn socket_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("qmp-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_persistent_qmp_reconnects() {
+        let path = socket_path("persistent");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let running = r#"{"return": {"status": "running"}}"#;
+            serve_session(&listener, &[running]);
+            serve_session(&listener, &[running]);
+        });
+
+        let mut qmp = PersistentQmp::connect(&path, 1).unwrap();
+        let status = qmp.execute("query-status", Value::Null).unwrap();
+        assert_eq!(status["status"], "running");
+
+        // the first session is gone, the wrapper reconnects behind our back
+        let status = qmp.execute("query-status", Value::Null).unwrap();
+        assert_eq!(status["status"], "running");
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_qmp_events() {
+        let path = socket_path("events");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            let stopped = format!("{}\n{}", event("STOP", json!({})), r#"{"return": {}}"#);
+            let (_, mut writer) = serve_session(&listener, &[&stopped]);
+            writeln!(writer, "{}", event("RESUME", json!({}))).unwrap();
+        });
+
+        let mut qmp = QmpClient::connect(&path).unwrap();
+        assert_eq!(qmp.execute("stop", Value::Null).unwrap(), json!({}));
+
+        server.join().unwrap();
+        let events: Vec<QmpEvent> = qmp.events().map(|e| e.unwrap()).collect();
+        let names: Vec<&str> = events.iter().map(|e| e.event.as_str()).collect();
+        assert_eq!(names, vec!["STOP", "RESUME"]);
+        assert_eq!(events[0].timestamp.microseconds, 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_wait_for_shutdown() {
+        let path = socket_path("shutdown");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (resume, resumed) = std::sync::mpsc::channel();
+        let server = thread::spawn(move || {
+            let (_, mut writer) = serve_session(&listener, &[]);
+
+            // the event of a qemu before 4.0 has no reason, and is cut by the timeout
+            let shutdown = event("SHUTDOWN", json!({ "guest": true }));
+            let (start, end) = shutdown.split_at(shutdown.len() / 2);
+            writeln!(writer, "{}", event("STOP", json!({}))).unwrap();
+            write!(writer, "{}", start).unwrap();
+            resumed.recv().unwrap();
+            writeln!(writer, "{}", end).unwrap();
+        });
+
+        let mut qmp = QmpClient::connect(&path).unwrap();
+        let err = qmp
+            .wait_for_shutdown(Duration::from_millis(100))
+            .unwrap_err();
+        assert!(err.to_string().contains("no SHUTDOWN event"));
+        assert_eq!(qmp.reader.get_ref().read_timeout().unwrap(), None);
+
+        resume.send(()).unwrap();
+        assert_eq!(qmp.wait_for_shutdown(Duration::from_secs(5)).unwrap(), None);
+        server.join().unwrap();
+
+        // the events skipped while waiting are kept
+        let names: Vec<String> = qmp.events().map(|e| e.unwrap().event).collect();
+        assert_eq!(names, vec!["STOP"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_greeting_and_errors() {
+        let path = socket_path("greeting");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || {
+            serve_greeted_session(
+                &listener,
+                r#"{"QMP": {"version": {"qemu": {"major": 8, "minor": 2, "micro": 1}, "package": ""}, "capabilities": ["oob"]}}"#,
+                &[
+                    r#"{"return": {"status": "prelaunch", "running": false}}"#,
+                    r#"{"error": {"class": "GenericError", "desc": "not allowed in prelaunch"}}"#,
+                ],
+            );
+        });
+
+        let mut qmp = QmpClient::connect(&path).unwrap();
+        let version: QemuVersion = qmp.greeting().version.qemu.into();
+        assert_eq!(version, (8, 2, 1));
+        assert_eq!(qmp.greeting().capabilities, vec!["oob"]);
+        assert_eq!(qmp.query_status().unwrap(), "prelaunch");
+
+        let err = qmp.system_powerdown().unwrap_err();
+        assert_eq!(err.to_string(), "qmp command system_powerdown failed");
+        let err = err.downcast_ref::<QmpError>().unwrap();
+        assert_eq!(err.class, "GenericError");
+        assert_eq!(err.desc, "not allowed in prelaunch");
+
+        server.join().unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_check_migration_uri() {
+        assert!(check_migration_uri("rdma:192.168.1.2:4444").is_ok());
+        assert!(check_migration_uri("tcp:192.168.1.2:4444").is_ok());
+        assert!(check_migration_uri("rdma:192.168.1.2").is_err());
+        assert!(check_migration_uri("rdma::4444").is_err());
+    }
+}
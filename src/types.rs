@@ -1,11 +1,16 @@
+use std::collections::BTreeMap;
 use std::os::unix::prelude::RawFd;
 
 use crate::config::QemuConfig;
+use crate::device_consts::{TRANSPORTCCW, TRANSPORTPCI};
 use serde::{Deserialize, Serialize};
 
 pub(crate) const MIGRATION_FD: &str = "fd";
 pub(crate) const MIGRATION_EXEC: &str = "exec";
 pub(crate) const MIGRATION_DEFER: &str = "defer";
+pub(crate) const MIGRATION_RDMA: &str = "rdma";
+pub(crate) const MIGRATION_TCP: &str = "tcp";
+pub(crate) const MIGRATION_UNIX: &str = "unix";
 pub(crate) const MACHINE_TYPE_MICROVM: &str = "microvm";
 
 /// the machine that qemu will emulate...
@@ -24,6 +29,85 @@ pub struct Machine {
     pub(crate) options: String,
 }
 
+impl Machine {
+    /// whether the guest runs under tcg emulation, qemu's default without an accelerator
+    pub(crate) fn is_tcg(&self) -> bool {
+        self.acceleration.is_empty() || self.acceleration.starts_with("tcg")
+    }
+
+    /// whether vcpus can be hotplugged on this machine type
+    pub(crate) fn supports_cpu_hotplug(&self) -> bool {
+        const CPU_HOTPLUG_MACHINES: [&str; 4] = ["pc", "q35", "pseries", "s390-ccw-virtio"];
+
+        CPU_HOTPLUG_MACHINES
+            .iter()
+            .any(|m| self.machine_type == *m || self.machine_type.starts_with(&format!("{}-", m)))
+    }
+}
+
+/// baselines for the commonly used machines, see `QemuConfig::apply_preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MachinePreset {
+    /// q35 chipset with pcie root ports to hotplug devices into
+    Q35Modern,
+
+    /// i440fx chipset for guests without pcie support
+    PcLegacy,
+
+    /// aarch64 virt board with pcie root ports
+    Arm64Virt,
+
+    /// minimal x86 machine without pci, for fast booting guests
+    Microvm,
+}
+
+/// firmware boot options, -boot
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Boot {
+    /// drives to boot from in order, a and b are floppies, c the first
+    /// hard disk, d the first cdrom and n to p the network adapters
+    #[serde(default)]
+    pub(crate) order: String,
+
+    /// interactive boot menu
+    #[serde(default)]
+    pub(crate) menu: bool,
+
+    /// image shown with the boot menu
+    #[serde(default)]
+    pub(crate) splash: String,
+
+    /// milliseconds before rebooting when no boot device is found, -1 never reboots
+    #[serde(default)]
+    pub(crate) reboot_timeout: Option<i32>,
+}
+
+impl Boot {
+    const DEVICE_LETTERS: &'static str = "abcdnop";
+
+    pub(crate) fn valid(&self) -> bool {
+        self.order.chars().all(|c| Self::DEVICE_LETTERS.contains(c))
+    }
+
+    /// the -boot suboptions, empty when nothing is set
+    pub(crate) fn params(&self) -> Vec<String> {
+        let mut params = vec![];
+        if !self.order.is_empty() {
+            params.push(format!("order={}", self.order));
+        }
+        if self.menu {
+            params.push("menu=on".to_owned());
+        }
+        if !self.splash.is_empty() {
+            params.push(format!("splash={}", qemu_escape(&self.splash)));
+        }
+        if let Some(timeout) = self.reboot_timeout {
+            params.push(format!("reboot-timeout={}", timeout));
+        }
+        params
+    }
+}
+
 /// real time clock
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Rtc {
@@ -48,8 +132,17 @@ impl Rtc {
         const SLEW: &str = "slew";
         const NODRIFTFIX: &str = "none";
 
-        let clock_valid = (self.clock == HOST) || (self.clock == RT) || (self.clock == VM);
-        let drift_fix_valid = (self.drift_fix == SLEW) || (self.drift_fix == NODRIFTFIX);
+        if self.base.is_empty() {
+            return false;
+        }
+
+        // clock and driftfix are optional, only check them when set
+        let clock_valid = self.clock.is_empty()
+            || (self.clock == HOST)
+            || (self.clock == RT)
+            || (self.clock == VM);
+        let drift_fix_valid =
+            self.drift_fix.is_empty() || (self.drift_fix == SLEW) || (self.drift_fix == NODRIFTFIX);
         clock_valid && drift_fix_valid
     }
 }
@@ -61,10 +154,22 @@ pub struct QmpSocket {
     #[serde(default)]
     pub(crate) socket_type: String,
 
-    /// socket name
+    /// socket name, the path of a unix socket
     #[serde(default)]
     pub(crate) name: String,
 
+    /// host a tcp socket listens on or connects to
+    #[serde(default)]
+    pub(crate) host: String,
+
+    /// port of a tcp socket
+    #[serde(default)]
+    pub(crate) port: u16,
+
+    /// pick a free port for a tcp socket at launch, see `Qemu::qmp_port`
+    #[serde(default)]
+    pub(crate) dynamic_port: bool,
+
     /// is socket a server?
     #[serde(default)]
     pub(crate) is_server: bool,
@@ -72,21 +177,129 @@ pub struct QmpSocket {
     /// if qemu should block waiting for a client to connect
     #[serde(default)]
     pub(crate) no_wait: bool,
+
+    /// what the socket is meant for, see `Qemu::qmp_client_for_role`
+    #[serde(default)]
+    pub(crate) role: QmpRole,
+}
+
+/// the intended use of a qmp socket, qemu itself grants the same
+/// commands on all of them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QmpRole {
+    /// issues commands that change the vm
+    #[default]
+    Control,
+
+    /// only queries the vm and listens to events
+    Monitor,
 }
 
 impl QmpSocket {
+    pub(crate) const UNIX_SOCKET: &'static str = "unix";
+    pub(crate) const TCP_SOCKET: &'static str = "tcp";
+
     pub(crate) fn valid(&self) -> bool {
-        const UNIX_SOCKET: &str = "unix";
+        match self.socket_type.as_str() {
+            Self::UNIX_SOCKET => !self.name.is_empty(),
+            Self::TCP_SOCKET => !self.host.is_empty() && self.port > 0,
+            _ => false,
+        }
+    }
 
-        if self.socket_type.is_empty() || self.name.is_empty() {
-            return false;
+    /// the address in qemu's chardev syntax, unix:<path> or tcp:<host>:<port>,
+    /// an ipv6 host is written in brackets
+    pub(crate) fn address(&self) -> String {
+        if self.socket_type == Self::TCP_SOCKET {
+            let host = unbracket_host(&self.host);
+            if host.contains(':') {
+                format!("{}:[{}]:{}", self.socket_type, host, self.port)
+            } else {
+                format!("{}:{}:{}", self.socket_type, host, self.port)
+            }
+        } else {
+            format!("{}:{}", self.socket_type, qemu_escape(&self.name))
         }
+    }
 
-        if self.socket_type != UNIX_SOCKET {
-            return false;
+    /// the address with the server options, e.g. unix:/run/qmp.sock,server=on,wait=off
+    pub(crate) fn params(&self) -> String {
+        let mut params = vec![self.address()];
+        if self.is_server {
+            params.push("server=on".to_owned());
+            if self.no_wait {
+                params.push("wait=off".to_owned());
+            }
         }
+        params.join(",")
+    }
 
-        true
+    /// set the port of a tcp socket to a free one of its host
+    pub(crate) fn pick_free_port(&mut self) -> std::io::Result<u16> {
+        // the listener is dropped right away, qemu binds the port once launched
+        self.port = std::net::TcpListener::bind((unbracket_host(&self.host), 0))?
+            .local_addr()?
+            .port();
+        Ok(self.port)
+    }
+}
+
+/// human monitor (HMP) socket, for interactive debugging
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct MonitorSocket {
+    /// the backend of the monitor, unix, tcp, stdio or pty
+    #[serde(default)]
+    pub(crate) socket_type: String,
+
+    /// path of a unix socket
+    #[serde(default)]
+    pub(crate) name: String,
+
+    /// host a tcp socket listens on
+    #[serde(default)]
+    pub(crate) host: String,
+
+    /// port of a tcp socket
+    #[serde(default)]
+    pub(crate) port: u16,
+
+    /// is socket a server?
+    #[serde(default)]
+    pub(crate) is_server: bool,
+
+    /// if qemu should block waiting for a client to connect
+    #[serde(default)]
+    pub(crate) no_wait: bool,
+}
+
+impl MonitorSocket {
+    /// the backends that are not a socket
+    const STDIO_BACKENDS: [&'static str; 2] = ["stdio", "pty"];
+
+    /// a unix or tcp monitor is addressed like a qmp socket
+    fn socket(&self) -> QmpSocket {
+        QmpSocket {
+            socket_type: self.socket_type.to_owned(),
+            name: self.name.to_owned(),
+            host: self.host.to_owned(),
+            port: self.port,
+            is_server: self.is_server,
+            no_wait: self.no_wait,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn valid(&self) -> bool {
+        Self::STDIO_BACKENDS.contains(&self.socket_type.as_str()) || self.socket().valid()
+    }
+
+    /// the -monitor value, e.g. unix:/run/hmp.sock,server=on,wait=off
+    pub(crate) fn params(&self) -> String {
+        if Self::STDIO_BACKENDS.contains(&self.socket_type.as_str()) {
+            return self.socket_type.to_owned();
+        }
+        self.socket().params()
     }
 }
 
@@ -151,6 +364,287 @@ pub struct Memory {
     /// used by file backed memory
     #[serde(default)]
     pub(crate) path: String,
+
+    /// where hugetlbfs is mounted, used with knobs.hugepages
+    #[serde(default)]
+    pub(crate) hugepages_path: String,
+}
+
+impl Memory {
+    const DEFAULT_HUGEPAGES_PATH: &'static str = "/dev/hugepages";
+
+    /// the hugetlbfs mount, /dev/hugepages if unset
+    pub(crate) fn hugepages_path(&self) -> &str {
+        if self.hugepages_path.is_empty() {
+            Self::DEFAULT_HUGEPAGES_PATH
+        } else {
+            &self.hugepages_path
+        }
+    }
+
+    /// size in bytes, a size without suffix is in MiB like qemu's -m
+    pub(crate) fn size_bytes(&self) -> Option<u64> {
+        parse_size(&self.size, 20)
+    }
+
+    /// max_memory in bytes, a size without suffix is in MiB like qemu's -m
+    pub(crate) fn max_memory_bytes(&self) -> Option<u64> {
+        parse_size(&self.max_memory, 20)
+    }
+}
+
+/// parse a qemu size like 512M or 4G to bytes, a size without
+/// suffix is shifted by default_shift, i.e. 0 for bytes or 20 for MiB
+pub(crate) fn parse_size(size: &str, default_shift: u32) -> Option<u64> {
+    let size = size.trim();
+    let (num, shift) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 10),
+        'M' => (&size[..size.len() - 1], 20),
+        'G' => (&size[..size.len() - 1], 30),
+        'T' => (&size[..size.len() - 1], 40),
+        _ => (size, default_shift),
+    };
+    num.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// a host without the brackets an ipv6 address is written in, for it to be bound
+pub(crate) fn unbracket_host(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// escape a suboption value for qemu, which splits suboptions on commas,
+/// a literal comma is written as two
+pub(crate) fn qemu_escape(value: &str) -> String {
+    value.replace(',', ",,")
+}
+
+/// the guest architecture, which qemu-system-* binary runs the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Ppc64le,
+    S390x,
+    Riscv64,
+}
+
+impl Arch {
+    /// the architecture of the host, x86_64 if it is not one qemu-launch knows
+    pub(crate) fn host() -> Self {
+        match std::env::consts::ARCH {
+            "aarch64" => Arch::Aarch64,
+            "powerpc64" => Arch::Ppc64le,
+            "s390x" => Arch::S390x,
+            "riscv64" => Arch::Riscv64,
+            _ => Arch::X86_64,
+        }
+    }
+
+    /// the architecture told by the machine type, the host's one if the
+    /// machine type is empty, unknown or shared by several architectures
+    pub(crate) fn from_machine_or_host(machine_type: &str) -> Self {
+        let host = Self::host();
+        match machine_type {
+            "pc" | "q35" | "isapc" | MACHINE_TYPE_MICROVM => Arch::X86_64,
+            m if m.starts_with("pc-") => Arch::X86_64,
+            m if m.starts_with("pseries") || m == "powernv" => Arch::Ppc64le,
+            m if m.starts_with("s390-ccw-virtio") => Arch::S390x,
+            "sifive_u" | "spike" => Arch::Riscv64,
+            // virt is both the arm and the risc-v board
+            "virt" if host == Arch::Riscv64 => Arch::Riscv64,
+            "virt" => Arch::Aarch64,
+            _ => host,
+        }
+    }
+
+    /// the virtio transport of devices that do not set one
+    pub(crate) fn default_transport(&self) -> &'static str {
+        match self {
+            Arch::S390x => TRANSPORTCCW,
+            _ => TRANSPORTPCI,
+        }
+    }
+
+    /// whether the guest memory can be split in dimms
+    pub(crate) fn supports_dimm(&self) -> bool {
+        matches!(self, Arch::X86_64 | Arch::Aarch64 | Arch::Ppc64le)
+    }
+
+    /// the machine type used when none is configured
+    pub(crate) fn default_machine(&self) -> &'static str {
+        match self {
+            Arch::X86_64 => "q35",
+            Arch::Aarch64 | Arch::Riscv64 => "virt",
+            Arch::Ppc64le => "pseries",
+            Arch::S390x => "s390-ccw-virtio",
+        }
+    }
+}
+
+/// what the host must provide to run a config, to schedule it on a capable host
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HostFeature {
+    /// /dev/kvm
+    Kvm,
+
+    /// hugetlbfs mounted, on /dev/hugepages by default
+    Hugepages,
+
+    /// devices bound to a vfio driver
+    Vfio,
+
+    /// an AMD SEV capable cpu and firmware
+    Sev,
+
+    /// vhost kernel modules or vhost-user backends
+    Vhost,
+}
+
+/// qemu version as (major, minor, micro)
+pub type QemuVersion = (u32, u32, u32);
+
+/// assigns cpus of the topology to a numa node, -numa cpu
+/// unset ids match every cpu of the upper level, e.g. a whole socket
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NumaCpu {
+    #[serde(default)]
+    pub(crate) node_id: u32,
+
+    #[serde(default)]
+    pub(crate) socket_id: Option<u32>,
+
+    #[serde(default)]
+    pub(crate) core_id: Option<u32>,
+
+    #[serde(default)]
+    pub(crate) thread_id: Option<u32>,
+}
+
+/// distance between two numa nodes, -numa dist
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct NumaDistance {
+    #[serde(default)]
+    pub(crate) src: u32,
+
+    #[serde(default)]
+    pub(crate) dst: u32,
+
+    /// 10 is the local distance, remote nodes are farther than that
+    #[serde(default)]
+    pub(crate) val: u8,
+}
+
+/// a numa node with its own memory backend, -numa node
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct NumaNode {
+    /// amount of memory of the node, suffixed with M or G
+    #[serde(default)]
+    pub(crate) size: String,
+
+    /// the cpu indexes belonging to the node
+    #[serde(default)]
+    pub(crate) cpus: Vec<u32>,
+
+    /// id of the memory backend, numa<nodeid> if unset
+    #[serde(default)]
+    pub(crate) memdev: String,
+
+    /// file backing the node with knobs.file_backed_mem, memory.path if unset
+    #[serde(default)]
+    pub(crate) mem_path: String,
+}
+
+impl NumaNode {
+    /// the file backing the node with knobs.file_backed_mem
+    pub(crate) fn mem_path<'a>(&'a self, memory: &'a Memory) -> &'a str {
+        if self.mem_path.is_empty() {
+            &memory.path
+        } else {
+            &self.mem_path
+        }
+    }
+
+    /// the cpus as qemu ranges, e.g. [0, 1, 2, 5] gives cpus=0-2,cpus=5
+    pub(crate) fn cpus_param(&self) -> String {
+        let mut cpus = self.cpus.clone();
+        cpus.sort_unstable();
+        cpus.dedup();
+
+        let mut ranges: Vec<(u32, u32)> = vec![];
+        for cpu in cpus {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == cpu => *end = cpu,
+                _ => ranges.push((cpu, cpu)),
+            }
+        }
+        ranges
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    format!("cpus={}", start)
+                } else {
+                    format!("cpus={}-{}", start, end)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// numa topology on top of the memory nodes
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Numa {
+    /// the memory nodes, a single node backed by the whole memory if empty
+    #[serde(default)]
+    pub(crate) nodes: Vec<NumaNode>,
+
+    /// per node cpu assignments
+    #[serde(default)]
+    pub(crate) cpus: Vec<NumaCpu>,
+
+    /// distance matrix, the reverse of a pair is filled in if missing
+    #[serde(default)]
+    pub(crate) distances: Vec<NumaDistance>,
+}
+
+impl Numa {
+    /// distances must not be shorter than the local one,
+    /// and a pair given both ways must be symmetric
+    pub(crate) fn valid(&self) -> bool {
+        const LOCAL_DISTANCE: u8 = 10;
+
+        self.distances.iter().all(|d| {
+            let local_ok = if d.src == d.dst {
+                d.val == LOCAL_DISTANCE
+            } else {
+                d.val > LOCAL_DISTANCE
+            };
+            let symmetric = self
+                .distances
+                .iter()
+                .filter(|r| r.src == d.dst && r.dst == d.src)
+                .all(|r| r.val == d.val);
+            local_ok && symmetric
+        })
+    }
+
+    /// the distance matrix with the missing reverse pairs filled in
+    pub(crate) fn full_distances(&self) -> Vec<NumaDistance> {
+        let mut distances = self.distances.clone();
+        for d in &self.distances {
+            let has_reverse = distances.iter().any(|r| r.src == d.dst && r.dst == d.src);
+            if !has_reverse {
+                distances.push(NumaDistance {
+                    src: d.dst,
+                    dst: d.src,
+                    val: d.val,
+                });
+            }
+        }
+        distances
+    }
 }
 
 /// Regroups a set of qemu boolean setups
@@ -217,6 +711,158 @@ pub struct Knobs {
     /// enable iommu for supported devices
     #[serde(default)]
     pub(crate) iommu_platform: bool,
+
+    /// enable the seccomp sandbox, -sandbox on unless seccomp_sandbox
+    /// gives the filter, which must not turn it off
+    #[serde(default)]
+    pub(crate) seccomp: bool,
+
+    /// hand the devices, drives, chardevs, netdevs and objects to qemu in a
+    /// -readconfig file written at launch, keeping the command line short,
+    /// the file is removed once qemu exits
+    #[serde(default)]
+    pub(crate) write_config: bool,
+}
+
+/// what qemu does when the guest panics or triple-faults,
+/// expressed as intent instead of no_reboot/no_shutdown knobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum ExitPolicy {
+    /// let the guest handle the panic and reboot, qemu keeps running
+    RebootOnPanic,
+
+    /// qemu exits on guest panic, and on reboot after a triple fault
+    ExitOnPanic,
+
+    /// qemu pauses the guest for inspection instead of exiting or rebooting
+    PauseOnPanic,
+}
+
+impl ExitPolicy {
+    /// the -no-reboot/-no-shutdown and pvpanic action flags of the policy
+    pub(crate) fn qemu_params(&self) -> Vec<String> {
+        let (legacy_flags, panic_action): (&[&str], &str) = match self {
+            ExitPolicy::RebootOnPanic => (&[], "none"),
+            ExitPolicy::ExitOnPanic => (&["--no-reboot"], "shutdown"),
+            // reboot turns into shutdown, which then turns into a pause
+            ExitPolicy::PauseOnPanic => (&["--no-reboot", "--no-shutdown"], "pause"),
+        };
+
+        let mut params: Vec<String> = legacy_flags.iter().map(|f| f.to_string()).collect();
+        params.push("-action".to_owned());
+        params.push(format!("panic={}", panic_action));
+        params
+    }
+}
+
+/// whether value is a known -action for the event name,
+/// e.g. reboot=shutdown or panic=pause
+pub(crate) fn valid_action(name: &str, value: &str) -> bool {
+    let values: &[&str] = match name {
+        "reboot" => &["reset", "shutdown"],
+        "shutdown" => &["poweroff", "pause"],
+        "panic" => &["pause", "shutdown", "exit-failure", "none"],
+        "watchdog" => &[
+            "reset",
+            "shutdown",
+            "poweroff",
+            "pause",
+            "debug",
+            "none",
+            "inject-nmi",
+        ],
+        _ => &[],
+    };
+    values.contains(&value)
+}
+
+/// the -display backends, vnc is served on the vnc config and emitted as -vnc
+pub(crate) const DISPLAY_TYPES: [&str; 5] = ["gtk", "sdl", "vnc", "egl-headless", "none"];
+
+/// the display adapters accepted by -vga
+pub(crate) const VGA_TYPES: [&str; 6] = ["std", "cirrus", "vmware", "qxl", "virtio", "none"];
+
+/// a plain -drive, for disks that do not need a device of their own
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DriveSpec {
+    /// the host image
+    #[serde(default)]
+    pub(crate) file: String,
+
+    /// image format, probed by qemu if empty
+    #[serde(default)]
+    pub(crate) format: String,
+
+    /// the interface the drive is attached on, e.g. ide or virtio
+    #[serde(default, rename = "if")]
+    pub(crate) interface: String,
+
+    /// disk or cdrom
+    #[serde(default)]
+    pub(crate) media: String,
+}
+
+impl DriveSpec {
+    pub(crate) fn valid(&self) -> bool {
+        const INTERFACES: [&str; 8] = [
+            "ide", "scsi", "sd", "mtd", "floppy", "pflash", "virtio", "none",
+        ];
+        const MEDIAS: [&str; 2] = ["disk", "cdrom"];
+
+        !self.file.is_empty()
+            && (self.interface.is_empty() || INTERFACES.contains(&self.interface.as_str()))
+            && (self.media.is_empty() || MEDIAS.contains(&self.media.as_str()))
+    }
+
+    pub(crate) fn params(&self) -> String {
+        let mut params = vec![format!("file={}", qemu_escape(&self.file))];
+        if !self.format.is_empty() {
+            params.push(format!("format={}", self.format));
+        }
+        if !self.interface.is_empty() {
+            params.push(format!("if={}", self.interface));
+        }
+        if !self.media.is_empty() {
+            params.push(format!("media={}", self.media));
+        }
+        params.join(",")
+    }
+}
+
+/// a default property value of every device of a driver, -global driver.property=value
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct GlobalParam {
+    #[serde(default)]
+    pub(crate) driver: String,
+
+    #[serde(default)]
+    pub(crate) property: String,
+
+    #[serde(default)]
+    pub(crate) value: String,
+}
+
+impl GlobalParam {
+    pub fn new(driver: &str, property: &str, value: &str) -> Self {
+        Self {
+            driver: driver.to_owned(),
+            property: property.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    /// the driver and property are split on the first '.' and '=' by qemu
+    pub(crate) fn valid(&self) -> bool {
+        let name_valid = |name: &str| !name.is_empty() && !name.contains(['.', '=']);
+        name_valid(&self.driver) && name_valid(&self.property) && !self.value.is_empty()
+    }
+
+    /// qemu takes the value after '=' as is, commas included
+    pub(crate) fn param(&self) -> String {
+        format!("{}.{}={}", self.driver, self.property, self.value)
+    }
 }
 
 /// Allows IO to be performed on a separated thread
@@ -229,7 +875,7 @@ pub struct IoThread {
 /// controls qemu live migration source preparation
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Incoming {
-    /// possible migration types are "fd", "exec", "defer"
+    /// possible migration types are "fd", "exec", "defer", "rdma", "tcp", "unix"
     #[serde(default)]
     pub(crate) migration_type: String,
 
@@ -240,6 +886,22 @@ pub struct Incoming {
     /// only valid if migration type if "exec"
     #[serde(default)]
     pub(crate) exec: String,
+
+    /// only valid if migration type is "rdma" or "tcp", the address to listen on
+    #[serde(default)]
+    pub(crate) host: String,
+
+    /// only valid if migration type is "rdma" or "tcp"
+    #[serde(default)]
+    pub(crate) port: u16,
+
+    /// only valid if migration type is "unix", the socket to listen on
+    #[serde(default)]
+    pub(crate) path: String,
+
+    /// id of the tls-creds object securing the migration stream
+    #[serde(default)]
+    pub(crate) tls_creds: String,
 }
 
 /// firmware config allows qemu to pass entries to the guest, could be found under sysfs
@@ -282,11 +944,11 @@ impl FwCfg {
         }
 
         if !self.file.is_empty() {
-            fw_cfg_params.push(format!("file={}", &self.file));
+            fw_cfg_params.push(format!("file={}", qemu_escape(&self.file)));
         }
 
         if !self.str.is_empty() {
-            fw_cfg_params.push(format!("string={}", &self.str));
+            fw_cfg_params.push(format!("string={}", qemu_escape(&self.str)));
         }
 
         config.qemu_params.push("-fw_cfg".to_owned());
@@ -294,3 +956,265 @@ impl FwCfg {
     }
 }
 
+/// x509 tls credentials, referenced by id from vnc and migration
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TlsCreds {
+    #[serde(default)]
+    pub(crate) id: String,
+
+    /// directory holding the ca, cert and key pem files
+    #[serde(default)]
+    pub(crate) dir: String,
+
+    /// either "server" or "client"
+    #[serde(default)]
+    pub(crate) endpoint: String,
+
+    /// require the peer to present a certificate signed by the ca
+    #[serde(default)]
+    pub(crate) verify_peer: bool,
+}
+
+impl TlsCreds {
+    pub(crate) fn valid(&self) -> bool {
+        const SERVER: &str = "server";
+        const CLIENT: &str = "client";
+
+        !self.id.is_empty()
+            && !self.dir.is_empty()
+            && (self.endpoint == SERVER || self.endpoint == CLIENT)
+    }
+
+    /// setup tls-creds' qemu params
+    pub(crate) fn qemu_params(&self, config: &mut QemuConfig) {
+        let verify_peer = if self.verify_peer { "on" } else { "off" };
+
+        config.qemu_params.push("-object".to_owned());
+        config.qemu_params.push(format!(
+            "tls-creds-x509,id={},dir={},endpoint={},verify-peer={}",
+            self.id,
+            qemu_escape(&self.dir),
+            self.endpoint,
+            verify_peer
+        ));
+    }
+}
+
+/// vnc server for the guest display
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Vnc {
+    /// display to listen on, e.g. ":0" or "0.0.0.0:1"
+    #[serde(default)]
+    pub(crate) display: String,
+
+    /// id of the tls-creds object encrypting the connections
+    #[serde(default)]
+    pub(crate) tls_creds: String,
+
+    /// listen on a free port picked at launch instead of the display's one,
+    /// the host part of the display is kept
+    #[serde(default)]
+    pub(crate) dynamic_port: bool,
+}
+
+impl Vnc {
+    /// vnc display N listens on tcp port 5900 + N
+    pub(crate) const BASE_PORT: u16 = 5900;
+
+    /// the tcp port the server listens on, None without a display
+    pub(crate) fn port(&self) -> Option<u16> {
+        let (_, display) = self.display.rsplit_once(':')?;
+        let display: u16 = display.parse().ok()?;
+        Self::BASE_PORT.checked_add(display)
+    }
+
+    /// replace the display with the one of a free port of the display's host
+    pub(crate) fn pick_free_port(&mut self) -> std::io::Result<u16> {
+        if self.display.starts_with("unix:") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a tcp display", self.display),
+            ));
+        }
+        let host = self
+            .display
+            .rsplit_once(':')
+            .map(|(host, _)| host)
+            .unwrap_or_default();
+        let bind_host = if host.is_empty() {
+            "0.0.0.0"
+        } else {
+            unbracket_host(host)
+        };
+
+        // the listener is dropped right away, qemu binds the port once launched
+        let port = loop {
+            let port = std::net::TcpListener::bind((bind_host, 0))?
+                .local_addr()?
+                .port();
+            if port >= Self::BASE_PORT {
+                break port;
+            }
+        };
+        self.display = format!("{}:{}", host, port - Self::BASE_PORT);
+        Ok(port)
+    }
+}
+
+/// tcg plugin loaded into qemu, for instrumentation
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Plugin {
+    /// path of the plugin shared library
+    #[serde(default)]
+    pub(crate) path: String,
+
+    /// arguments passed to the plugin
+    #[serde(default)]
+    pub(crate) args: BTreeMap<String, String>,
+}
+
+impl Plugin {
+    pub(crate) fn valid(&self) -> bool {
+        !self.path.is_empty()
+    }
+
+    /// setup plugin's qemu params
+    pub(crate) fn qemu_params(&self, config: &mut QemuConfig) {
+        let mut plugin_params = vec![qemu_escape(&self.path)];
+        for (name, value) in &self.args {
+            plugin_params.push(format!("{}={}", name, qemu_escape(value)));
+        }
+
+        config.qemu_params.push("-plugin".to_owned());
+        config.qemu_params.push(plugin_params.join(","));
+    }
+}
+
+/// -sandbox seccomp filter, each set option denies a class of syscalls
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Seccomp {
+    /// deny obsolete syscalls
+    #[serde(default)]
+    pub(crate) deny_obsolete: bool,
+
+    /// deny setuid/setgid and friends
+    #[serde(default)]
+    pub(crate) deny_elevate_privileges: bool,
+
+    /// deny fork and exec
+    #[serde(default)]
+    pub(crate) deny_spawn: bool,
+
+    /// deny process affinity and scheduler priority changes
+    #[serde(default)]
+    pub(crate) deny_resource_control: bool,
+}
+
+impl Seccomp {
+    /// a filter denying everything qemu can run without
+    pub fn hardened() -> Self {
+        Self {
+            deny_obsolete: true,
+            deny_elevate_privileges: true,
+            deny_spawn: true,
+            deny_resource_control: true,
+        }
+    }
+
+    /// the -sandbox value of the filter
+    pub fn param(&self) -> String {
+        let options = [
+            ("obsolete", self.deny_obsolete),
+            ("elevateprivileges", self.deny_elevate_privileges),
+            ("spawn", self.deny_spawn),
+            ("resourcecontrol", self.deny_resource_control),
+        ];
+
+        let mut params = vec!["on".to_owned()];
+        for (name, _) in options.iter().filter(|(_, deny)| *deny) {
+            params.push(format!("{}=deny", name));
+        }
+        params.join(",")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rtc_valid() {
+        let mut rtc = Rtc::default();
+        assert!(!rtc.valid());
+
+        rtc.base = "utc".to_owned();
+        assert!(rtc.valid());
+
+        rtc.clock = "host".to_owned();
+        assert!(rtc.valid());
+
+        rtc.clock = "bogus".to_owned();
+        assert!(!rtc.valid());
+    }
+
+    #[test]
+    fn test_exit_policy_params() {
+        assert_eq!(
+            ExitPolicy::RebootOnPanic.qemu_params(),
+            vec!["-action", "panic=none"]
+        );
+        assert_eq!(
+            ExitPolicy::ExitOnPanic.qemu_params(),
+            vec!["--no-reboot", "-action", "panic=shutdown"]
+        );
+        assert_eq!(
+            ExitPolicy::PauseOnPanic.qemu_params(),
+            vec!["--no-reboot", "--no-shutdown", "-action", "panic=pause"]
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1G", 0), Some(1 << 30));
+        assert_eq!(parse_size("512m", 0), Some(512 << 20));
+        assert_eq!(parse_size("4096", 0), Some(4096));
+        assert_eq!(parse_size("4096", 20), Some(4096 << 20));
+        assert_eq!(parse_size("", 20), None);
+        assert_eq!(parse_size("lots", 20), None);
+    }
+
+    #[test]
+    fn test_arch_defaults() {
+        let x86 = Arch::from_machine_or_host("q35");
+        assert_eq!(x86, Arch::X86_64);
+        assert_eq!(x86.default_transport(), "pci");
+        assert!(x86.supports_dimm());
+        assert_eq!(x86.default_machine(), "q35");
+        assert_eq!(Arch::from_machine_or_host("pc-i440fx-7.2"), Arch::X86_64);
+
+        let arm = Arch::Aarch64;
+        assert_eq!(arm.default_transport(), "pci");
+        assert!(arm.supports_dimm());
+        assert_eq!(arm.default_machine(), "virt");
+
+        let ppc = Arch::from_machine_or_host("pseries-7.2");
+        assert_eq!(ppc, Arch::Ppc64le);
+        assert_eq!(ppc.default_transport(), "pci");
+        assert!(ppc.supports_dimm());
+        assert_eq!(ppc.default_machine(), "pseries");
+
+        let s390 = Arch::from_machine_or_host("s390-ccw-virtio");
+        assert_eq!(s390, Arch::S390x);
+        assert_eq!(s390.default_transport(), "ccw");
+        assert!(!s390.supports_dimm());
+        assert_eq!(s390.default_machine(), "s390-ccw-virtio");
+
+        let riscv = Arch::from_machine_or_host("sifive_u");
+        assert_eq!(riscv, Arch::Riscv64);
+        assert_eq!(riscv.default_transport(), "pci");
+        assert!(!riscv.supports_dimm());
+        assert_eq!(riscv.default_machine(), "virt");
+
+        assert_eq!(Arch::from_machine_or_host(""), Arch::host());
+    }
+}
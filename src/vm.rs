@@ -0,0 +1,248 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+
+use crate::config::QemuConfig;
+use crate::qemu::Qemu;
+use crate::qmp::{QmpClient, ShutdownReason};
+use crate::types::{QmpRole, QmpSocket};
+
+/// how long qemu gets to create its qmp socket once launched
+const QMP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// delay between two attempts to connect to the qmp socket
+const QMP_CONNECT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// a virtual machine, the qemu process along with its control qmp session
+///
+/// The qemu process is killed when the vm is dropped, unless it was shut down.
+pub struct Vm {
+    qemu: Qemu,
+
+    /// the config qemu was built from, for the settings applied once launched
+    config: QemuConfig,
+
+    /// the session on the control qmp socket, None until started
+    qmp: Option<QmpClient>,
+}
+
+impl Vm {
+    /// build the qemu command line of config, which needs a unix control qmp socket
+    pub fn new(config: QemuConfig) -> Result<Self> {
+        let qemu = Qemu::from_config(config.clone())?;
+        let socket = qemu.qmp_socket(QmpRole::Control)?;
+        if socket.socket_type != QmpSocket::UNIX_SOCKET {
+            return Err(anyhow!(
+                "vm connects to unix control qmp sockets only, not {}",
+                socket.address()
+            ));
+        }
+        Ok(Self {
+            qemu,
+            config,
+            qmp: None,
+        })
+    }
+
+    /// launch qemu, connect to its control qmp socket and apply the settings
+    /// of the devices qemu only takes over qmp, see `QemuConfig::post_launch()`
+    pub fn start(&mut self) -> Result<()> {
+        if self.qmp.is_some() {
+            return Err(anyhow!("vm is already started"));
+        }
+        self.qemu.launch()?;
+
+        let deadline = Instant::now() + QMP_CONNECT_TIMEOUT;
+        loop {
+            match self.qemu.qmp_client_for_role(QmpRole::Control) {
+                Ok(client) => {
+                    let qmp = self.qmp.insert(client);
+                    return self.config.post_launch(qmp);
+                }
+                Err(e) => {
+                    if let Some(status) = self.qemu.try_wait()? {
+                        return Err(anyhow!("qemu exited with {} before serving qmp", status));
+                    }
+                    if Instant::now() >= deadline {
+                        return Err(e.context("qemu did not serve qmp in time"));
+                    }
+                    thread::sleep(QMP_CONNECT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// execute a qmp command on the control session, see `QmpClient::execute()`
+    ///
+    /// device_add and device_del are refused, devices are plugged with
+    /// `Vm::device_add()` and `Vm::device_del()` which keep track of them.
+    pub fn execute(&mut self, cmd: &str, args: Value) -> Result<Value> {
+        if cmd == "device_add" || cmd == "device_del" {
+            return Err(anyhow!("{} goes through Vm::{}()", cmd, cmd));
+        }
+        self.qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?
+            .execute(cmd, args)
+    }
+
+    /// the underlying qemu instance
+    pub fn qemu(&self) -> &Qemu {
+        &self.qemu
+    }
+
+    /// hotplug a device, see `Qemu::device_add()`
+    pub fn device_add(&mut self, args: Value) -> Result<()> {
+        let qmp = self
+            .qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?;
+        self.qemu.device_add(qmp, args)
+    }
+
+    /// request the unplug of a hotplugged device, see `Qemu::device_del()`
+    pub fn device_del(&mut self, id: &str) -> Result<()> {
+        let qmp = self
+            .qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?;
+        self.qemu.device_del(qmp, id)
+    }
+
+    /// wait for an unplugged device to be deleted, see `Qemu::wait_for_device_deleted()`
+    pub fn wait_for_device_deleted(&mut self, id: &str, timeout: Duration) -> Result<()> {
+        let qmp = self
+            .qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?;
+        self.qemu.wait_for_device_deleted(qmp, id, timeout)
+    }
+
+    /// wait on the control qmp session for qemu to shut down, and tell why,
+    /// the session is closed once it did
+    pub fn wait_for_shutdown(&mut self, timeout: Duration) -> Result<Option<ShutdownReason>> {
+        let qmp = self
+            .qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?;
+        let reason = qmp.wait_for_shutdown(timeout)?;
+        self.qmp = None;
+        Ok(reason)
+    }
+
+    /// ask the guest to power off and wait at most timeout for qemu to exit,
+    /// qemu is killed if the guest does not shut down in time
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<Option<ShutdownReason>> {
+        let deadline = Instant::now() + timeout;
+        self.qmp
+            .as_mut()
+            .ok_or_else(|| anyhow!("vm is not started"))?
+            .system_powerdown()?;
+
+        let reason = match self.wait_for_shutdown(timeout) {
+            Ok(reason) => reason,
+            Err(e) => {
+                self.qemu.kill()?;
+                return Err(e);
+            }
+        };
+
+        while self.qemu.try_wait()?.is_none() {
+            if Instant::now() >= deadline {
+                log::warn!("qemu did not exit after the guest shut down, killing it");
+                self.qemu.kill()?;
+                break;
+            }
+            thread::sleep(QMP_CONNECT_INTERVAL);
+        }
+        Ok(reason)
+    }
+}
+
+impl Drop for Vm {
+    fn drop(&mut self) {
+        if let Ok(None) = self.qemu.try_wait() {
+            if let Err(e) = self.qemu.kill() {
+                log::warn!("failed to kill qemu: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::tests::{event, executed, serve_session, socket_path};
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn test_vm_lifecycle() {
+        let control = socket_path("vm");
+
+        // a qemu that only lives for a while, the qmp side is mocked below
+        let bin = std::env::temp_dir().join(format!("fake-qemu-{}", std::process::id()));
+        std::fs::write(&bin, "#!/bin/sh\nsleep 1\n").unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "bin_path": bin,
+            "qmp_sockets": [{ "socket_type": "unix", "name": control, "is_server": true }],
+            "device_specs": [{ "kind": "balloon", "id": "balloon0", "stats_polling_interval": 5 }]
+        }))
+        .unwrap();
+        let mut vm = Vm::new(config).unwrap();
+
+        let listener = UnixListener::bind(&control).unwrap();
+        let server = thread::spawn(move || {
+            let (requests, mut writer) = serve_session(
+                &listener,
+                &[
+                    r#"{"return": {}}"#,
+                    r#"{"return": {"status": "running", "running": true}}"#,
+                    r#"{"return": {}}"#,
+                ],
+            );
+            let shutdown = serde_json::json!({ "guest": true, "reason": "guest-shutdown" });
+            writeln!(writer, "{}", event("SHUTDOWN", shutdown)).unwrap();
+            requests
+        });
+
+        vm.start().unwrap();
+        let status = vm.execute("query-status", Value::Null).unwrap();
+        assert_eq!(status["status"], "running");
+        // devices are only unplugged through the hotplug tracking
+        let err = vm
+            .execute("device_del", serde_json::json!({ "id": "balloon0" }))
+            .unwrap_err();
+        assert!(err.to_string().contains("Vm::device_del()"));
+
+        let reason = vm.shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(reason, Some(ShutdownReason::GuestShutdown));
+        assert!(vm.qemu.try_wait().unwrap().is_some());
+        assert_eq!(
+            executed(&server.join().unwrap()),
+            vec![
+                "qmp_capabilities",
+                "qom-set",
+                "query-status",
+                "system_powerdown"
+            ]
+        );
+
+        let _ = std::fs::remove_file(&control);
+        let _ = std::fs::remove_file(&bin);
+    }
+
+    #[test]
+    fn test_vm_needs_unix_control_socket() {
+        let config: QemuConfig = serde_json::from_value(serde_json::json!({
+            "qmp_sockets": [{ "socket_type": "tcp", "host": "::1", "port": 4444, "is_server": true }]
+        }))
+        .unwrap();
+        let err = Vm::new(config).err().unwrap();
+        assert!(err.to_string().contains("tcp:[::1]:4444"));
+    }
+}